@@ -12,6 +12,10 @@ class Relay
     private const MESSAGE_TYPE_IDENTITY = 0;
     private const MESSAGE_TYPE_REQUEST = 1;
     private const MESSAGE_TYPE_RESPONSE = 2;
+    private const MESSAGE_TYPE_PUBLISH = 3;
+    private const MESSAGE_TYPE_PING = 4;
+    private const MESSAGE_TYPE_PONG = 5;
+    private const MESSAGE_TYPE_STOP = 6;
 
     private const TYPE_LENGTH = 1;
     private const SIZE_LENGTH = 8;
@@ -29,10 +33,22 @@ class Relay
         $this->sendIdentity();
     }
 
+    /**
+     * Returns the next request, or null once coyote asks the worker to stop
+     * so it can clean up and exit.
+     */
     public function next(): ?string
     {
         try {
             [$type, $size] = $this->readHeader();
+            // Pings only arrive between requests, answer them while waiting.
+            while ($type === self::MESSAGE_TYPE_PING) {
+                $this->write(self::MESSAGE_TYPE_PONG, "");
+                [$type, $size] = $this->readHeader();
+            }
+            if ($type === self::MESSAGE_TYPE_STOP) {
+                return null;
+            }
             if ($type !== self::MESSAGE_TYPE_REQUEST) {
                 throw new \Exception("expected Request message, got: %d", $type);
             }
@@ -49,6 +65,19 @@ class Relay
         $this->write(self::MESSAGE_TYPE_RESPONSE, $payload);
     }
 
+    /**
+     * Publishes to subscribers of a topic, may be called while handling a
+     * request.
+     */
+    public function publish(string $topic, string $data, ?string $event = null)
+    {
+        $message = ["channel" => $topic, "data" => $data];
+        if (null !== $event) {
+            $message["event"] = $event;
+        }
+        $this->write(self::MESSAGE_TYPE_PUBLISH, json_encode($message));
+    }
+
     public function __destruct()
     {
         fclose($this->fp);
@@ -93,7 +122,12 @@ class Relay
                 fwrite($this->fp, pack("CJ", $type, $payload));
                 break;
 
+            case self::MESSAGE_TYPE_PONG:
+                fwrite($this->fp, pack("CJ", $type, 0));
+                break;
+
             case self::MESSAGE_TYPE_RESPONSE:
+            case self::MESSAGE_TYPE_PUBLISH:
                 fwrite($this->fp, pack("CJ", $type, mb_strlen($payload, "8bit")));
                 fwrite($this->fp, $payload);
                 break;
@@ -0,0 +1,444 @@
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::os::unix::fs::PermissionsExt;
+use std::sync::{
+    Arc,
+    Mutex,
+};
+use std::time::Instant;
+
+use anyhow::{
+    anyhow,
+    Result,
+};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{
+    header,
+    Body,
+    Method,
+    Request,
+    Response,
+    StatusCode,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use serde_json::Value;
+use tokio::io::{
+    AsyncRead,
+    AsyncWrite,
+};
+use tokio::net::{
+    TcpListener,
+    UnixListener,
+};
+use tokio::sync::Notify;
+
+use crate::config::Config;
+use crate::opt::Opt;
+use crate::worker::ipc::Pid;
+use crate::worker::pool::Static;
+
+/// Body of `POST /pools/<name>/scale`.
+#[derive(Debug, Deserialize)]
+struct Scale {
+    size: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct Error {
+    error: String,
+}
+
+/// Server wide part of `GET /status`.
+#[derive(Debug, Serialize)]
+struct Status {
+    version: &'static str,
+    pid:     u32,
+    /// Seconds since the server started.
+    uptime:  u64,
+}
+
+/// Inspects and steers a running server: lists, resets and scales pools,
+/// replaces workers, reloads the configuration, dumps it and stops.
+///
+/// - `GET /status`
+/// - `GET /pools`, `GET /pools/<name>`
+/// - `POST /pools/<name>/reset`
+/// - `POST /pools/<name>/scale` with `{"size": <workers>}`
+/// - `POST /pools/<name>/workers/<pid>/kill`
+/// - `POST /reload`
+/// - `GET /config`
+/// - `POST /stop`
+pub struct Admin {
+    pools:   BTreeMap<String, Arc<Static>>,
+    opt:     Opt,
+    /// Configuration in effect, as dumped by `GET /config`.
+    config:  Mutex<Value>,
+    /// Bearer token requests must present, if any.
+    token:   Option<String>,
+    started: Instant,
+    stop:    Notify,
+}
+
+impl Admin {
+    pub fn new(
+        pools: BTreeMap<String, Arc<Static>>,
+        opt: Opt,
+        config: &Config,
+    ) -> Result<Self> {
+        Ok(Self {
+            pools,
+            opt,
+            config: Mutex::new(serde_json::to_value(config)?),
+            token: config.admin.as_ref().and_then(|admin| admin.token.clone()),
+            started: Instant::now(),
+            stop: Notify::new(),
+        })
+    }
+
+    /// Resolves once `POST /stop` was requested.
+    pub async fn stopped(&self) {
+        self.stop.notified().await
+    }
+
+    pub async fn handle(
+        &self,
+        req: Request<Body>,
+    ) -> Result<Response<Body>> {
+        if !self.authorized(&req) {
+            return Ok(error(StatusCode::UNAUTHORIZED, "invalid admin token"));
+        }
+
+        let method = req.method().clone();
+        let path = req.uri().path().trim_matches('/').to_string();
+        let segments = path.split('/').collect::<Vec<_>>();
+        match (method, segments.as_slice()) {
+            (Method::GET, ["status"]) => {
+                let status = Status {
+                    version: env!("CARGO_PKG_VERSION"),
+                    pid:     std::process::id(),
+                    uptime:  self.started.elapsed().as_secs(),
+                };
+                json(StatusCode::OK, &status)
+            }
+            (Method::GET, ["pools"]) => {
+                let pools = self
+                    .pools
+                    .iter()
+                    .map(|(name, pool)| (name.as_str(), pool.status()))
+                    .collect::<BTreeMap<_, _>>();
+                json(StatusCode::OK, &pools)
+            }
+            (Method::GET, ["pools", name]) => match self.pools.get(*name) {
+                Some(pool) => json(StatusCode::OK, &pool.status()),
+                None => Ok(unknown_pool(name)),
+            },
+            (Method::POST, ["pools", name, "reset"]) => {
+                match self.pools.get(*name) {
+                    Some(pool) => {
+                        log::info!("Resetting pool {}", name);
+                        pool.reset();
+                        Ok(accepted())
+                    }
+                    None => Ok(unknown_pool(name)),
+                }
+            }
+            (Method::POST, ["pools", name, "scale"]) => {
+                let pool = match self.pools.get(*name) {
+                    Some(pool) => pool.clone(),
+                    None => return Ok(unknown_pool(name)),
+                };
+                let body = hyper::body::to_bytes(req.into_body()).await?;
+                match serde_json::from_slice::<Scale>(&body) {
+                    Ok(Scale { size }) if size > 0 => {
+                        log::info!("Scaling pool {} to {} workers", name, size);
+                        pool.scale(size);
+                        Ok(accepted())
+                    }
+                    Ok(_) => {
+                        let reason = "pool size must be positive";
+                        Ok(error(StatusCode::BAD_REQUEST, reason))
+                    }
+                    Err(err) => {
+                        Ok(error(StatusCode::BAD_REQUEST, &err.to_string()))
+                    }
+                }
+            }
+            (Method::POST, ["pools", name, "workers", pid, "kill"]) => {
+                let pool = match self.pools.get(*name) {
+                    Some(pool) => pool,
+                    None => return Ok(unknown_pool(name)),
+                };
+                match pid.parse::<Pid>() {
+                    Ok(pid) if pool.kill(pid) => {
+                        log::info!("Replacing worker {} of pool {}", pid, name);
+                        Ok(accepted())
+                    }
+                    _ => {
+                        let reason = format!("unknown worker: {}", pid);
+                        Ok(error(StatusCode::NOT_FOUND, &reason))
+                    }
+                }
+            }
+            (Method::POST, ["reload"]) => match self.reload() {
+                Ok(()) => Ok(accepted()),
+                Err(err) => {
+                    log::error!("could not reload: {}", err);
+                    let status = StatusCode::UNPROCESSABLE_ENTITY;
+                    Ok(error(status, &err.to_string()))
+                }
+            },
+            (Method::GET, ["config"]) => {
+                let config = self.config.lock().unwrap().clone();
+                json(StatusCode::OK, &config)
+            }
+            (Method::POST, ["stop"]) => {
+                log::info!("Stopping on admin request");
+                self.stop.notify_one();
+                Ok(accepted())
+            }
+            _ => Ok(error(StatusCode::NOT_FOUND, "unknown admin endpoint")),
+        }
+    }
+
+    /// Reloads the configuration, resizes pools and replaces every worker.
+    ///
+    /// Only pool sizes are applied, other changes need a restart.
+    pub fn reload(&self) -> Result<()> {
+        let config = Config::load(&self.opt)?;
+        log::info!("Reloading configuration");
+
+        for name in config.pools.keys() {
+            if !self.pools.contains_key(name) {
+                log::warn!("new pool {} needs a restart", name);
+            }
+        }
+        for (name, pool) in &self.pools {
+            match config.pools.get(name) {
+                Some(pool_config) => {
+                    pool.scale(pool_config.size);
+                    pool.reset();
+                }
+                None => log::warn!("removing pool {} needs a restart", name),
+            }
+        }
+
+        *self.config.lock().unwrap() = serde_json::to_value(&config)?;
+        Ok(())
+    }
+
+    fn authorized(
+        &self,
+        req: &Request<Body>,
+    ) -> bool {
+        let token = match &self.token {
+            Some(token) => token,
+            None => return true,
+        };
+        req.headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|given| constant_time_eq(given, token))
+    }
+}
+
+/// Serves the admin API on a loopback TCP `listener`.
+pub async fn serve_tcp(
+    listener: TcpListener,
+    admin: Arc<Admin>,
+) -> Result<()> {
+    log::info!("Serving admin API on: {}", listener.local_addr()?);
+
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                log::error!("could not accept new connection: {}", err);
+                continue;
+            }
+        };
+
+        tokio::spawn(serve_connection(stream, admin.clone()));
+    }
+}
+
+/// Serves the admin API on a Unix domain socket only the owner can use.
+pub async fn serve_unix(
+    listener: UnixListener,
+    admin: Arc<Admin>,
+) -> Result<()> {
+    if let Some(path) = listener.local_addr()?.as_pathname() {
+        std::fs::set_permissions(path, PermissionsExt::from_mode(0o600))?;
+    }
+    log::info!("Serving admin API on: {:?}", listener.local_addr()?);
+
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                log::error!("could not accept new connection: {}", err);
+                continue;
+            }
+        };
+
+        tokio::spawn(serve_connection(stream, admin.clone()));
+    }
+}
+
+async fn serve_connection(
+    stream: impl AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    admin: Arc<Admin>,
+) {
+    let service = service_fn(move |req| {
+        let admin = admin.clone();
+        async move {
+            let response = match admin.handle(req).await {
+                Ok(response) => response,
+                Err(err) => {
+                    error(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string())
+                }
+            };
+            Ok::<_, Infallible>(response)
+        }
+    });
+
+    if let Err(err) = Http::new()
+        .http1_only(true)
+        .serve_connection(stream, service)
+        .await
+    {
+        log::debug!("admin connection failed: {}", err);
+    }
+}
+
+fn json<T: Serialize + ?Sized>(
+    status: StatusCode,
+    body: &T,
+) -> Result<Response<Body>> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(body)?.into())
+        .map_err(|err| anyhow!("could not build response: {}", err))
+}
+
+fn error(
+    status: StatusCode,
+    reason: &str,
+) -> Response<Body> {
+    let body = Error {
+        error: reason.to_string(),
+    };
+    json(status, &body).unwrap_or_else(|_| crate::http::status(status))
+}
+
+fn accepted() -> Response<Body> {
+    crate::http::status(StatusCode::ACCEPTED)
+}
+
+fn unknown_pool(name: &str) -> Response<Body> {
+    error(StatusCode::NOT_FOUND, &format!("unknown pool: {}", name))
+}
+
+fn constant_time_eq(
+    a: &str,
+    b: &str,
+) -> bool {
+    a.len() == b.len() &&
+        a.bytes().zip(b.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use structopt::StructOpt;
+
+    use super::*;
+    use crate::worker::pool::SpawnBackoff;
+    use crate::worker::Isolation;
+
+    const CONFIG: &str = r#"
+        [pools.app]
+        script = "./src/worker/test_data/sleepy_pid_worker.php"
+        socket = "/tmp/coyote.test.sock.12"
+        size = 1
+
+        [admin]
+        listen = "unix:/tmp/coyote.test.admin.sock"
+        token = "secret"
+    "#;
+
+    fn request(
+        method: Method,
+        path: &str,
+        body: &str,
+    ) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(path)
+            .header(header::AUTHORIZATION, "Bearer secret")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    async fn body(response: Response<Body>) -> Result<Value> {
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    #[tokio::test]
+    async fn controlling_pools() -> Result<()> {
+        let path = std::env::temp_dir().join("coyote.test.admin.toml");
+        std::fs::write(&path, CONFIG)?;
+        let path = path.to_str().unwrap();
+        let opt = Opt::from_iter(&["coyote", "--config", path]);
+        let config = Config::load(&opt)?;
+        let pool = Static::new(
+            "/tmp/coyote.test.sock.12",
+            "./src/worker/test_data/sleepy_pid_worker.php",
+            1,
+            Isolation::default(),
+            SpawnBackoff::default(),
+        )
+        .await?;
+        let pools = vec![("app".to_string(), Arc::new(pool))];
+        let admin = Admin::new(pools.into_iter().collect(), opt, &config)?;
+
+        let req = Request::get("/pools").body(Body::empty())?;
+        let response = admin.handle(req).await?;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = admin.handle(request(Method::GET, "/pools", "")).await?;
+        let pools = body(response).await?;
+        assert_eq!(pools["app"]["size"], 1);
+        assert_eq!(pools["app"]["workers"][0]["state"], "idle");
+        let pid = pools["app"]["workers"][0]["pid"].as_u64().unwrap();
+
+        let scale = request(Method::POST, "/pools/app/scale", r#"{"size":2}"#);
+        let response = admin.handle(scale).await?;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let kill = format!("/pools/app/workers/{}/kill", pid);
+        let response = admin.handle(request(Method::POST, &kill, "")).await?;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let response = admin.handle(request(Method::POST, &kill, "")).await?;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let unknown = request(Method::POST, "/pools/other/reset", "");
+        let response = admin.handle(unknown).await?;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let reload = request(Method::POST, "/reload", "");
+        let response = admin.handle(reload).await?;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert_eq!(admin.pools["app"].status().size, 1);
+
+        let response = admin.handle(request(Method::GET, "/config", "")).await?;
+        let config = body(response).await?;
+        assert_eq!(config["pools"]["app"]["size"], 1);
+        assert_eq!(config["admin"].get("token"), None);
+
+        Ok(())
+    }
+}
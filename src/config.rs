@@ -0,0 +1,1508 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::str::FromStr;
+
+use anyhow::{
+    anyhow,
+    bail,
+    Result,
+};
+use globset::Glob;
+use hyper::Uri;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+use crate::http::Encoding;
+use crate::opt::Opt;
+use crate::server::Addr;
+
+pub const DEFAULT_POOL: &str = "default";
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Worker pools by name.
+    pub pools:        BTreeMap<String, PoolConfig>,
+    /// Routing rules, the first matching route wins.
+    #[serde(default)]
+    pub routes:       Vec<RouteConfig>,
+    /// HTTP listeners, `--http-listen` addresses are used if neither these,
+    /// `tls` nor `fastcgi` are configured.
+    #[serde(default)]
+    pub listeners:    Vec<ListenerConfig>,
+    /// Static files served before falling through to PHP.
+    #[serde(rename = "static")]
+    pub static_files: Option<StaticConfig>,
+    /// Response compression, disabled if omitted.
+    pub compression:  Option<CompressionConfig>,
+    /// WebSocket endpoint bridged to PHP workers, disabled if omitted.
+    pub websocket:    Option<WebSocketConfig>,
+    /// Server-Sent Events and long-polling endpoint, disabled if omitted.
+    pub events:       Option<EventsConfig>,
+    /// FastCGI listener, disabled if omitted.
+    pub fastcgi:      Option<FastCgiConfig>,
+    /// Per request log lines, disabled if omitted.
+    pub access_log:   Option<AccessLogConfig>,
+    #[serde(default)]
+    pub log:          LogConfig,
+    /// OpenTelemetry trace export, disabled if omitted.
+    pub telemetry:    Option<TelemetryConfig>,
+    /// Admin API, disabled if omitted.
+    pub admin:        Option<AdminConfig>,
+    /// Liveness and readiness endpoints, disabled if omitted.
+    pub health:       Option<HealthConfig>,
+    /// HTTPS listener, disabled if omitted.
+    pub tls:          Option<TlsConfig>,
+    #[serde(default)]
+    pub http2:        Http2Config,
+    pub http3:        Option<Http3Config>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PoolConfig {
+    /// PHP Worker script to use.
+    pub script:         String,
+    /// Unix socket to use, defaults to `/tmp/coyote.<pool name>.sock`.
+    pub socket:         Option<String>,
+    /// PHP Worker count.
+    pub size:           usize,
+    /// Maximum number of requests waiting for a free worker.
+    pub max_queue_len:  Option<usize>,
+    /// Maximum time in milliseconds a request waits for a free worker.
+    pub max_queue_wait: Option<u64>,
+    /// `Retry-After` seconds sent with 503 responses when overloaded.
+    #[serde(default = "default_retry_after")]
+    pub retry_after:    u64,
+    /// Largest request body in bytes, unlimited if omitted.
+    pub max_body_size:  Option<u64>,
+    /// Directory multipart uploads are streamed to before reaching PHP,
+    /// bodies are passed as is if omitted.
+    pub upload_dir:     Option<String>,
+    /// Milliseconds an idle worker waits before being pinged, workers
+    /// aren't pinged if omitted.
+    pub ping_interval:  Option<u64>,
+    /// Milliseconds a pinged worker has to answer before being replaced.
+    #[serde(default = "default_ping_timeout")]
+    pub ping_timeout:   u64,
+    /// Milliseconds a stopping worker has to exit before `SIGTERM` is
+    /// sent, and again before `SIGKILL`.
+    #[serde(default = "default_grace_period")]
+    pub grace_period:   u64,
+    /// Restarts workers when application files change, for development.
+    pub watch:          Option<WatchConfig>,
+    /// User workers run as, by name or id. Requires running as root.
+    pub user:           Option<String>,
+    /// Group workers run as, defaults to the user's primary group.
+    pub group:          Option<String>,
+    /// Resource limits of each worker process.
+    pub limits:         Option<LimitsConfig>,
+    /// cgroup v2 the pool's workers are placed in, limiting them together.
+    pub cgroup:         Option<CgroupConfig>,
+    /// Delays between attempts to start workers after failed ones.
+    pub backoff:        Option<BackoffConfig>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Bytes of virtual memory.
+    pub address_space: Option<u64>,
+    /// Seconds of CPU time over a worker's lifetime.
+    pub cpu_time:      Option<u64>,
+    pub open_files:    Option<u64>,
+    /// Largest core dump in bytes, 0 disables core dumps.
+    pub core_size:     Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CgroupConfig {
+    /// cgroup directory, defaults to `/sys/fs/cgroup/coyote/<pool name>`.
+    pub path:       Option<String>,
+    /// Bytes of memory, processes are OOM killed past it.
+    pub memory_max: Option<u64>,
+    /// CPUs the workers may use together, e.g. `1.5`.
+    pub cpu_max:    Option<f64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BackoffConfig {
+    /// Milliseconds to wait after the first failure, doubled after each
+    /// further one.
+    #[serde(default = "default_backoff_initial")]
+    pub initial:      u64,
+    /// Longest wait between attempts in milliseconds.
+    #[serde(default = "default_backoff_max")]
+    pub max:          u64,
+    /// Failures in a row after which the pool rejects requests until a
+    /// worker starts again.
+    #[serde(default = "default_max_failures")]
+    pub max_failures: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WatchConfig {
+    /// Directories watched recursively.
+    pub paths:    Vec<String>,
+    /// Globs changed files must match, relative to the watched directory.
+    #[serde(default = "default_watch_patterns")]
+    pub patterns: Vec<String>,
+    /// Milliseconds to wait for further changes before restarting.
+    #[serde(default = "default_debounce")]
+    pub debounce: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    /// Host to match, `*.example.com` matches any subdomain.
+    pub host:          Option<String>,
+    /// Path prefix to match.
+    pub path:          Option<String>,
+    /// Name of the pool serving matching requests.
+    pub pool:          String,
+    /// Overrides the pool's body size limit for matching requests.
+    pub max_body_size: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StaticConfig {
+    /// Document root to serve files from.
+    pub root:          String,
+    /// Extensions allowed to be served, everything if omitted.
+    pub allow:         Option<Vec<String>>,
+    /// Extensions never served.
+    #[serde(default = "default_deny")]
+    pub deny:          Vec<String>,
+    /// Serve `.br`/`.gz` siblings of files if the client accepts them.
+    #[serde(default = "default_true")]
+    pub precompressed: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CompressionConfig {
+    /// Encodings in order of preference, `br`, `gzip` or `zstd`.
+    #[serde(default = "default_encodings")]
+    pub encodings:  Vec<String>,
+    /// Responses with a known length below this aren't compressed.
+    #[serde(default = "default_min_size")]
+    pub min_size:   u64,
+    /// Compressed content types, `text/*` matches any subtype.
+    #[serde(default = "default_mime_types")]
+    pub mime_types: Vec<String>,
+    /// Compression level, defaults depend on the encoding.
+    pub level:      Option<u32>,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            encodings:  default_encodings(),
+            min_size:   default_min_size(),
+            mime_types: default_mime_types(),
+            level:      None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    /// Serving address, `unix:<path>` listens on a Unix domain socket.
+    pub listen: String,
+    /// Pool serving every request on this listener, bypassing routes.
+    pub pool:   Option<String>,
+    /// Terminates TLS on this listener if given.
+    pub tls:    Option<ListenerTlsConfig>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerTlsConfig {
+    /// Seconds between checks for changed certificate files.
+    #[serde(default = "default_reload_interval")]
+    pub reload_interval: u64,
+    /// Certificates selected by SNI, the first one is the default.
+    pub certificates:    Vec<CertificateConfig>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebSocketConfig {
+    /// Path prefix accepting WebSocket upgrades.
+    pub path:             String,
+    /// Pool receiving connection events, routes are used if omitted.
+    pub pool:             Option<String>,
+    /// Largest message in bytes accepted from clients.
+    #[serde(default = "default_max_message_size")]
+    pub max_message_size: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EventsConfig {
+    /// Path prefix accepting subscriptions.
+    pub path:         String,
+    /// Pool authorizing subscriptions, routes are used if omitted.
+    pub pool:         Option<String>,
+    /// Seconds between comments keeping idle streams open.
+    #[serde(default = "default_keep_alive")]
+    pub keep_alive:   u64,
+    /// Seconds long-polling requests wait for a message.
+    #[serde(default = "default_poll_timeout")]
+    pub poll_timeout: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LogConfig {
+    /// Log output, levels are still filtered with `RUST_LOG`.
+    #[serde(default)]
+    pub format: LogFormat,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line, with the spans an event happened in.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(format: &str) -> Result<Self> {
+        match format {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => bail!("unknown log format: {}", format),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessLogConfig {
+    /// File to append to, reopened on SIGUSR1, stdout if omitted or `-`.
+    pub path:   Option<String>,
+    /// `common`, `combined`, `json` or a template with `{field}`
+    /// placeholders.
+    #[serde(default = "default_access_log_format")]
+    pub format: String,
+}
+
+impl AccessLogConfig {
+    pub fn path(&self) -> Option<&str> {
+        self.path.as_deref().filter(|path| *path != "-")
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TelemetryConfig {
+    /// OTLP/HTTP traces endpoint, e.g. `http://localhost:4318/v1/traces`.
+    pub endpoint:       String,
+    /// `service.name` resource attribute of exported spans.
+    #[serde(default = "default_service_name")]
+    pub service_name:   String,
+    /// Milliseconds between span exports.
+    #[serde(default = "default_flush_interval")]
+    pub flush_interval: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HealthConfig {
+    /// Liveness endpoint.
+    #[serde(default = "default_health_path")]
+    pub health_path:   String,
+    /// Readiness endpoint.
+    #[serde(default = "default_ready_path")]
+    pub ready_path:    String,
+    /// Dedicated listener, the endpoints are served on every HTTP listener
+    /// if omitted.
+    pub listen:        Option<String>,
+    /// Workers every pool needs to be ready.
+    #[serde(default = "default_min_workers")]
+    pub min_workers:   usize,
+    /// Queue length past which a pool isn't ready, the pool's
+    /// `max_queue_len` if omitted.
+    pub max_queue_len: Option<usize>,
+    /// Request sent through every pool, disabled if omitted.
+    pub check:         Option<HealthCheckConfig>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HealthCheckConfig {
+    /// Path requested from PHP, a 2xx answer passes the check.
+    pub path:     String,
+    /// Seconds between checks.
+    #[serde(default = "default_check_interval")]
+    pub interval: u64,
+    /// Seconds to wait for the answer.
+    #[serde(default = "default_check_timeout")]
+    pub timeout:  u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    /// `unix:<path>` or a loopback address.
+    pub listen: String,
+    /// Bearer token requests must present, required on TCP.
+    #[serde(skip_serializing)]
+    pub token:  Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FastCgiConfig {
+    /// Serving address, `unix:<path>` listens on a Unix domain socket.
+    pub listen: String,
+    /// Pool serving every request, routes are used if omitted.
+    pub pool:   Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// HTTPS listener's serving address.
+    pub listen:          String,
+    /// Address of a listener redirecting plain HTTP to HTTPS.
+    pub redirect_listen: Option<String>,
+    /// Seconds between checks for changed certificate files.
+    #[serde(default = "default_reload_interval")]
+    pub reload_interval: u64,
+    /// Certificates selected by SNI, the first one is the default.
+    pub certificates:    Vec<CertificateConfig>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CertificateConfig {
+    /// PEM certificate chain.
+    pub cert:  String,
+    /// PEM private key.
+    pub key:   String,
+    /// Server names selecting this certificate, `*.example.com` matches any
+    /// subdomain.
+    #[serde(default)]
+    pub hosts: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Http2Config {
+    /// Negotiate HTTP/2 over TLS via ALPN.
+    #[serde(default = "default_true")]
+    pub enabled:                bool,
+    /// Accept cleartext HTTP/2 with prior knowledge on plain listeners.
+    #[serde(default)]
+    pub h2c:                    bool,
+    /// Maximum concurrent streams per connection.
+    pub max_concurrent_streams: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Http3Config {
+    /// UDP serving address, defaults to the TLS listener's address.
+    pub listen:  Option<String>,
+    /// Seconds clients may remember the `Alt-Svc` advertisement.
+    #[serde(default = "default_alt_svc_max_age")]
+    pub max_age: u64,
+}
+
+impl Default for Http2Config {
+    fn default() -> Self {
+        Self {
+            enabled:                true,
+            h2c:                    false,
+            max_concurrent_streams: None,
+        }
+    }
+}
+
+fn default_retry_after() -> u64 {
+    1
+}
+
+fn default_ping_timeout() -> u64 {
+    1000
+}
+
+fn default_grace_period() -> u64 {
+    5000
+}
+
+fn default_backoff_initial() -> u64 {
+    100
+}
+
+fn default_backoff_max() -> u64 {
+    30000
+}
+
+fn default_max_failures() -> u32 {
+    5
+}
+
+fn default_watch_patterns() -> Vec<String> {
+    vec!["*.php".to_string()]
+}
+
+fn default_debounce() -> u64 {
+    300
+}
+
+fn default_deny() -> Vec<String> {
+    vec!["php".to_string()]
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_reload_interval() -> u64 {
+    10
+}
+
+fn default_max_message_size() -> usize {
+    64 * 1024
+}
+
+fn default_access_log_format() -> String {
+    "combined".to_string()
+}
+
+fn default_health_path() -> String {
+    "/health".to_string()
+}
+
+fn default_ready_path() -> String {
+    "/ready".to_string()
+}
+
+fn default_min_workers() -> usize {
+    1
+}
+
+fn default_check_interval() -> u64 {
+    10
+}
+
+fn default_check_timeout() -> u64 {
+    5
+}
+
+fn default_service_name() -> String {
+    "coyote".to_string()
+}
+
+fn default_flush_interval() -> u64 {
+    1000
+}
+
+fn default_keep_alive() -> u64 {
+    15
+}
+
+fn default_poll_timeout() -> u64 {
+    30
+}
+
+fn default_alt_svc_max_age() -> u64 {
+    86400
+}
+
+fn default_encodings() -> Vec<String> {
+    vec!["br".to_string(), "gzip".to_string()]
+}
+
+fn default_min_size() -> u64 {
+    1024
+}
+
+fn default_mime_types() -> Vec<String> {
+    [
+        "text/*",
+        "application/javascript",
+        "application/json",
+        "application/xml",
+        "image/svg+xml",
+    ]
+    .iter()
+    .map(|mime| mime.to_string())
+    .collect()
+}
+
+impl Config {
+    /// Loads config from `opt.config` if given, otherwise builds a single
+    /// pool config from command line flags.
+    pub fn load(opt: &Opt) -> Result<Self> {
+        let mut config = match &opt.config {
+            Some(path) => {
+                let content = fs::read_to_string(path).map_err(|err| {
+                    anyhow!("could not read config {}: {}", path, err)
+                })?;
+                Self::parse(&content)?
+            }
+            None => Self::from_opt(opt),
+        };
+        // HTTPS or FastCGI only setups don't get a plain HTTP port too.
+        if config.listeners.is_empty() &&
+            config.tls.is_none() &&
+            config.fastcgi.is_none()
+        {
+            config.listeners = opt
+                .http_listen
+                .iter()
+                .map(|listen| ListenerConfig {
+                    listen: listen.clone(),
+                    pool:   None,
+                    tls:    None,
+                })
+                .collect();
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn parse(content: &str) -> Result<Self> {
+        toml::from_str(content)
+            .map_err(|err| anyhow!("could not parse config: {}", err))
+    }
+
+    fn from_opt(opt: &Opt) -> Self {
+        let mut pools = BTreeMap::new();
+        pools.insert(DEFAULT_POOL.to_string(), PoolConfig {
+            script:         opt.worker_script.clone(),
+            socket:         Some(opt.unix_socket.clone()),
+            size:           opt.worker_count,
+            max_queue_len:  opt.max_queue_len,
+            max_queue_wait: opt.max_queue_wait,
+            retry_after:    opt.retry_after,
+            max_body_size:  None,
+            upload_dir:     None,
+            ping_interval:  None,
+            ping_timeout:   default_ping_timeout(),
+            grace_period:   default_grace_period(),
+            watch:          if opt.watch.is_empty() {
+                None
+            } else {
+                Some(WatchConfig {
+                    paths:    opt.watch.clone(),
+                    patterns: default_watch_patterns(),
+                    debounce: default_debounce(),
+                })
+            },
+            user:           None,
+            group:          None,
+            limits:         None,
+            cgroup:         None,
+            backoff:        None,
+        });
+
+        Self {
+            pools,
+            routes: vec![RouteConfig {
+                host:          None,
+                path:          None,
+                pool:          DEFAULT_POOL.to_string(),
+                max_body_size: None,
+            }],
+            listeners: vec![],
+            static_files: opt.document_root.clone().map(|root| StaticConfig {
+                root,
+                allow: None,
+                deny: default_deny(),
+                precompressed: true,
+            }),
+            compression: if opt.compress {
+                Some(CompressionConfig::default())
+            } else {
+                None
+            },
+            websocket: None,
+            events: None,
+            fastcgi: opt.fastcgi_listen.clone().map(|listen| FastCgiConfig {
+                listen,
+                pool: None,
+            }),
+            access_log: opt.access_log.clone().map(|path| AccessLogConfig {
+                path:   Some(path),
+                format: default_access_log_format(),
+            }),
+            log: LogConfig {
+                format: opt.log_format,
+            },
+            telemetry: None,
+            admin: Some(AdminConfig {
+                listen: opt.admin_listen.clone(),
+                token:  None,
+            }),
+            health: None,
+            tls: None,
+            http2: Http2Config::default(),
+            http3: None,
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.pools.is_empty() {
+            bail!("at least one pool must be configured");
+        }
+
+        for route in &self.routes {
+            if !self.pools.contains_key(&route.pool) {
+                bail!("route refers to unknown pool: {}", route.pool);
+            }
+        }
+
+        for listener in &self.listeners {
+            let addr = listener.listen.parse::<Addr>()?;
+            if let Some(pool) = &listener.pool {
+                if !self.pools.contains_key(pool) {
+                    bail!("listener refers to unknown pool: {}", pool);
+                }
+            }
+            if let Some(tls) = &listener.tls {
+                if let Addr::Unix(_) = addr {
+                    bail!("TLS is not supported on Unix sockets");
+                }
+                if tls.certificates.is_empty() {
+                    bail!("at least one TLS certificate must be configured");
+                }
+            }
+        }
+
+        let websocket = self.websocket.as_ref();
+        if let Some(pool) = websocket.and_then(|ws| ws.pool.as_ref()) {
+            if !self.pools.contains_key(pool) {
+                bail!("WebSocket endpoint refers to unknown pool: {}", pool);
+            }
+        }
+
+        if let Some(events) = &self.events {
+            if events.keep_alive == 0 {
+                bail!("events keep-alive interval must be positive");
+            }
+            if let Some(pool) = &events.pool {
+                if !self.pools.contains_key(pool) {
+                    bail!("events endpoint refers to unknown pool: {}", pool);
+                }
+            }
+        }
+
+        if let Some(telemetry) = &self.telemetry {
+            let endpoint = telemetry.endpoint.parse::<Uri>()?;
+            if endpoint.scheme_str() != Some("http") {
+                bail!("OTLP endpoint must be http: {}", endpoint);
+            }
+            if telemetry.flush_interval == 0 {
+                bail!("telemetry flush interval must be positive");
+            }
+        }
+
+        if let Some(health) = &self.health {
+            if let Some(listen) = &health.listen {
+                listen.parse::<Addr>()?;
+            }
+            for path in &[&health.health_path, &health.ready_path] {
+                if !path.starts_with('/') {
+                    bail!("health endpoint must start with /: {}", path);
+                }
+            }
+            if let Some(check) = &health.check {
+                if check.interval == 0 || check.timeout == 0 {
+                    bail!("health check interval and timeout must be positive");
+                }
+            }
+        }
+
+        if let Some(admin) = &self.admin {
+            if let Addr::Tcp(addr) = admin.listen.parse::<Addr>()? {
+                if !addr.ip().is_loopback() {
+                    bail!("admin API must listen on a loopback address");
+                }
+                if admin.token.is_none() {
+                    bail!("admin API on TCP requires a token");
+                }
+            }
+        }
+
+        if let Some(fastcgi) = &self.fastcgi {
+            fastcgi.listen.parse::<Addr>()?;
+            if let Some(pool) = &fastcgi.pool {
+                if !self.pools.contains_key(pool) {
+                    bail!("FastCGI listener refers to unknown pool: {}", pool);
+                }
+            }
+        }
+
+        if let Some(compression) = &self.compression {
+            for encoding in &compression.encodings {
+                Encoding::from_name(encoding)?;
+            }
+        }
+
+        if let Some(tls) = &self.tls {
+            if tls.certificates.is_empty() {
+                bail!("at least one TLS certificate must be configured");
+            }
+            // Only the [tls] listener serves HTTP/3 and advertises it.
+            if self.listeners.iter().any(|listener| listener.tls.is_some()) {
+                bail!("[tls] can't be combined with TLS listeners");
+            }
+        }
+
+        if self.http3.is_some() && self.tls.is_none() {
+            bail!("HTTP/3 requires [tls] to be configured");
+        }
+
+        let mut sockets = BTreeMap::new();
+        for (name, pool) in &self.pools {
+            if pool.ping_interval == Some(0) || pool.ping_timeout == 0 {
+                bail!(
+                    "pool {} ping interval and timeout must be positive",
+                    name
+                );
+            }
+            let cpus = pool.cgroup.as_ref().and_then(|cgroup| cgroup.cpu_max);
+            if cpus.is_some_and(|cpus| cpus <= 0.0) {
+                bail!("pool {} cgroup cpu_max must be positive", name);
+            }
+            if let Some(backoff) = &pool.backoff {
+                if backoff.initial == 0 || backoff.max < backoff.initial {
+                    bail!(
+                        "pool {} backoff must be positive and at most its max",
+                        name
+                    );
+                }
+                if backoff.max_failures == 0 {
+                    bail!(
+                        "pool {} backoff max_failures must be positive",
+                        name
+                    );
+                }
+            }
+            if let Some(watch) = &pool.watch {
+                if watch.paths.is_empty() {
+                    bail!("pool {} must watch at least one path", name);
+                }
+                for pattern in &watch.patterns {
+                    Glob::new(pattern).map_err(|err| {
+                        anyhow!("invalid watch pattern {}: {}", pattern, err)
+                    })?;
+                }
+            }
+            if let Some(other) = sockets.insert(pool.socket(name), name) {
+                bail!("pools {} and {} share the same socket", other, name);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl PoolConfig {
+    pub fn socket(
+        &self,
+        name: &str,
+    ) -> String {
+        self.socket
+            .clone()
+            .unwrap_or_else(|| format!("/tmp/coyote.{}.sock", name))
+    }
+
+    pub fn cgroup_path(
+        &self,
+        name: &str,
+    ) -> Option<String> {
+        let cgroup = self.cgroup.as_ref()?;
+        Some(
+            cgroup
+                .path
+                .clone()
+                .unwrap_or_else(|| format!("/sys/fs/cgroup/coyote/{}", name)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use structopt::StructOpt;
+
+    use super::*;
+
+    #[test]
+    fn parsing_pools_and_routes() -> Result<()> {
+        let config = Config::parse(
+            r#"
+            [pools.api]
+            script = "api.php"
+            size = 8
+            max_queue_len = 100
+
+            [pools.reports]
+            script = "reports.php"
+            socket = "/tmp/reports.sock"
+            size = 2
+            max_queue_wait = 5000
+            retry_after = 30
+            max_body_size = 1048576
+            upload_dir = "/tmp/uploads"
+            ping_interval = 30000
+            ping_timeout = 500
+            grace_period = 10000
+            user = "www-data"
+
+            [pools.reports.limits]
+            address_space = 536870912
+            open_files = 1024
+            core_size = 0
+
+            [pools.reports.cgroup]
+            memory_max = 1073741824
+            cpu_max = 1.5
+
+            [pools.reports.backoff]
+            initial = 500
+            max_failures = 10
+
+            [pools.reports.watch]
+            paths = ["src", "templates"]
+            patterns = ["*.php", "*.twig"]
+
+            [[routes]]
+            path = "/reports/"
+            pool = "reports"
+            max_body_size = 4096
+
+            [[routes]]
+            pool = "api"
+            "#,
+        )?;
+        config.validate()?;
+
+        let api = &config.pools["api"];
+        assert_eq!(api.size, 8);
+        assert_eq!(api.max_queue_len, Some(100));
+        assert_eq!(api.retry_after, 1);
+        assert_eq!(api.socket("api"), "/tmp/coyote.api.sock");
+
+        let reports = &config.pools["reports"];
+        assert_eq!(reports.max_queue_wait, Some(5000));
+        assert_eq!(reports.retry_after, 30);
+        assert_eq!(reports.socket("reports"), "/tmp/reports.sock");
+        assert_eq!(reports.max_body_size, Some(1048576));
+        assert_eq!(reports.upload_dir.as_deref(), Some("/tmp/uploads"));
+        assert_eq!(reports.ping_interval, Some(30000));
+        assert_eq!(reports.ping_timeout, 500);
+        assert_eq!(api.ping_interval, None);
+        assert_eq!(api.ping_timeout, 1000);
+        assert_eq!(reports.grace_period, 10000);
+        assert_eq!(api.grace_period, 5000);
+        let watch = reports.watch.as_ref().unwrap();
+        assert_eq!(watch.paths, vec!["src", "templates"]);
+        assert_eq!(watch.debounce, 300);
+        assert!(api.watch.is_none());
+        let backoff = reports.backoff.as_ref().unwrap();
+        assert_eq!(backoff.initial, 500);
+        assert_eq!(backoff.max, 30000);
+        assert_eq!(backoff.max_failures, 10);
+        assert!(api.backoff.is_none());
+        assert_eq!(reports.user.as_deref(), Some("www-data"));
+        assert_eq!(reports.group, None);
+        let limits = reports.limits.as_ref().unwrap();
+        assert_eq!(limits.address_space, Some(536870912));
+        assert_eq!(limits.cpu_time, None);
+        assert_eq!(limits.core_size, Some(0));
+        assert_eq!(
+            reports.cgroup_path("reports").as_deref(),
+            Some("/sys/fs/cgroup/coyote/reports")
+        );
+        assert_eq!(api.cgroup_path("api"), None);
+
+        assert_eq!(config.routes.len(), 2);
+        assert_eq!(config.routes[0].path.as_deref(), Some("/reports/"));
+        assert_eq!(config.routes[0].max_body_size, Some(4096));
+        assert_eq!(config.routes[1].pool, "api");
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejecting_routes_to_unknown_pools() -> Result<()> {
+        let config = Config::parse(
+            r#"
+            [pools.api]
+            script = "api.php"
+            size = 8
+
+            [[routes]]
+            pool = "reports"
+            "#,
+        )?;
+
+        assert!(config.validate().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejecting_shared_sockets() -> Result<()> {
+        let config = Config::parse(
+            r#"
+            [pools.api]
+            script = "api.php"
+            socket = "/tmp/coyote.sock"
+            size = 8
+
+            [pools.reports]
+            script = "reports.php"
+            socket = "/tmp/coyote.sock"
+            size = 2
+            "#,
+        )?;
+
+        assert!(config.validate().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn parsing_static_files() -> Result<()> {
+        let config = Config::parse(
+            r#"
+            [pools.app]
+            script = "index.php"
+            size = 4
+
+            [static]
+            root = "public"
+            allow = ["css", "js"]
+            "#,
+        )?;
+
+        let static_files = config.static_files.unwrap();
+        assert_eq!(static_files.root, "public");
+        assert_eq!(static_files.allow, Some(vec!["css".into(), "js".into()]));
+        assert_eq!(static_files.deny, vec!["php"]);
+        assert!(static_files.precompressed);
+
+        Ok(())
+    }
+
+    #[test]
+    fn parsing_compression() -> Result<()> {
+        let config = Config::parse(
+            r#"
+            [pools.app]
+            script = "index.php"
+            size = 4
+
+            [compression]
+            encodings = ["zstd", "gzip"]
+            min_size = 256
+            "#,
+        )?;
+        config.validate()?;
+
+        let compression = config.compression.unwrap();
+        assert_eq!(compression.encodings, vec!["zstd", "gzip"]);
+        assert_eq!(compression.min_size, 256);
+        assert_eq!(compression.mime_types, default_mime_types());
+
+        let config = Config::parse(
+            r#"
+            [pools.app]
+            script = "index.php"
+            size = 4
+
+            [compression]
+            encodings = ["deflate"]
+            "#,
+        )?;
+        assert!(config.validate().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn parsing_tls() -> Result<()> {
+        let config = Config::parse(
+            r#"
+            [pools.app]
+            script = "index.php"
+            size = 4
+
+            [tls]
+            listen = "0.0.0.0:443"
+            redirect_listen = "0.0.0.0:80"
+
+            [[tls.certificates]]
+            cert = "example.com.pem"
+            key = "example.com.key"
+            hosts = ["example.com", "*.example.com"]
+
+            [[tls.certificates]]
+            cert = "example.org.pem"
+            key = "example.org.key"
+            "#,
+        )?;
+        config.validate()?;
+
+        let tls = config.tls.unwrap();
+        assert_eq!(tls.listen, "0.0.0.0:443");
+        assert_eq!(tls.redirect_listen.as_deref(), Some("0.0.0.0:80"));
+        assert_eq!(tls.reload_interval, 10);
+        assert_eq!(tls.certificates.len(), 2);
+        assert_eq!(tls.certificates[0].hosts.len(), 2);
+        assert!(tls.certificates[1].hosts.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn parsing_http2() -> Result<()> {
+        let config = Config::parse(
+            r#"
+            [pools.app]
+            script = "index.php"
+            size = 4
+            "#,
+        )?;
+        assert!(config.http2.enabled);
+        assert!(!config.http2.h2c);
+
+        let config = Config::parse(
+            r#"
+            [pools.app]
+            script = "index.php"
+            size = 4
+
+            [http2]
+            h2c = true
+            max_concurrent_streams = 64
+            "#,
+        )?;
+        assert!(config.http2.enabled);
+        assert!(config.http2.h2c);
+        assert_eq!(config.http2.max_concurrent_streams, Some(64));
+
+        Ok(())
+    }
+
+    #[test]
+    fn parsing_listeners() -> Result<()> {
+        let config = Config::parse(
+            r#"
+            [pools.app]
+            script = "index.php"
+            size = 4
+
+            [pools.admin]
+            script = "admin.php"
+            size = 1
+
+            [[listeners]]
+            listen = "0.0.0.0:8080"
+
+            [[listeners]]
+            listen = "[::]:8080"
+
+            [[listeners]]
+            listen = "unix:/run/coyote.sock"
+            pool = "admin"
+
+            [[listeners]]
+            listen = "0.0.0.0:8443"
+
+            [listeners.tls]
+            [[listeners.tls.certificates]]
+            cert = "example.com.pem"
+            key = "example.com.key"
+            "#,
+        )?;
+        config.validate()?;
+
+        assert_eq!(config.listeners.len(), 4);
+        assert_eq!(config.listeners[2].pool.as_deref(), Some("admin"));
+        let tls = config.listeners[3].tls.as_ref().unwrap();
+        assert_eq!(tls.reload_interval, 10);
+        assert_eq!(tls.certificates.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejecting_mixed_tls_configs() -> Result<()> {
+        let config = Config::parse(
+            r#"
+            [pools.app]
+            script = "index.php"
+            size = 4
+
+            [tls]
+            listen = "0.0.0.0:443"
+            certificates = [{ cert = "a.pem", key = "a.key" }]
+
+            [[listeners]]
+            listen = "0.0.0.0:8443"
+
+            [listeners.tls]
+            certificates = [{ cert = "b.pem", key = "b.key" }]
+            "#,
+        )?;
+        assert!(config.validate().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn adding_default_listeners() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let load = |content: &str| {
+            let path = dir.path().join("coyote.toml");
+            fs::write(&path, content)?;
+            let path = path.to_str().unwrap();
+            Config::load(&Opt::from_iter(&["coyote", "--config", path]))
+        };
+        let pool = r#"
+            [pools.app]
+            script = "index.php"
+            size = 4
+        "#;
+
+        let config = load(pool)?;
+        assert_eq!(config.listeners.len(), 1);
+        assert_eq!(config.listeners[0].listen, "127.0.0.1:3000");
+
+        let config = load(&format!(
+            r#"{}
+            [tls]
+            listen = "0.0.0.0:443"
+            certificates = [{{ cert = "a.pem", key = "a.key" }}]
+            "#,
+            pool
+        ))?;
+        assert!(config.listeners.is_empty());
+
+        let config = load(&format!(
+            r#"{}
+            [fastcgi]
+            listen = "127.0.0.1:9000"
+            "#,
+            pool
+        ))?;
+        assert!(config.listeners.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejecting_invalid_listeners() -> Result<()> {
+        let invalid = [
+            r#"listen = "localhost""#,
+            r#"listen = "0.0.0.0:80"
+            pool = "unknown""#,
+            r#"listen = "unix:/run/coyote.sock"
+            [listeners.tls]
+            certificates = [{ cert = "a.pem", key = "a.key" }]"#,
+        ];
+        for listener in &invalid {
+            let config = Config::parse(&format!(
+                r#"
+                [pools.app]
+                script = "index.php"
+                size = 4
+
+                [[listeners]]
+                {}
+                "#,
+                listener
+            ))?;
+            assert!(config.validate().is_err(), "{}", listener);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn parsing_websocket() -> Result<()> {
+        let config = Config::parse(
+            r#"
+            [pools.app]
+            script = "index.php"
+            size = 4
+
+            [websocket]
+            path = "/ws/"
+            pool = "app"
+            "#,
+        )?;
+        config.validate()?;
+
+        let websocket = config.websocket.unwrap();
+        assert_eq!(websocket.path, "/ws/");
+        assert_eq!(websocket.pool.as_deref(), Some("app"));
+        assert_eq!(websocket.max_message_size, 65536);
+
+        Ok(())
+    }
+
+    #[test]
+    fn parsing_log_format() -> Result<()> {
+        let config = Config::parse(
+            r#"
+            [pools.app]
+            script = "index.php"
+            size = 4
+
+            [log]
+            format = "json"
+            "#,
+        )?;
+        assert_eq!(config.log.format, LogFormat::Json);
+        assert_eq!("text".parse::<LogFormat>()?, LogFormat::Text);
+        assert!("xml".parse::<LogFormat>().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn parsing_health() -> Result<()> {
+        let config = Config::parse(
+            r#"
+            [pools.app]
+            script = "index.php"
+            size = 4
+
+            [health]
+            listen = "127.0.0.1:8081"
+
+            [health.check]
+            path = "/status.php"
+            "#,
+        )?;
+        config.validate()?;
+
+        let health = config.health.unwrap();
+        assert_eq!(health.health_path, "/health");
+        assert_eq!(health.ready_path, "/ready");
+        assert_eq!(health.min_workers, 1);
+        let check = health.check.unwrap();
+        assert_eq!((check.interval, check.timeout), (10, 5));
+
+        let config = Config::parse(
+            r#"
+            [pools.app]
+            script = "index.php"
+            size = 4
+
+            [health]
+            ready_path = "ready"
+            "#,
+        )?;
+        assert!(config.validate().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn parsing_admin() -> Result<()> {
+        let parse = |admin: &str| {
+            Config::parse(&format!(
+                r#"
+                [pools.app]
+                script = "index.php"
+                size = 4
+
+                [admin]
+                {}
+                "#,
+                admin
+            ))
+            .and_then(|config| config.validate().map(|_| config))
+        };
+
+        let config = parse(r#"listen = "unix:/run/coyote.admin.sock""#)?;
+        assert!(config.admin.unwrap().token.is_none());
+        let config = parse("listen = \"127.0.0.1:9001\"\ntoken = \"secret\"")?;
+        let dump = serde_json::to_value(&config)?;
+        assert_eq!(dump["admin"], serde_json::json!({
+            "listen": "127.0.0.1:9001",
+        }));
+
+        assert!(parse(r#"listen = "127.0.0.1:9001""#).is_err());
+        let public = "listen = \"0.0.0.0:9001\"\ntoken = \"secret\"";
+        assert!(parse(public).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn parsing_telemetry() -> Result<()> {
+        let config = Config::parse(
+            r#"
+            [pools.app]
+            script = "index.php"
+            size = 4
+
+            [telemetry]
+            endpoint = "http://localhost:4318/v1/traces"
+            "#,
+        )?;
+        config.validate()?;
+
+        let telemetry = config.telemetry.unwrap();
+        assert_eq!(telemetry.service_name, "coyote");
+        assert_eq!(telemetry.flush_interval, 1000);
+
+        let config = Config::parse(
+            r#"
+            [pools.app]
+            script = "index.php"
+            size = 4
+
+            [telemetry]
+            endpoint = "https://collector/v1/traces"
+            "#,
+        )?;
+        assert!(config.validate().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn parsing_access_log() -> Result<()> {
+        let config = Config::parse(
+            r#"
+            [pools.app]
+            script = "index.php"
+            size = 4
+
+            [access_log]
+            path = "-"
+            "#,
+        )?;
+        config.validate()?;
+
+        let access_log = config.access_log.unwrap();
+        assert_eq!(access_log.path(), None);
+        assert_eq!(access_log.format, "combined");
+
+        Ok(())
+    }
+
+    #[test]
+    fn parsing_events() -> Result<()> {
+        let config = Config::parse(
+            r#"
+            [pools.app]
+            script = "index.php"
+            size = 4
+
+            [events]
+            path = "/events"
+            pool = "app"
+            poll_timeout = 10
+            "#,
+        )?;
+        config.validate()?;
+
+        let events = config.events.unwrap();
+        assert_eq!(events.path, "/events");
+        assert_eq!(events.pool.as_deref(), Some("app"));
+        assert_eq!(events.keep_alive, 15);
+        assert_eq!(events.poll_timeout, 10);
+
+        Ok(())
+    }
+
+    #[test]
+    fn parsing_fastcgi() -> Result<()> {
+        let config = Config::parse(
+            r#"
+            [pools.app]
+            script = "index.php"
+            size = 4
+
+            [fastcgi]
+            listen = "unix:/run/coyote-fcgi.sock"
+            pool = "app"
+            "#,
+        )?;
+        config.validate()?;
+
+        let fastcgi = config.fastcgi.unwrap();
+        assert_eq!(fastcgi.listen, "unix:/run/coyote-fcgi.sock");
+        assert_eq!(fastcgi.pool.as_deref(), Some("app"));
+
+        let config = Config::parse(
+            r#"
+            [pools.app]
+            script = "index.php"
+            size = 4
+
+            [fastcgi]
+            listen = "127.0.0.1:9000"
+            pool = "unknown"
+            "#,
+        )?;
+        assert!(config.validate().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn parsing_http3() -> Result<()> {
+        let config = Config::parse(
+            r#"
+            [pools.app]
+            script = "index.php"
+            size = 4
+
+            [tls]
+            listen = "0.0.0.0:443"
+
+            [[tls.certificates]]
+            cert = "example.com.pem"
+            key = "example.com.key"
+
+            [http3]
+            "#,
+        )?;
+        config.validate()?;
+
+        let http3 = config.http3.unwrap();
+        assert_eq!(http3.listen, None);
+        assert_eq!(http3.max_age, 86400);
+
+        let config = Config::parse(
+            r#"
+            [pools.app]
+            script = "index.php"
+            size = 4
+
+            [http3]
+            listen = "0.0.0.0:443"
+            "#,
+        )?;
+        assert!(config.validate().is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,385 @@
+use std::collections::BTreeMap;
+use std::fs;
+
+use anyhow::{
+    anyhow,
+    bail,
+    Result,
+};
+use hyper::client::conn;
+use hyper::{
+    header,
+    Body,
+    Method,
+    Request,
+};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use tokio::io::{
+    AsyncRead,
+    AsyncWrite,
+};
+use tokio::net::{
+    TcpStream,
+    UnixStream,
+};
+
+use crate::config::Config;
+use crate::opt::{
+    Command,
+    Control,
+};
+use crate::server::Addr;
+use crate::worker::pool::{
+    PoolStatus,
+    WorkerState,
+};
+
+/// Runs a subcommand controlling a running server.
+pub async fn run(command: Command) -> Result<()> {
+    match command {
+        Command::Serve(_) => bail!("serving is not a control command"),
+        Command::Reload(control) => {
+            Client::new(&control)?.post("/reload").await?;
+            println!("Reloading");
+        }
+        Command::Stop(control) => {
+            Client::new(&control)?.post("/stop").await?;
+            println!("Stopping");
+        }
+        Command::Status(control) => {
+            let client = Client::new(&control)?;
+            let status = client.get::<Value>("/status").await?;
+            let pools = client.get("/pools").await?;
+            print!("{}", format_status(&status, &pools));
+        }
+        Command::Workers(control) => {
+            let pools = Client::new(&control)?.get("/pools").await?;
+            print!("{}", format_workers(&pools));
+        }
+    }
+
+    Ok(())
+}
+
+/// Admin API client, one connection per request.
+struct Client {
+    addr:  Addr,
+    token: Option<String>,
+}
+
+impl Client {
+    /// Uses the `[admin]` section of `--config` if given, `--token` takes
+    /// precedence over its token.
+    fn new(control: &Control) -> Result<Self> {
+        let mut listen = control.admin.clone();
+        let mut token = None;
+        if let Some(path) = &control.config {
+            let content = fs::read_to_string(path).map_err(|err| {
+                anyhow!("could not read config {}: {}", path, err)
+            })?;
+            let admin = Config::parse(&content)?.admin.ok_or_else(|| {
+                anyhow!("admin API is not configured in {}", path)
+            })?;
+            listen = admin.listen;
+            token = admin.token;
+        }
+
+        Ok(Self {
+            addr:  listen.parse()?,
+            token: control.token.clone().or(token),
+        })
+    }
+
+    async fn get<T: DeserializeOwned>(
+        &self,
+        path: &str,
+    ) -> Result<T> {
+        let body = self.request(Method::GET, path).await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    async fn post(
+        &self,
+        path: &str,
+    ) -> Result<()> {
+        self.request(Method::POST, path).await?;
+        Ok(())
+    }
+
+    async fn request(
+        &self,
+        method: Method,
+        path: &str,
+    ) -> Result<hyper::body::Bytes> {
+        let mut req = Request::builder()
+            .method(method)
+            .uri(path)
+            .header(header::HOST, "coyote");
+        if let Some(token) = &self.token {
+            let bearer = format!("Bearer {}", token);
+            req = req.header(header::AUTHORIZATION, bearer);
+        }
+        let req = req.body(Body::empty())?;
+
+        let response = send(&self.addr, req)
+            .await
+            .map_err(|err| anyhow!("could not reach admin API: {}", err))?;
+
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        if !status.is_success() {
+            let reason = serde_json::from_slice::<Value>(&body)
+                .ok()
+                .and_then(|body| body["error"].as_str().map(String::from))
+                .unwrap_or_else(|| status.to_string());
+            bail!("admin API answered {}: {}", status.as_u16(), reason);
+        }
+
+        Ok(body)
+    }
+}
+
+async fn send(
+    addr: &Addr,
+    req: Request<Body>,
+) -> Result<hyper::Response<Body>> {
+    match addr {
+        Addr::Tcp(addr) => send_on(TcpStream::connect(addr).await?, req).await,
+        Addr::Unix(path) => {
+            send_on(UnixStream::connect(path).await?, req).await
+        }
+    }
+}
+
+async fn send_on(
+    stream: impl AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    req: Request<Body>,
+) -> Result<hyper::Response<Body>> {
+    let (mut sender, connection) = conn::handshake(stream).await?;
+    tokio::spawn(async move {
+        if let Err(err) = connection.await {
+            log::debug!("admin connection failed: {}", err);
+        }
+    });
+    Ok(sender.send_request(req).await?)
+}
+
+fn format_status(
+    status: &Value,
+    pools: &BTreeMap<String, PoolStatus>,
+) -> String {
+    let mut rows = vec![row(&[
+        "POOL", "SIZE", "WORKERS", "BUSY", "IDLE", "QUEUE",
+    ])];
+    for (name, pool) in pools {
+        let count = |state| {
+            let workers = pool.workers.iter();
+            workers.filter(|worker| worker.state == state).count()
+        };
+        rows.push(vec![
+            name.clone(),
+            pool.size.to_string(),
+            pool.workers.len().to_string(),
+            count(WorkerState::Busy).to_string(),
+            count(WorkerState::Idle).to_string(),
+            pool.queue_len.to_string(),
+        ]);
+    }
+
+    format!(
+        "coyote {}, pid {}, up {}\n\n{}",
+        status["version"].as_str().unwrap_or("?"),
+        status["pid"],
+        format_duration(status["uptime"].as_u64().unwrap_or_default()),
+        table(&rows)
+    )
+}
+
+fn format_workers(pools: &BTreeMap<String, PoolStatus>) -> String {
+    let mut rows = vec![row(&[
+        "POOL", "PID", "STATE", "UPTIME", "REQUESTS", "MEMORY",
+    ])];
+    for (name, pool) in pools {
+        for worker in &pool.workers {
+            rows.push(vec![
+                name.clone(),
+                worker.pid.to_string(),
+                format!("{:?}", worker.state).to_lowercase(),
+                format_duration(worker.uptime),
+                worker.requests.to_string(),
+                worker.memory.map_or_else(|| "-".into(), format_bytes),
+            ]);
+        }
+    }
+
+    table(&rows)
+}
+
+fn row(cells: &[&str]) -> Vec<String> {
+    cells.iter().map(|cell| cell.to_string()).collect()
+}
+
+/// Left aligned columns separated by two spaces.
+fn table(rows: &[Vec<String>]) -> String {
+    let mut widths = vec![];
+    for row in rows {
+        widths.resize(widths.len().max(row.len()), 0);
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let mut table = String::new();
+    for row in rows {
+        let cells = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect::<Vec<_>>();
+        table.push_str(cells.join("  ").trim_end());
+        table.push('\n');
+    }
+    table
+}
+
+/// Two most significant units, e.g. `1h02m`.
+fn format_duration(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m{:02}s", secs / 60, secs % 60),
+        3600..=86399 => format!("{}h{:02}m", secs / 3600, secs % 3600 / 60),
+        _ => format!("{}d{:02}h", secs / 86400, secs % 86400 / 3600),
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["K", "M", "G", "T"];
+    if bytes < 1024 {
+        return format!("{}B", bytes);
+    }
+
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1}{}", size, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use structopt::StructOpt;
+
+    use super::*;
+    use crate::admin::{
+        self,
+        Admin,
+    };
+    use crate::opt::Opt;
+    use crate::server;
+    use crate::worker::pool::{
+        SpawnBackoff,
+        Static,
+        WorkerStatus,
+    };
+    use crate::worker::Isolation;
+
+    #[test]
+    fn formatting_tables() {
+        assert_eq!(format_duration(42), "42s");
+        assert_eq!(format_duration(3723), "1h02m");
+        assert_eq!(format_duration(2 * 86400 + 7200), "2d02h");
+        assert_eq!(format_bytes(512), "512B");
+        assert_eq!(format_bytes(25 * 1024 * 1024 + 512 * 1024), "25.5M");
+
+        let pools = vec![("app".to_string(), PoolStatus {
+            size:      2,
+            queue_len: 0,
+            workers:   vec![WorkerStatus {
+                pid:      4242,
+                state:    WorkerState::Busy,
+                uptime:   65,
+                requests: 12,
+                memory:   None,
+            }],
+            failing:   false,
+        })]
+        .into_iter()
+        .collect();
+        assert_eq!(
+            format_workers(&pools),
+            concat!(
+                "POOL  PID   STATE  UPTIME  REQUESTS  MEMORY\n",
+                "app   4242  busy   1m05s   12        -\n",
+            )
+        );
+
+        let status = serde_json::json!({
+            "version": "0.1.0",
+            "pid": 1,
+            "uptime": 3,
+        });
+        assert_eq!(
+            format_status(&status, &pools),
+            concat!(
+                "coyote 0.1.0, pid 1, up 3s\n",
+                "\n",
+                "POOL  SIZE  WORKERS  BUSY  IDLE  QUEUE\n",
+                "app   2     1        1     0     0\n",
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn controlling_a_running_server() -> Result<()> {
+        let socket = "/tmp/coyote.test.admin.13.sock";
+        let opt = Opt::from_iter(&[
+            "coyote",
+            "--unix-socket",
+            "/tmp/coyote.test.sock.13",
+            "--worker-script",
+            "./src/worker/test_data/sleepy_pid_worker.php",
+            "--worker-count",
+            "1",
+        ]);
+        let config = Config::load(&opt)?;
+        let pool = Static::new(
+            "/tmp/coyote.test.sock.13",
+            "./src/worker/test_data/sleepy_pid_worker.php",
+            1,
+            Isolation::default(),
+            SpawnBackoff::default(),
+        )
+        .await?;
+        let pools = vec![("default".to_string(), Arc::new(pool))];
+        let admin = Arc::new(Admin::new(
+            pools.into_iter().collect(),
+            opt,
+            &config,
+        )?);
+        let listener = server::bind_unix(socket.as_ref())?;
+        tokio::spawn(admin::serve_unix(listener, admin.clone()));
+
+        let client = Client::new(&Control {
+            admin:  format!("unix:{}", socket),
+            config: None,
+            token:  None,
+        })?;
+        let pools = client.get::<BTreeMap<String, PoolStatus>>("/pools").await?;
+        assert_eq!(pools["default"].workers.len(), 1);
+        let err = client.post("/pools/other/reset").await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "admin API answered 404: unknown pool: other"
+        );
+
+        client.post("/stop").await?;
+        admin.stopped().await;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,454 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{
+    anyhow,
+    Result,
+};
+use futures::stream;
+use hyper::{
+    header,
+    Body,
+    Method,
+    Request,
+    Response,
+    StatusCode,
+};
+use percent_encoding::percent_decode_str;
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tokio::time::{
+    interval_at,
+    timeout,
+    Instant,
+    Interval,
+};
+
+use crate::http::{
+    self,
+    PayloadTooLarge,
+    RequestEnvelope,
+    Router,
+    Upstream,
+};
+use crate::websocket::{
+    Commands,
+    ConnectionId,
+    Hub,
+    Outgoing,
+};
+use crate::worker::pool::Overloaded;
+
+/// Subscription delivered to PHP workers, answered with [`Commands`]
+/// joining the connection to the topics it may receive.
+#[derive(Debug, Serialize)]
+struct SubscribeEnvelope<'a> {
+    event:      &'a str,
+    connection: ConnectionId,
+    request:    &'a RequestEnvelope,
+    /// Topics the client asked for.
+    topics:     &'a [String],
+}
+
+/// Event delivered to long-polling clients.
+#[derive(Debug, Serialize)]
+struct Event {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    event: Option<String>,
+    data:  String,
+}
+
+/// Streams messages workers publish to topics to subscribed clients, as
+/// Server-Sent Events or by long-polling, without holding a worker.
+pub struct Events {
+    /// Path prefix accepting subscriptions.
+    path:         String,
+    /// Pool authorizing subscriptions, routes are used if `None`.
+    upstream:     Option<Arc<Upstream>>,
+    hub:          Arc<Hub>,
+    /// Interval of comments keeping idle streams open.
+    keep_alive:   Duration,
+    /// Time long-polling requests wait for a message.
+    poll_timeout: Duration,
+}
+
+impl Events {
+    pub fn new(
+        path: String,
+        upstream: Option<Arc<Upstream>>,
+        hub: Arc<Hub>,
+        keep_alive: Duration,
+        poll_timeout: Duration,
+    ) -> Self {
+        Self {
+            path,
+            upstream,
+            hub,
+            keep_alive,
+            poll_timeout,
+        }
+    }
+
+    /// Whether `req` subscribes to this endpoint.
+    pub fn matches(
+        &self,
+        req: &Request<Body>,
+    ) -> bool {
+        req.method() == Method::GET &&
+            req.uri().path().starts_with(self.path.as_str())
+    }
+
+    /// Subscribes to the `topic` query parameters a worker allows, clients
+    /// accepting `text/event-stream` get a stream, others wait for the next
+    /// messages.
+    pub async fn subscribe(
+        &self,
+        req: Request<Body>,
+        host: Option<&str>,
+        router: &Router,
+    ) -> Result<Response<Body>> {
+        let topics = topics(req.uri().query().unwrap_or_default());
+        if topics.is_empty() {
+            return Ok(http::status(StatusCode::BAD_REQUEST));
+        }
+        let (upstream, max_body_size) = match &self.upstream {
+            Some(upstream) => (upstream.clone(), upstream.max_body_size),
+            None => match router.route(host, req.uri().path()) {
+                Some(route) => (route.upstream.clone(), route.max_body_size()),
+                None => return Ok(http::status(StatusCode::NOT_FOUND)),
+            },
+        };
+
+        let event_stream = accepts_event_stream(&req);
+        let request = match RequestEnvelope::from_request_limited(
+            req,
+            max_body_size,
+            None,
+        )
+        .await
+        {
+            Ok(request) => request,
+            Err(err) if err.downcast_ref::<PayloadTooLarge>().is_some() => {
+                return Ok(http::status(StatusCode::PAYLOAD_TOO_LARGE));
+            }
+            Err(err) => return Err(err),
+        };
+
+        let subscription = Subscription::new(self.hub.clone());
+        let envelope = SubscribeEnvelope {
+            event:      "subscribe",
+            connection: subscription.id,
+            request:    &request,
+            topics:     &topics,
+        };
+        let req = serde_json::to_vec(&envelope)?.into();
+        let response = match upstream.pool.exec(req).await {
+            Ok(response) => response,
+            Err(err) => match err.downcast_ref::<Overloaded>() {
+                Some(reason) => {
+                    log::warn!(
+                        "rejecting subscription to pool {}: {}",
+                        upstream.name,
+                        reason
+                    );
+                    let retry_after = upstream.retry_after;
+                    return Ok(http::service_unavailable(retry_after));
+                }
+                None => return Err(err),
+            },
+        };
+        let commands = serde_json::from_slice::<Commands>(&response.0)
+            .map_err(|err| anyhow!("could not decode commands: {}", err))?;
+        self.hub.apply(commands);
+        if !self.hub.joined(subscription.id) {
+            return Ok(http::status(StatusCode::FORBIDDEN));
+        }
+
+        if event_stream {
+            self.stream(subscription)
+        } else {
+            self.poll(subscription).await
+        }
+    }
+
+    fn stream(
+        &self,
+        subscription: Subscription,
+    ) -> Result<Response<Body>> {
+        let start = Instant::now() + self.keep_alive;
+        let keep_alive = interval_at(start, self.keep_alive);
+        let events = stream::unfold((subscription, keep_alive), next_chunk);
+
+        let mut response = Response::new(Body::wrap_stream(events));
+        let headers = response.headers_mut();
+        headers.insert(header::CONTENT_TYPE, "text/event-stream".parse()?);
+        headers
+            .insert(header::CACHE_CONTROL, "no-cache, no-transform".parse()?);
+        Ok(response)
+    }
+
+    /// Answers with the messages published until `poll_timeout`, 204 if
+    /// there were none. Messages published between polls are not kept.
+    async fn poll(
+        &self,
+        mut subscription: Subscription,
+    ) -> Result<Response<Body>> {
+        let mut events = vec![];
+        let messages = &mut subscription.messages;
+        let mut next = timeout(self.poll_timeout, messages.recv()).await.ok();
+        while let Some(Some(Outgoing::Message { event, data, .. })) = next {
+            events.push(Event { event, data });
+            next = messages.try_recv().ok().map(Some);
+        }
+
+        if events.is_empty() {
+            return Ok(http::status(StatusCode::NO_CONTENT));
+        }
+
+        let mut response = Response::new(serde_json::to_vec(&events)?.into());
+        let headers = response.headers_mut();
+        headers.insert(header::CONTENT_TYPE, "application/json".parse()?);
+        headers.insert(header::CACHE_CONTROL, "no-cache".parse()?);
+        Ok(response)
+    }
+}
+
+/// A client joined to the topics a worker allowed, unregistered from the
+/// hub on drop.
+struct Subscription {
+    hub:      Arc<Hub>,
+    id:       ConnectionId,
+    messages: mpsc::UnboundedReceiver<Outgoing>,
+}
+
+impl Subscription {
+    fn new(hub: Arc<Hub>) -> Self {
+        let (id, messages) = hub.register();
+        Self { hub, id, messages }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.hub.unregister(self.id);
+    }
+}
+
+/// Waits for the next message or keep-alive comment, `None` ends the
+/// stream.
+async fn next_chunk(
+    (mut subscription, mut keep_alive): (Subscription, Interval)
+) -> Option<(Result<String, Infallible>, (Subscription, Interval))> {
+    let chunk = tokio::select! {
+        message = subscription.messages.recv() => match message? {
+            Outgoing::Message { event, data, .. } => {
+                format_event(event.as_deref(), &data)
+            }
+            Outgoing::Close => return None,
+        },
+        _ = keep_alive.tick() => ":\n\n".to_string(),
+    };
+    Some((Ok(chunk), (subscription, keep_alive)))
+}
+
+fn topics(query: &str) -> Vec<String> {
+    query
+        .split('&')
+        .filter_map(|param| param.strip_prefix("topic="))
+        .filter_map(|topic| percent_decode_str(topic).decode_utf8().ok())
+        .filter(|topic| !topic.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn accepts_event_stream(req: &Request<Body>) -> bool {
+    req.headers()
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|accept| accept.to_str().ok())
+        .flat_map(|accept| accept.split(','))
+        .any(|accept| accept.trim().starts_with("text/event-stream"))
+}
+
+/// Encodes an event, every line of `data` gets its own field. Line breaks
+/// in `event` are dropped so they can't inject fields.
+fn format_event(
+    event: Option<&str>,
+    data: &str,
+) -> String {
+    let mut chunk = String::new();
+    if let Some(event) = event {
+        let event = event.replace(['\r', '\n'], "");
+        chunk.push_str(&format!("event: {}\n", event));
+    }
+    // Clients accept CRLF, CR and LF as line endings.
+    let lines = data.split("\r\n").flat_map(|line| line.split(['\r', '\n']));
+    for line in lines {
+        chunk.push_str(&format!("data: {}\n", line));
+    }
+    chunk.push('\n');
+    chunk
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::body::HttpBody;
+
+    use super::*;
+    use crate::worker::pool::tests::Stub;
+
+    /// Allows any topic but `private`.
+    async fn authorize(event: serde_json::Value) -> Result<serde_json::Value> {
+        let join = event["topics"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter(|topic| *topic != "private")
+            .map(|topic| {
+                serde_json::json!({
+                    "connection": event["connection"],
+                    "channel": topic,
+                })
+            })
+            .collect::<Vec<_>>();
+        Ok(serde_json::json!({ "join": join }))
+    }
+
+    fn events() -> Events {
+        let hub = Arc::new(Hub::new());
+        let upstream = Arc::new(Upstream {
+            name:          "app".into(),
+            pool:          Arc::new(Stub(authorize)),
+            retry_after:   1,
+            hub:           hub.clone(),
+            max_body_size: None,
+            upload_dir:    None,
+        });
+        Events::new(
+            "/events".into(),
+            Some(upstream),
+            hub,
+            Duration::from_secs(15),
+            Duration::from_millis(100),
+        )
+    }
+
+    fn request(
+        uri: &str,
+        accept: &str,
+    ) -> Request<Body> {
+        Request::get(uri)
+            .header(header::ACCEPT, accept)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    async fn subscribe(
+        events: &Events,
+        req: Request<Body>,
+    ) -> Result<Response<Body>> {
+        events.subscribe(req, None, &Router::new(vec![])).await
+    }
+
+    fn broadcast(
+        hub: &Hub,
+        channel: &str,
+        event: Option<&str>,
+        data: &str,
+    ) {
+        let commands = serde_json::json!({
+            "broadcast": [{ "channel": channel, "event": event, "data": data }],
+        });
+        hub.apply(serde_json::from_value(commands).unwrap());
+    }
+
+    #[test]
+    fn parsing_topics() {
+        assert_eq!(topics("topic=news&page=2&topic=a%2Fb&topic="), vec![
+            "news", "a/b"
+        ]);
+        assert!(topics("").is_empty());
+        assert_eq!(format_event(Some("post"), "a\nb"), concat!(
+            "event: post\n",
+            "data: a\n",
+            "data: b\n",
+            "\n"
+        ));
+    }
+
+    #[test]
+    fn escaping_line_breaks() {
+        let chunk = format_event(Some("post\r\ndata: x"), "a\r\nb\rc\n");
+        assert_eq!(chunk, concat!(
+            "event: postdata: x\n",
+            "data: a\n",
+            "data: b\n",
+            "data: c\n",
+            "data: \n",
+            "\n"
+        ));
+    }
+
+    #[tokio::test]
+    async fn streaming_events() -> Result<()> {
+        let events = events();
+        let req = request("/events?topic=news", "text/event-stream");
+        let mut response = subscribe(&events, req).await?;
+        let content_type = &response.headers()[header::CONTENT_TYPE];
+        assert_eq!(content_type, "text/event-stream");
+
+        broadcast(&events.hub, "news", Some("post"), "hello");
+        broadcast(&events.hub, "other", None, "ignored");
+        let chunk = response.body_mut().data().await.unwrap()?;
+        assert_eq!(chunk, "event: post\ndata: hello\n\n");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn long_polling() -> Result<()> {
+        let events = events();
+        let req = request("/events?topic=news", "*/*");
+        let response = subscribe(&events, req).await?;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let hub = events.hub.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            broadcast(&hub, "news", None, "1");
+            broadcast(&hub, "news", Some("post"), "2");
+        });
+        let req = request("/events?topic=news", "*/*");
+        let response = subscribe(&events, req).await?;
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        assert_eq!(body, r#"[{"data":"1"},{"event":"post","data":"2"}]"#);
+
+        let response = subscribe(&events, request("/events", "*/*")).await?;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn authorizing_subscriptions() -> Result<()> {
+        let events = events();
+        let req = request("/events?topic=private", "text/event-stream");
+        let response = subscribe(&events, req).await?;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let req = request(
+            "/events?topic=private&topic=news",
+            "text/event-stream",
+        );
+        let mut response = subscribe(&events, req).await?;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        broadcast(&events.hub, "private", None, "secret");
+        broadcast(&events.hub, "news", None, "hello");
+        let chunk = response.body_mut().data().await.unwrap()?;
+        assert_eq!(chunk, "data: hello\n\n");
+
+        Ok(())
+    }
+}
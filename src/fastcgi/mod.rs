@@ -0,0 +1,645 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+use anyhow::Result;
+use hyper::{
+    Body,
+    Response,
+    StatusCode,
+};
+use tokio::io::{
+    AsyncRead,
+    AsyncWrite,
+    AsyncWriteExt,
+    BufReader,
+    BufWriter,
+};
+use tokio::net::{
+    TcpListener,
+    UnixListener,
+};
+
+mod record;
+
+use record::{
+    ProtocolStatus,
+    Record,
+    RecordType,
+};
+
+use crate::http::{
+    self,
+    RequestEnvelope,
+    Route,
+    Router,
+};
+
+/// Largest params stream accepted, well above web servers' header limits.
+const MAX_PARAMS_LEN: usize = 1 << 20;
+
+/// A request assembled from begin request, params and stdin records.
+#[derive(Default)]
+struct Request {
+    id:        u16,
+    keep_conn: bool,
+    params:    Vec<u8>,
+    stdin:     Vec<u8>,
+    /// Built from the params once they are complete, without body.
+    envelope:  RequestEnvelope,
+    route:     Option<Route>,
+    /// Answered without reaching a worker, sent as soon as it is known.
+    rejected:  Option<StatusCode>,
+}
+
+/// Serves FastCGI responder requests on `listener`.
+pub async fn serve_tcp(
+    listener: TcpListener,
+    router: Arc<Router>,
+) -> Result<()> {
+    log::info!("Serving FastCGI on: {}", listener.local_addr()?);
+
+    loop {
+        let (stream, remote) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                log::error!("could not accept new connection: {}", err);
+                continue;
+            }
+        };
+
+        tokio::spawn(serve_connection(stream, remote, router.clone()));
+    }
+}
+
+/// Serves FastCGI responder requests on a Unix domain socket `listener`.
+pub async fn serve_unix(
+    listener: UnixListener,
+    router: Arc<Router>,
+) -> Result<()> {
+    log::info!("Serving FastCGI on: {:?}", listener.local_addr()?);
+
+    loop {
+        let (stream, remote) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                log::error!("could not accept new connection: {}", err);
+                continue;
+            }
+        };
+
+        tokio::spawn(serve_connection(stream, remote, router.clone()));
+    }
+}
+
+async fn serve_connection(
+    stream: impl AsyncRead + AsyncWrite + Unpin,
+    remote: impl fmt::Debug,
+    router: Arc<Router>,
+) {
+    if let Err(err) = handle_connection(stream, &router).await {
+        log::debug!("FastCGI connection with {:?} failed: {}", remote, err);
+    }
+}
+
+// NOTE: requests on a connection are served one after another, web servers
+// don't multiplex and `FCGI_MPXS_CONNS` is reported as 0.
+async fn handle_connection(
+    stream: impl AsyncRead + AsyncWrite + Unpin,
+    router: &Router,
+) -> Result<()> {
+    let (reader, writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+
+    while let Some(req) =
+        read_request(&mut reader, &mut writer, router).await?
+    {
+        let (id, keep_conn) = (req.id, req.keep_conn);
+        let response = match respond(req).await {
+            Ok(response) => response,
+            Err(err) => {
+                log::error!("FastCGI request failed: {}", err);
+                http::status(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        };
+
+        for chunk in to_cgi(response).await?.chunks(record::MAX_CONTENT_LEN) {
+            Record::new(RecordType::Stdout, id, chunk.to_vec())
+                .write_to(&mut writer)
+                .await?;
+        }
+        Record::new(RecordType::Stdout, id, vec![])
+            .write_to(&mut writer)
+            .await?;
+        Record::end_request(id, ProtocolStatus::RequestComplete)
+            .write_to(&mut writer)
+            .await?;
+        writer.flush().await?;
+
+        if !keep_conn {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads records until a request is complete, answering management and
+/// rejected requests on the way. `None` if the connection should be
+/// closed.
+///
+/// Requests crossing the params cap or their route's body limit are
+/// returned right away, their remaining records are ignored.
+async fn read_request(
+    mut reader: impl AsyncRead + Unpin,
+    mut writer: impl AsyncWrite + Unpin,
+    router: &Router,
+) -> Result<Option<Request>> {
+    let mut req: Option<Request> = None;
+    let mut params_done = false;
+
+    while let Some(record) = Record::read_from(&mut reader).await? {
+        let ty = match record.record_type() {
+            Some(ty) => ty,
+            None => {
+                Record::unknown_type(record.ty).write_to(&mut writer).await?;
+                writer.flush().await?;
+                continue;
+            }
+        };
+
+        if ty == RecordType::GetValues {
+            let values = record::decode_params(&record.content)?;
+            let known = values
+                .iter()
+                .filter(|(name, _)| name == "FCGI_MPXS_CONNS")
+                .map(|(name, _)| (name.as_str(), "0"));
+            let content = record::encode_params(known);
+            Record::new(RecordType::GetValuesResult, 0, content)
+                .write_to(&mut writer)
+                .await?;
+            writer.flush().await?;
+            continue;
+        }
+
+        let active = req.as_ref().map(|req| req.id);
+        match (ty, active) {
+            (RecordType::BeginRequest, Some(_)) => {
+                Record::end_request(
+                    record.request_id,
+                    ProtocolStatus::CantMpxConn,
+                )
+                .write_to(&mut writer)
+                .await?;
+                writer.flush().await?;
+            }
+            (RecordType::BeginRequest, None) => {
+                let (role, flags) = record.begin_request()?;
+                if role != record::RESPONDER {
+                    Record::end_request(
+                        record.request_id,
+                        ProtocolStatus::UnknownRole,
+                    )
+                    .write_to(&mut writer)
+                    .await?;
+                    writer.flush().await?;
+                    continue;
+                }
+
+                req = Some(Request {
+                    id: record.request_id,
+                    keep_conn: flags & record::KEEP_CONN != 0,
+                    ..Request::default()
+                });
+                params_done = false;
+            }
+            // Records of other requests are ignored.
+            (_, Some(id)) if id != record.request_id => {}
+            (RecordType::AbortRequest, Some(id)) => {
+                let keep_conn = req.take().is_some_and(|req| req.keep_conn);
+                Record::end_request(id, ProtocolStatus::RequestComplete)
+                    .write_to(&mut writer)
+                    .await?;
+                writer.flush().await?;
+                if !keep_conn {
+                    return Ok(None);
+                }
+            }
+            (RecordType::Params, Some(_)) => {
+                let active = req.as_mut().expect("request is active");
+                if record.content.is_empty() {
+                    params_done = true;
+                    route(active, router);
+                    continue;
+                }
+                active.params.extend(record.content);
+                if active.params.len() > MAX_PARAMS_LEN {
+                    active.rejected =
+                        Some(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+                    return Ok(req);
+                }
+            }
+            (RecordType::Stdin, Some(_)) => {
+                let active = req.as_mut().expect("request is active");
+                if record.content.is_empty() {
+                    if params_done {
+                        return Ok(req);
+                    }
+                    continue;
+                }
+                active.stdin.extend(record.content);
+                // Uploads are left to the web server in front, only the
+                // size is checked.
+                let len = active.stdin.len() as u64;
+                let route = active.route.as_ref();
+                if route
+                    .and_then(Route::max_body_size)
+                    .is_some_and(|limit| len > limit)
+                {
+                    active.rejected = Some(StatusCode::PAYLOAD_TOO_LARGE);
+                    return Ok(req);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(None)
+}
+
+/// Builds the envelope of `req` from its complete params and finds its
+/// route, whose body limit applies while stdin is read.
+fn route(
+    req: &mut Request,
+    router: &Router,
+) {
+    let params = match record::decode_params(&req.params) {
+        Ok(params) => params,
+        Err(err) => {
+            log::debug!("invalid FastCGI params: {}", err);
+            req.rejected = Some(StatusCode::BAD_REQUEST);
+            return;
+        }
+    };
+    req.envelope = envelope(params, &[]);
+
+    let host = req
+        .envelope
+        .headers
+        .get("host")
+        .and_then(|values| values.first())
+        .map(String::as_str);
+    let path = req.envelope.uri.split('?').next().unwrap_or("/");
+    req.route = router.route(host, path).cloned();
+}
+
+async fn respond(req: Request) -> Result<Response<Body>> {
+    if let Some(status) = req.rejected {
+        return Ok(http::status(status));
+    }
+    let route = match req.route {
+        Some(route) => route,
+        None => return Ok(http::status(StatusCode::NOT_FOUND)),
+    };
+
+    let mut envelope = req.envelope;
+    envelope.set_body(&req.stdin);
+    http::dispatch(&route.upstream, envelope).await
+}
+
+/// Builds the request envelope from CGI params as nginx and Apache send
+/// them.
+fn envelope(
+    params: Vec<(String, String)>,
+    stdin: &[u8],
+) -> RequestEnvelope {
+    let params = params.into_iter().collect::<BTreeMap<_, _>>();
+    let param = |name: &str| params.get(name).filter(|value| !value.is_empty());
+
+    let uri = match param("REQUEST_URI") {
+        Some(uri) => uri.clone(),
+        None => {
+            let mut uri = format!(
+                "{}{}",
+                param("SCRIPT_NAME").map_or("", String::as_str),
+                param("PATH_INFO").map_or("", String::as_str),
+            );
+            if let Some(query) = param("QUERY_STRING") {
+                uri.push('?');
+                uri.push_str(query);
+            }
+            uri
+        }
+    };
+
+    let mut headers = BTreeMap::<_, Vec<_>>::new();
+    for (name, value) in &params {
+        let name = match name.strip_prefix("HTTP_") {
+            Some(name) => name,
+            None if name == "CONTENT_TYPE" || name == "CONTENT_LENGTH" => {
+                if value.is_empty() {
+                    continue;
+                }
+                name
+            }
+            None => continue,
+        };
+        headers
+            .entry(name.to_lowercase().replace('_', "-"))
+            .or_default()
+            .push(value.clone());
+    }
+
+    let mut envelope = RequestEnvelope {
+        method: param("REQUEST_METHOD").map_or("GET", String::as_str).into(),
+        uri,
+        protocol: param("SERVER_PROTOCOL")
+            .map_or("HTTP/1.1", String::as_str)
+            .into(),
+        headers,
+        ..RequestEnvelope::default()
+    };
+    envelope.set_body(stdin);
+    envelope
+}
+
+/// Encodes a response as CGI output, the status goes into a `Status`
+/// header.
+async fn to_cgi(response: Response<Body>) -> Result<Vec<u8>> {
+    let (parts, body) = response.into_parts();
+
+    let mut buf = format!(
+        "Status: {} {}\r\n",
+        parts.status.as_u16(),
+        parts.status.canonical_reason().unwrap_or_default()
+    )
+    .into_bytes();
+    for (name, value) in &parts.headers {
+        buf.extend(name.as_str().as_bytes());
+        buf.extend(b": ");
+        buf.extend(value.as_bytes());
+        buf.extend(b"\r\n");
+    }
+    buf.extend(b"\r\n");
+    buf.extend(hyper::body::to_bytes(body).await?);
+
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+    use tokio::io::duplex;
+
+    use super::*;
+    use crate::http::{
+        Route,
+        Upstream,
+    };
+    use crate::websocket::Hub;
+    use crate::worker::pool::tests::Stub;
+    use crate::worker::pool::Overloaded;
+
+    /// Answers with the request's method, uri and body.
+    async fn echo(req: serde_json::Value) -> Result<serde_json::Value> {
+        if req["uri"] == "/app/busy" {
+            return Err(anyhow!(Overloaded::QueueFull));
+        }
+
+        let body = format!(
+            "{} {} {} {}",
+            req["method"].as_str().unwrap_or_default(),
+            req["uri"].as_str().unwrap_or_default(),
+            req["headers"]["content-type"][0].as_str().unwrap_or_default(),
+            req["body"].as_str().unwrap_or_default(),
+        );
+        Ok(serde_json::json!({
+            "headers": { "content-type": "text/plain" },
+            "body": body,
+        }))
+    }
+
+    fn router() -> Router {
+        Router::new(vec![Route {
+            host:          None,
+            path:          Some("/app/".into()),
+            upstream:      Arc::new(Upstream {
+                name:          "echo".into(),
+                pool:          Arc::new(Stub(echo)),
+                retry_after:   2,
+                hub:           Arc::new(Hub::new()),
+                max_body_size: Some(16),
+                upload_dir:    None,
+            }),
+            max_body_size: None,
+        }])
+    }
+
+    async fn send_request(
+        mut client: impl AsyncWrite + Unpin,
+        id: u16,
+        params: &[(&str, &str)],
+        stdin: &[u8],
+    ) -> Result<()> {
+        let begin = vec![0, 1, record::KEEP_CONN, 0, 0, 0, 0, 0];
+        Record::new(RecordType::BeginRequest, id, begin)
+            .write_to(&mut client)
+            .await?;
+        let params = record::encode_params(params.iter().copied());
+        Record::new(RecordType::Params, id, params)
+            .write_to(&mut client)
+            .await?;
+        Record::new(RecordType::Params, id, vec![])
+            .write_to(&mut client)
+            .await?;
+        if !stdin.is_empty() {
+            Record::new(RecordType::Stdin, id, stdin.to_vec())
+                .write_to(&mut client)
+                .await?;
+        }
+        Record::new(RecordType::Stdin, id, vec![])
+            .write_to(&mut client)
+            .await?;
+        client.flush().await?;
+        Ok(())
+    }
+
+    /// Reads stdout until the request ends.
+    async fn read_response(
+        mut server: impl AsyncRead + Unpin,
+        id: u16,
+    ) -> Result<String> {
+        let mut stdout = vec![];
+        loop {
+            let record = Record::read_from(&mut server)
+                .await?
+                .ok_or_else(|| anyhow!("connection closed"))?;
+            assert_eq!(record.request_id, id);
+            match record.record_type() {
+                Some(RecordType::Stdout) => stdout.extend(record.content),
+                Some(RecordType::EndRequest) => {
+                    return Ok(String::from_utf8(stdout)?)
+                }
+                ty => panic!("unexpected record: {:?}", ty),
+            }
+        }
+    }
+
+    #[test]
+    fn building_envelopes_from_params() {
+        let params = vec![
+            ("REQUEST_METHOD", "POST"),
+            ("SCRIPT_NAME", "/index.php"),
+            ("PATH_INFO", "/users"),
+            ("QUERY_STRING", "page=2"),
+            ("SERVER_PROTOCOL", "HTTP/2.0"),
+            ("CONTENT_TYPE", "application/json"),
+            ("CONTENT_LENGTH", ""),
+            ("HTTP_HOST", "example.com"),
+            ("HTTP_X_FORWARDED_FOR", "10.0.0.1"),
+            ("REMOTE_ADDR", "127.0.0.1"),
+        ];
+        let params = params
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+
+        let envelope = envelope(params, b"{}");
+
+        assert_eq!(envelope.method, "POST");
+        assert_eq!(envelope.uri, "/index.php/users?page=2");
+        assert_eq!(envelope.protocol, "HTTP/2.0");
+        assert_eq!(envelope.headers.len(), 3);
+        assert_eq!(envelope.headers["content-type"], vec!["application/json"]);
+        assert_eq!(envelope.headers["host"], vec!["example.com"]);
+        assert_eq!(envelope.headers["x-forwarded-for"], vec!["10.0.0.1"]);
+        assert_eq!(envelope.body, "{}");
+        assert!(!envelope.binary);
+
+        let envelope = super::envelope(vec![], &[0xff, 0x00]);
+        assert_eq!((envelope.body.as_str(), envelope.binary), ("/wA=", true));
+    }
+
+    #[tokio::test]
+    async fn serving_requests() -> Result<()> {
+        let (mut client, server) = duplex(1024);
+        let router = Arc::new(router());
+        tokio::spawn(async move { handle_connection(server, &router).await });
+
+        let params = [
+            ("REQUEST_METHOD", "POST"),
+            ("REQUEST_URI", "/app/users?page=2"),
+            ("CONTENT_TYPE", "text/plain"),
+            ("HTTP_HOST", "example.com"),
+        ];
+        send_request(&mut client, 1, &params, b"hello").await?;
+        let response = read_response(&mut client, 1).await?;
+        assert_eq!(
+            response,
+            "Status: 200 OK\r\ncontent-type: text/plain\r\n\r\nPOST \
+             /app/users?page=2 text/plain hello"
+        );
+
+        // Connection is kept alive for the next requests.
+        send_request(&mut client, 2, &[("REQUEST_URI", "/other")], b"")
+            .await?;
+        let response = read_response(&mut client, 2).await?;
+        assert!(response.starts_with("Status: 404 Not Found\r\n"));
+
+        send_request(&mut client, 3, &[("REQUEST_URI", "/app/busy")], b"")
+            .await?;
+        let response = read_response(&mut client, 3).await?;
+        assert!(response.starts_with("Status: 503 Service Unavailable\r\n"));
+        assert!(response.contains("retry-after: 2\r\n"));
+
+        let body = [b'x'; 17];
+        send_request(&mut client, 4, &[("REQUEST_URI", "/app/")], &body)
+            .await?;
+        let response = read_response(&mut client, 4).await?;
+        assert!(response.starts_with("Status: 413 Payload Too Large\r\n"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejecting_large_bodies_early() -> Result<()> {
+        let (mut client, server) = duplex(1024);
+        let router = Arc::new(router());
+        tokio::spawn(async move { handle_connection(server, &router).await });
+
+        let begin = vec![0, 1, record::KEEP_CONN, 0, 0, 0, 0, 0];
+        Record::new(RecordType::BeginRequest, 1, begin)
+            .write_to(&mut client)
+            .await?;
+        let params = record::encode_params([("REQUEST_URI", "/app/")]);
+        Record::new(RecordType::Params, 1, params)
+            .write_to(&mut client)
+            .await?;
+        Record::new(RecordType::Params, 1, vec![])
+            .write_to(&mut client)
+            .await?;
+        Record::new(RecordType::Stdin, 1, vec![b'x'; 17])
+            .write_to(&mut client)
+            .await?;
+        client.flush().await?;
+
+        // Answered before the body ends.
+        let response = read_response(&mut client, 1).await?;
+        assert!(response.starts_with("Status: 413 Payload Too Large\r\n"));
+
+        // The rest of the body is skipped, the connection stays usable.
+        Record::new(RecordType::Stdin, 1, vec![b'x'; 100])
+            .write_to(&mut client)
+            .await?;
+        Record::new(RecordType::Stdin, 1, vec![])
+            .write_to(&mut client)
+            .await?;
+        send_request(&mut client, 2, &[("REQUEST_URI", "/app/")], b"hi")
+            .await?;
+        let response = read_response(&mut client, 2).await?;
+        assert!(response.ends_with("GET /app/  hi"));
+
+        let begin = vec![0, 1, record::KEEP_CONN, 0, 0, 0, 0, 0];
+        Record::new(RecordType::BeginRequest, 3, begin)
+            .write_to(&mut client)
+            .await?;
+        let cookie = "x".repeat(MAX_PARAMS_LEN);
+        let params = record::encode_params([("HTTP_COOKIE", cookie.as_str())]);
+        for chunk in params.chunks(record::MAX_CONTENT_LEN) {
+            Record::new(RecordType::Params, 3, chunk.to_vec())
+                .write_to(&mut client)
+                .await?;
+        }
+        client.flush().await?;
+        let response = read_response(&mut client, 3).await?;
+        assert!(response.starts_with("Status: 431 "));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejecting_unknown_roles() -> Result<()> {
+        let (mut client, server) = duplex(1024);
+        let router = Arc::new(router());
+        tokio::spawn(async move { handle_connection(server, &router).await });
+
+        // Authorizer role.
+        let begin = vec![0, 2, record::KEEP_CONN, 0, 0, 0, 0, 0];
+        Record::new(RecordType::BeginRequest, 1, begin)
+            .write_to(&mut client)
+            .await?;
+        Record::new(RecordType::GetValues, 0, vec![])
+            .write_to(&mut client)
+            .await?;
+        client.flush().await?;
+
+        let end = Record::read_from(&mut client).await?.unwrap();
+        assert_eq!(end.record_type(), Some(RecordType::EndRequest));
+        assert_eq!(end.content[4], ProtocolStatus::UnknownRole as u8);
+        let values = Record::read_from(&mut client).await?.unwrap();
+        assert_eq!(values.record_type(), Some(RecordType::GetValuesResult));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,264 @@
+use std::convert::TryInto;
+use std::io;
+
+use anyhow::{
+    anyhow,
+    bail,
+    Result,
+};
+use num_traits::FromPrimitive;
+use tokio::io::{
+    AsyncRead,
+    AsyncReadExt,
+    AsyncWrite,
+    AsyncWriteExt,
+};
+
+const VERSION: u8 = 1;
+const HEADER_SIZE: usize = 8;
+
+/// Largest content a single record can carry.
+pub const MAX_CONTENT_LEN: usize = u16::MAX as usize;
+
+/// `FCGI_KEEP_CONN` flag of begin request records.
+pub const KEEP_CONN: u8 = 1;
+
+/// `FCGI_RESPONDER` role, the only one supported.
+pub const RESPONDER: u16 = 1;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, FromPrimitive)]
+pub enum RecordType {
+    BeginRequest = 1,
+    AbortRequest,
+    EndRequest,
+    Params,
+    Stdin,
+    Stdout,
+    Stderr,
+    Data,
+    GetValues,
+    GetValuesResult,
+    UnknownType,
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProtocolStatus {
+    RequestComplete = 0,
+    CantMpxConn = 1,
+    // Overload is reported with a 503 `Status` instead of `FCGI_OVERLOADED`.
+    UnknownRole = 3,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    /// Raw type, unknown types must be reported back to the web server.
+    pub ty:         u8,
+    pub request_id: u16,
+    pub content:    Vec<u8>,
+}
+
+impl Record {
+    pub fn new(
+        ty: RecordType,
+        request_id: u16,
+        content: Vec<u8>,
+    ) -> Self {
+        Self {
+            ty: ty as u8,
+            request_id,
+            content,
+        }
+    }
+
+    pub fn end_request(
+        request_id: u16,
+        status: ProtocolStatus,
+    ) -> Self {
+        // Application status is always 0, errors are reported in `Status`.
+        let content = vec![0, 0, 0, 0, status as u8, 0, 0, 0];
+        Self::new(RecordType::EndRequest, request_id, content)
+    }
+
+    pub fn unknown_type(ty: u8) -> Self {
+        Self::new(RecordType::UnknownType, 0, vec![ty, 0, 0, 0, 0, 0, 0, 0])
+    }
+
+    pub fn record_type(&self) -> Option<RecordType> {
+        RecordType::from_u8(self.ty)
+    }
+
+    /// Role and flags of a begin request record.
+    pub fn begin_request(&self) -> Result<(u16, u8)> {
+        match self.content.get(..3) {
+            Some(body) => Ok((u16::from_be_bytes([body[0], body[1]]), body[2])),
+            None => bail!("truncated begin request record"),
+        }
+    }
+
+    /// Reads the next record, `None` if the connection was closed between
+    /// records.
+    pub async fn read_from(
+        mut src: impl AsyncRead + Unpin
+    ) -> Result<Option<Self>> {
+        let mut header = [0u8; HEADER_SIZE];
+        match src.read_exact(&mut header).await {
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                return Ok(None)
+            }
+            Err(err) => return Err(err.into()),
+        }
+
+        if header[0] != VERSION {
+            bail!("unsupported FastCGI version: {}", header[0]);
+        }
+        let request_id = u16::from_be_bytes(header[2..4].try_into()?);
+        let len = u16::from_be_bytes(header[4..6].try_into()?) as usize;
+        let padding = header[6] as usize;
+
+        let mut content = vec![0; len + padding];
+        src.read_exact(&mut content).await?;
+        content.truncate(len);
+
+        Ok(Some(Self {
+            ty: header[1],
+            request_id,
+            content,
+        }))
+    }
+
+    /// Writes the record, callers are expected to flush.
+    pub async fn write_to(
+        &self,
+        mut dst: impl AsyncWrite + Unpin,
+    ) -> Result<()> {
+        let len: u16 = self
+            .content
+            .len()
+            .try_into()
+            .map_err(|_| anyhow!("record content is too large"))?;
+        // Pad to 8 bytes as recommended by the specification.
+        let padding = (8 - self.content.len() % 8) % 8;
+
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        header.extend(&[VERSION, self.ty]);
+        header.extend(&self.request_id.to_be_bytes());
+        header.extend(&len.to_be_bytes());
+        header.extend(&[padding as u8, 0]);
+        dst.write_all(&header).await?;
+        dst.write_all(&self.content).await?;
+        dst.write_all(&[0; 8][..padding]).await?;
+
+        Ok(())
+    }
+}
+
+/// Decodes name-value pairs of params and get values records.
+pub fn decode_params(mut buf: &[u8]) -> Result<Vec<(String, String)>> {
+    let mut params = vec![];
+    while !buf.is_empty() {
+        let name_len = decode_len(&mut buf)?;
+        let value_len = decode_len(&mut buf)?;
+        if buf.len() < name_len + value_len {
+            bail!("truncated FastCGI params");
+        }
+
+        let (name, rest) = buf.split_at(name_len);
+        let (value, rest) = rest.split_at(value_len);
+        params.push((
+            String::from_utf8_lossy(name).into_owned(),
+            String::from_utf8_lossy(value).into_owned(),
+        ));
+        buf = rest;
+    }
+
+    Ok(params)
+}
+
+pub fn encode_params<'a>(
+    params: impl IntoIterator<Item = (&'a str, &'a str)>
+) -> Vec<u8> {
+    let mut buf = vec![];
+    for (name, value) in params {
+        encode_len(&mut buf, name.len());
+        encode_len(&mut buf, value.len());
+        buf.extend(name.as_bytes());
+        buf.extend(value.as_bytes());
+    }
+    buf
+}
+
+// NOTE: lengths up to 127 take one byte, longer ones four with the high
+// bit set.
+fn decode_len(buf: &mut &[u8]) -> Result<usize> {
+    match buf.first() {
+        Some(len) if len >> 7 == 0 => {
+            *buf = &buf[1..];
+            Ok(*len as usize)
+        }
+        Some(_) if buf.len() >= 4 => {
+            let len = u32::from_be_bytes(buf[..4].try_into()?) & 0x7fff_ffff;
+            *buf = &buf[4..];
+            Ok(len as usize)
+        }
+        _ => bail!("truncated FastCGI params"),
+    }
+}
+
+fn encode_len(
+    buf: &mut Vec<u8>,
+    len: usize,
+) {
+    if len < 128 {
+        buf.push(len as u8);
+    } else {
+        buf.extend(&(len as u32 | 0x8000_0000).to_be_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn reading_and_writing_records() -> Result<()> {
+        let (mut client, mut server) = duplex(128);
+        let record = Record::new(RecordType::Stdin, 1, b"hello".to_vec());
+        record.write_to(&mut client).await?;
+        Record::end_request(1, ProtocolStatus::UnknownRole)
+            .write_to(&mut client)
+            .await?;
+        drop(client);
+
+        assert_eq!(Record::read_from(&mut server).await?, Some(record));
+        let end = Record::read_from(&mut server).await?.unwrap();
+        assert_eq!(end.record_type(), Some(RecordType::EndRequest));
+        assert_eq!(end.content[4], ProtocolStatus::UnknownRole as u8);
+        assert_eq!(Record::read_from(&mut server).await?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn encoding_params() -> Result<()> {
+        let long = "x".repeat(300);
+        let buf = encode_params(vec![
+            ("REQUEST_METHOD", "GET"),
+            ("HTTP_COOKIE", long.as_str()),
+            ("QUERY_STRING", ""),
+        ]);
+
+        assert_eq!(decode_params(&buf)?, vec![
+            ("REQUEST_METHOD".to_string(), "GET".to_string()),
+            ("HTTP_COOKIE".to_string(), long),
+            ("QUERY_STRING".to_string(), String::new()),
+        ]);
+        assert!(decode_params(&buf[..buf.len() - 1]).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,358 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{
+    AtomicBool,
+    Ordering,
+};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{
+    anyhow,
+    bail,
+    Result,
+};
+use hyper::{
+    header,
+    Body,
+    Method,
+    Request,
+    Response,
+    StatusCode,
+};
+use serde::Serialize;
+use tokio::time::{
+    interval,
+    timeout,
+};
+
+use crate::http::{
+    self,
+    RequestEnvelope,
+    Upstream,
+};
+use crate::worker::pool::{
+    Pool,
+    Static,
+};
+
+/// Request PHP answers to report the application healthy.
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+    pub path:     String,
+    pub interval: Duration,
+    pub timeout:  Duration,
+}
+
+/// Readiness of a pool as reported by the readiness endpoint.
+#[derive(Debug, Serialize)]
+struct PoolReadiness {
+    ready:     bool,
+    /// Workers not being replaced.
+    workers:   usize,
+    queue_len: usize,
+    /// Outcome of the last health check request.
+    healthy:   bool,
+    /// Workers keep failing to start.
+    failing:   bool,
+}
+
+#[derive(Debug, Serialize)]
+struct Readiness {
+    ready: bool,
+    pools: BTreeMap<String, PoolReadiness>,
+}
+
+struct Probe {
+    upstream:      Arc<Upstream>,
+    pool:          Arc<Static>,
+    /// Queue length past which the pool counts as saturated.
+    max_queue_len: Option<usize>,
+    healthy:       AtomicBool,
+}
+
+/// Liveness and readiness endpoints for orchestrators.
+///
+/// The liveness endpoint answers as long as coyote serves requests, the
+/// readiness endpoint only while every pool runs enough workers, isn't
+/// saturated and passed its last health check.
+pub struct Health {
+    health_path: String,
+    ready_path:  String,
+    /// Workers every pool needs to be ready.
+    min_workers: usize,
+    pools:       BTreeMap<String, Probe>,
+}
+
+impl Health {
+    pub fn new(
+        health_path: String,
+        ready_path: String,
+        min_workers: usize,
+    ) -> Self {
+        Self {
+            health_path,
+            ready_path,
+            min_workers,
+            pools: BTreeMap::new(),
+        }
+    }
+
+    /// Takes `pool` into account for readiness, `upstream` receives its
+    /// health check requests.
+    pub fn with_pool(
+        mut self,
+        upstream: Arc<Upstream>,
+        pool: Arc<Static>,
+        max_queue_len: Option<usize>,
+    ) -> Self {
+        self.pools.insert(upstream.name.clone(), Probe {
+            upstream,
+            pool,
+            max_queue_len,
+            healthy: AtomicBool::new(true),
+        });
+        self
+    }
+
+    /// Whether `req` asks for liveness or readiness.
+    pub fn matches(
+        &self,
+        req: &Request<Body>,
+    ) -> bool {
+        let path = req.uri().path();
+        matches!(*req.method(), Method::GET | Method::HEAD) &&
+            (path == self.health_path || path == self.ready_path)
+    }
+
+    pub fn respond(
+        &self,
+        req: &Request<Body>,
+    ) -> Result<Response<Body>> {
+        if req.uri().path() == self.health_path {
+            let alive = serde_json::json!({ "status": "ok" });
+            return json(StatusCode::OK, &alive);
+        }
+
+        let readiness = self.readiness();
+        let status = match readiness.ready {
+            true => StatusCode::OK,
+            false => StatusCode::SERVICE_UNAVAILABLE,
+        };
+        json(status, &readiness)
+    }
+
+    fn readiness(&self) -> Readiness {
+        let pools = self
+            .pools
+            .iter()
+            .map(|(name, probe)| {
+                let workers = probe.pool.running();
+                let queue_len = probe.pool.queue_len();
+                let healthy = probe.healthy.load(Ordering::SeqCst);
+                let saturated = probe
+                    .max_queue_len
+                    .is_some_and(|max_queue_len| queue_len >= max_queue_len);
+                let failing = probe.pool.failing();
+                let ready = workers >= self.min_workers &&
+                    !saturated &&
+                    healthy &&
+                    !failing;
+                (name.clone(), PoolReadiness {
+                    ready,
+                    workers,
+                    queue_len,
+                    healthy,
+                    failing,
+                })
+            })
+            .collect::<BTreeMap<_, _>>();
+
+        Readiness {
+            ready: pools.values().all(|pool| pool.ready),
+            pools,
+        }
+    }
+
+    /// Sends `check` through every pool at its interval, pools failing it
+    /// aren't ready until they pass again.
+    pub fn check_periodically(
+        self: Arc<Self>,
+        check: HealthCheck,
+    ) {
+        tokio::spawn(async move {
+            let mut ticker = interval(check.interval);
+            loop {
+                ticker.tick().await;
+                for (name, probe) in &self.pools {
+                    let healthy = match self.check(probe, &check).await {
+                        Ok(()) => true,
+                        Err(err) => {
+                            log::warn!("pool {} is unhealthy: {}", name, err);
+                            false
+                        }
+                    };
+                    let was_healthy =
+                        probe.healthy.swap(healthy, Ordering::SeqCst);
+                    if healthy && !was_healthy {
+                        log::info!("pool {} is healthy again", name);
+                    }
+                }
+            }
+        });
+    }
+
+    async fn check(
+        &self,
+        probe: &Probe,
+        check: &HealthCheck,
+    ) -> Result<()> {
+        let mut envelope = RequestEnvelope {
+            method: Method::GET.to_string(),
+            uri: check.path.clone(),
+            protocol: "HTTP/1.1".to_string(),
+            ..RequestEnvelope::default()
+        };
+        envelope.headers.insert(header::USER_AGENT.to_string(), vec![
+            "coyote-health-check".to_string(),
+        ]);
+
+        // Timing out cancels the request, its worker is replaced rather
+        // than handing the late response to the next request.
+        let response = http::dispatch(&probe.upstream, envelope);
+        let response = timeout(check.timeout, response)
+            .await
+            .map_err(|_| anyhow!("health check timed out"))??;
+        if !response.status().is_success() {
+            bail!("health check answered {}", response.status());
+        }
+        Ok(())
+    }
+}
+
+fn json<T: Serialize>(
+    status: StatusCode,
+    body: &T,
+) -> Result<Response<Body>> {
+    let mut response = http::status(status);
+    *response.body_mut() = serde_json::to_vec(body)?.into();
+    response
+        .headers_mut()
+        .insert(header::CONTENT_TYPE, "application/json".parse()?);
+    response
+        .headers_mut()
+        .insert(header::CACHE_CONTROL, "no-store".parse()?);
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::websocket::Hub;
+    use crate::worker::pool::SpawnBackoff;
+    use crate::worker::Isolation;
+
+    fn request(path: &str) -> Request<Body> {
+        Request::get(path).body(Body::empty()).unwrap()
+    }
+
+    async fn readiness(health: &Health) -> Result<serde_json::Value> {
+        let response = health.respond(&request("/ready"))?;
+        let ready = response.status() == StatusCode::OK;
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        let body = serde_json::from_slice::<serde_json::Value>(&body)?;
+        assert_eq!(body["ready"], ready);
+        Ok(body)
+    }
+
+    async fn pool(
+        socket: &str,
+        script: &str,
+    ) -> Result<(Arc<Upstream>, Arc<Static>)> {
+        let pool = Static::new(
+            socket,
+            script,
+            1,
+            Isolation::default(),
+            SpawnBackoff::default(),
+        )
+        .await?;
+        let pool = Arc::new(pool);
+        let upstream = Arc::new(Upstream {
+            name:          "app".into(),
+            pool:          pool.clone(),
+            retry_after:   1,
+            hub:           Arc::new(Hub::new()),
+            max_body_size: None,
+            upload_dir:    None,
+        });
+        Ok((upstream, pool))
+    }
+
+    #[tokio::test]
+    async fn reporting_readiness() -> Result<()> {
+        let (upstream, pool) = pool(
+            "/tmp/coyote.test.sock.14",
+            "./src/worker/test_data/echo_worker.php",
+        )
+        .await?;
+        let health = |min_workers| {
+            Health::new("/health".into(), "/ready".into(), min_workers)
+                .with_pool(upstream.clone(), pool.clone(), Some(1))
+        };
+
+        let ready = health(1);
+        assert!(ready.matches(&request("/health")));
+        assert!(!ready.matches(&request("/ready/")));
+        let response = ready.respond(&request("/health"))?;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = readiness(&ready).await?;
+        assert_eq!(body["pools"]["app"]["workers"], 1);
+
+        let body = readiness(&health(2)).await?;
+        assert_eq!(body["ready"], false);
+
+        let check = HealthCheck {
+            path:     "/status.php".into(),
+            interval: Duration::from_millis(10),
+            timeout:  Duration::from_millis(50),
+        };
+        ready.check(&ready.pools["app"], &check).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failing_health_checks() -> Result<()> {
+        let (upstream, pool) = pool(
+            "/tmp/coyote.test.sock.15",
+            "./src/worker/test_data/sleepy_pid_worker.php",
+        )
+        .await?;
+        let health = Arc::new(
+            Health::new("/health".into(), "/ready".into(), 1)
+                .with_pool(upstream, pool.clone(), None),
+        );
+
+        let check = HealthCheck {
+            path:     "/status.php".into(),
+            interval: Duration::from_millis(10),
+            timeout:  Duration::from_millis(50),
+        };
+        let checked = pool.status().workers[0].pid;
+        let err = health.check(&health.pools["app"], &check).await;
+        assert_eq!(err.unwrap_err().to_string(), "health check timed out");
+
+        // The worker answering late isn't reused, a replacement answers.
+        let (response, execution) = pool.exec_traced("hello".into()).await?;
+        let pid = execution.pid.unwrap();
+        assert_ne!(pid, checked);
+        assert_eq!(response, pid.to_string().as_str().into());
+
+        health.clone().check_periodically(check);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let body = readiness(&health).await?;
+        assert_eq!(body["pools"]["app"]["healthy"], false);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,480 @@
+use std::fs::{
+    File,
+    OpenOptions,
+};
+use std::io::{
+    self,
+    Write,
+};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{
+    Arc,
+    Mutex,
+};
+use std::time::{
+    Duration,
+    SystemTime,
+    UNIX_EPOCH,
+};
+
+use anyhow::{
+    anyhow,
+    Error,
+    Result,
+};
+use futures::stream;
+use hyper::body::HttpBody;
+use hyper::{
+    header::{
+        self,
+        HeaderName,
+    },
+    Body,
+    Request,
+    Response,
+};
+use serde::Serialize;
+use tokio::signal::unix::{
+    signal,
+    SignalKind,
+};
+
+use crate::worker::ipc::Pid;
+use crate::worker::pool::Execution;
+
+/// Peer address of the connection a request came from, set by servers as
+/// a request extension.
+#[derive(Debug, Clone, Copy)]
+pub struct RemoteAddr(pub SocketAddr);
+
+/// Access log line format.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Format {
+    /// Common Log Format.
+    Common,
+    /// Combined Log Format, Common plus referer and user agent.
+    Combined,
+    /// One JSON object per line.
+    Json,
+    /// Template with `{field}` placeholders, see [`Entry::field`].
+    Template(String),
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(format: &str) -> Result<Self> {
+        Ok(match format {
+            "common" => Format::Common,
+            "combined" => Format::Combined,
+            "json" => Format::Json,
+            template => Format::Template(template.to_string()),
+        })
+    }
+}
+
+/// A served request as logged.
+#[derive(Debug, Serialize)]
+pub struct Entry {
+    pub time:        String,
+    pub request_id:  Option<String>,
+    pub remote_addr: Option<String>,
+    pub method:      String,
+    pub path:        String,
+    pub protocol:    String,
+    pub status:      u16,
+    /// Body bytes sent, counted as they are for streamed responses.
+    pub bytes:       Option<u64>,
+    pub duration_ms: f64,
+    pub pid:         Option<Pid>,
+    pub queue_ms:    Option<f64>,
+    pub referer:     Option<String>,
+    pub user_agent:  Option<String>,
+}
+
+impl Entry {
+    /// Captures what is logged of `req` before it is handled.
+    pub fn new(req: &Request<Body>) -> Self {
+        let header = |name| {
+            req.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(String::from)
+        };
+
+        Self {
+            time:        String::new(),
+            request_id:  header(HeaderName::from_static("x-request-id")),
+            remote_addr: req
+                .extensions()
+                .get::<RemoteAddr>()
+                .map(|remote| remote.0.ip().to_string()),
+            method:      req.method().to_string(),
+            path:        req
+                .uri()
+                .path_and_query()
+                .map_or("/", |path| path.as_str())
+                .to_string(),
+            protocol:    format!("{:?}", req.version()),
+            status:      0,
+            bytes:       None,
+            duration_ms: 0.0,
+            pid:         None,
+            queue_ms:    None,
+            referer:     header(header::REFERER),
+            user_agent:  header(header::USER_AGENT),
+        }
+    }
+
+    /// Completes the entry with the response and the time it took.
+    pub fn finish(
+        &mut self,
+        response: &Response<Body>,
+        duration: Duration,
+    ) {
+        self.time = clf_time(SystemTime::now());
+        self.status = response.status().as_u16();
+        self.bytes = response.body().size_hint().exact();
+        self.duration_ms = millis(duration);
+        if let Some(execution) = response.extensions().get::<Execution>() {
+            self.pid = execution.pid;
+            self.queue_ms = Some(millis(execution.queue_time));
+        }
+    }
+
+    /// Value of a template placeholder, `-` if unknown.
+    pub fn field(
+        &self,
+        name: &str,
+    ) -> Option<String> {
+        fn or_dash<T: ToString>(value: &Option<T>) -> String {
+            value
+                .as_ref()
+                .map_or_else(|| "-".to_string(), ToString::to_string)
+        }
+
+        Some(match name {
+            "time" => self.time.clone(),
+            "request_id" => or_dash(&self.request_id),
+            "remote_addr" => or_dash(&self.remote_addr),
+            "method" => self.method.clone(),
+            "path" => self.path.clone(),
+            "protocol" => self.protocol.clone(),
+            "status" => self.status.to_string(),
+            "bytes" => or_dash(&self.bytes),
+            "duration_ms" => format!("{:.3}", self.duration_ms),
+            "pid" => or_dash(&self.pid),
+            "queue_ms" => {
+                or_dash(&self.queue_ms.map(|ms| format!("{:.3}", ms)))
+            }
+            "referer" => or_dash(&self.referer),
+            "user_agent" => or_dash(&self.user_agent),
+            _ => return None,
+        })
+    }
+
+    pub fn format(
+        &self,
+        format: &Format,
+    ) -> String {
+        let quoted = |name| format!("\"{}\"", self.field(name).unwrap());
+        match format {
+            Format::Common | Format::Combined => {
+                let mut line = format!(
+                    "{} - - [{}] \"{} {} {}\" {} {}",
+                    self.field("remote_addr").unwrap(),
+                    self.time,
+                    self.method,
+                    self.path,
+                    self.protocol,
+                    self.status,
+                    self.field("bytes").unwrap(),
+                );
+                if *format == Format::Combined {
+                    line.push(' ');
+                    line.push_str(&quoted("referer"));
+                    line.push(' ');
+                    line.push_str(&quoted("user_agent"));
+                }
+                line
+            }
+            Format::Json => {
+                serde_json::to_string(self).expect("entries always encode")
+            }
+            Format::Template(template) => render(template, |name| {
+                self.field(name)
+            }),
+        }
+    }
+}
+
+/// Writes an entry per served request to a file or stdout.
+pub struct AccessLog {
+    format: Format,
+    /// File to append to, stdout if `None`.
+    path:   Option<PathBuf>,
+    file:   Mutex<Option<File>>,
+}
+
+impl AccessLog {
+    pub fn new(
+        path: Option<PathBuf>,
+        format: Format,
+    ) -> Result<Self> {
+        let file = match &path {
+            Some(path) => Some(open(path)?),
+            None => None,
+        };
+
+        Ok(Self {
+            format,
+            path,
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn write(
+        &self,
+        entry: &Entry,
+    ) {
+        let mut line = entry.format(&self.format);
+        line.push('\n');
+
+        let mut file = self.file.lock().expect("access log lock is poisoned");
+        let result = match file.as_mut() {
+            Some(file) => file.write_all(line.as_bytes()),
+            None => io::stdout().lock().write_all(line.as_bytes()),
+        };
+        if let Err(err) = result {
+            log::error!("could not write access log: {}", err);
+        }
+    }
+
+    /// Writes `entry` for `response`, right away if its body size is known,
+    /// otherwise once the body is sent or the client went away.
+    pub fn log(
+        self: &Arc<Self>,
+        mut entry: Entry,
+        response: Response<Body>,
+    ) -> Response<Body> {
+        if entry.bytes.is_some() {
+            self.write(&entry);
+            return response;
+        }
+
+        entry.bytes = Some(0);
+        let pending = Pending {
+            access_log: self.clone(),
+            entry,
+        };
+        let (parts, body) = response.into_parts();
+        let body = stream::unfold((body, pending), |(mut body, mut pending)| {
+            async move {
+                let chunk = body.data().await?;
+                if let Ok(data) = &chunk {
+                    let bytes = pending.entry.bytes.get_or_insert(0);
+                    *bytes += data.len() as u64;
+                }
+                Some((chunk, (body, pending)))
+            }
+        });
+        Response::from_parts(parts, Body::wrap_stream(body))
+    }
+
+    /// Reopens the log file, called after it was moved by logrotate.
+    pub fn reopen(&self) -> Result<()> {
+        if let Some(path) = &self.path {
+            let reopened = open(path)?;
+            *self.file.lock().expect("access log lock is poisoned") =
+                Some(reopened);
+        }
+        Ok(())
+    }
+
+    /// Reopens the log file whenever SIGUSR1 is received.
+    pub fn reopen_on_signal(self: Arc<Self>) -> Result<()> {
+        let mut signals = signal(SignalKind::user_defined1())?;
+        tokio::spawn(async move {
+            while signals.recv().await.is_some() {
+                match self.reopen() {
+                    Ok(()) => log::info!("Reopened access log"),
+                    Err(err) => {
+                        log::error!("could not reopen access log: {}", err)
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+/// Entry of a response whose body is being sent, written when dropped.
+struct Pending {
+    access_log: Arc<AccessLog>,
+    entry:      Entry,
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.access_log.write(&self.entry);
+    }
+}
+
+fn open(path: &PathBuf) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|err| {
+            anyhow!("could not open access log {}: {}", path.display(), err)
+        })
+}
+
+/// Replaces `{name}` placeholders, unknown ones are kept as is.
+fn render(
+    template: &str,
+    field: impl Fn(&str) -> Option<String>,
+) -> String {
+    let mut line = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        line.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let value = rest
+            .find('}')
+            .and_then(|end| field(&rest[1..end]).map(|value| (end, value)));
+        match value {
+            Some((end, value)) => {
+                line.push_str(&value);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                line.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    line.push_str(rest);
+    line
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Formats `time` as `10/Oct/2000:13:55:36 +0000`.
+fn clf_time(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct",
+        "Nov", "Dec",
+    ];
+
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (days, secs) = ((secs / 86400) as i64, secs % 86400);
+
+    // Civil date from days since the epoch, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::StatusCode;
+
+    use super::*;
+
+    fn entry() -> Entry {
+        let mut req = Request::get("/hello?name=world")
+            .header(header::USER_AGENT, "curl/7.68.0")
+            .body(Body::empty())
+            .unwrap();
+        req.extensions_mut()
+            .insert(RemoteAddr("127.0.0.1:51234".parse().unwrap()));
+
+        let mut response = Response::new(Body::from("hello"));
+        *response.status_mut() = StatusCode::CREATED;
+        response.extensions_mut().insert(Execution {
+            pid:        Some(42),
+            queue_time: Duration::from_millis(2),
+            exec_time:  Duration::from_millis(10),
+        });
+
+        let mut entry = Entry::new(&req);
+        entry.finish(&response, Duration::from_millis(15));
+        entry.time = "10/Oct/2000:13:55:36 +0000".into();
+        entry
+    }
+
+    #[test]
+    fn formatting_entries() {
+        let entry = entry();
+
+        assert_eq!(
+            entry.format(&Format::Common),
+            "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \
+             \"GET /hello?name=world HTTP/1.1\" 201 5"
+        );
+        assert_eq!(
+            entry.format(&Format::Combined),
+            "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \
+             \"GET /hello?name=world HTTP/1.1\" 201 5 \"-\" \"curl/7.68.0\""
+        );
+        assert_eq!(
+            entry.format(&"{status} {pid} {queue_ms} {bogus}".parse().unwrap()),
+            "201 42 2.000 {bogus}"
+        );
+
+        let json: serde_json::Value =
+            serde_json::from_str(&entry.format(&Format::Json)).unwrap();
+        assert_eq!(json["path"], "/hello?name=world");
+        assert_eq!(json["duration_ms"], 15.0);
+        assert_eq!(json["referer"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn formatting_time() {
+        let time = UNIX_EPOCH + Duration::from_secs(971_186_136);
+        assert_eq!(clf_time(time), "10/Oct/2000:13:55:36 +0000");
+        assert_eq!(clf_time(UNIX_EPOCH), "01/Jan/1970:00:00:00 +0000");
+    }
+
+    #[test]
+    fn reopening_files() -> Result<()> {
+        let path = std::env::temp_dir().join("coyote.test.access.log");
+        let rotated = path.with_extension("log.1");
+        let _ = std::fs::remove_file(&path);
+
+        let log = AccessLog::new(Some(path.clone()), Format::Common)?;
+        log.write(&entry());
+        std::fs::rename(&path, &rotated)?;
+        log.reopen()?;
+        log.write(&entry());
+
+        assert_eq!(std::fs::read_to_string(&rotated)?.lines().count(), 1);
+        assert_eq!(std::fs::read_to_string(&path)?.lines().count(), 1);
+
+        std::fs::remove_file(path)?;
+        std::fs::remove_file(rotated)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,266 @@
+use std::fmt;
+use std::path::Path;
+
+use anyhow::Result;
+use hyper::body::{
+    Bytes,
+    HttpBody,
+};
+use hyper::Body;
+use multer::{
+    Constraints,
+    Multipart,
+    SizeLimit,
+};
+use serde::Serialize;
+use tempfile::TempPath;
+use tokio::io::AsyncWriteExt;
+
+/// Request body exceeding the configured limit, answered with 413.
+#[derive(Debug, PartialEq)]
+pub struct PayloadTooLarge {
+    pub limit: u64,
+}
+
+impl fmt::Display for PayloadTooLarge {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        write!(f, "request body exceeds {} bytes", self.limit)
+    }
+}
+
+impl std::error::Error for PayloadTooLarge {}
+
+/// Non-file field of a multipart form, `$_POST` on the PHP side.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct FormField {
+    pub name:  String,
+    pub value: String,
+}
+
+/// File uploaded with a multipart form, keys follow PHP's `$_FILES`.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct UploadedFile {
+    /// Form field the file was sent with.
+    pub field:    String,
+    /// Client side file name.
+    pub name:     String,
+    #[serde(rename = "type")]
+    pub mime:     String,
+    /// Temporary file holding the upload, removed after the response.
+    pub tmp_name: String,
+    pub size:     u64,
+    /// `UPLOAD_ERR_*` code, `UPLOAD_ERR_NO_FILE` for empty file inputs.
+    pub error:    u8,
+}
+
+/// `UPLOAD_ERR_NO_FILE` in PHP.
+const UPLOAD_ERR_NO_FILE: u8 = 4;
+
+/// Fields and files of a multipart form streamed to disk.
+#[derive(Debug, Default)]
+pub struct Form {
+    pub fields: Vec<FormField>,
+    pub files:  Vec<UploadedFile>,
+    /// Temporary files, deleted when dropped.
+    pub temp:   Vec<TempPath>,
+}
+
+/// Reads the whole body, failing with [`PayloadTooLarge`] once it grows
+/// past `max_size`.
+pub async fn read(
+    mut body: Body,
+    max_size: Option<u64>,
+) -> Result<Bytes> {
+    let mut buf = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if let Some(limit) = max_size {
+            if (buf.len() + chunk.len()) as u64 > limit {
+                return Err(PayloadTooLarge { limit }.into());
+            }
+        }
+        buf.extend_from_slice(&chunk);
+    }
+
+    Ok(buf.into())
+}
+
+/// Streams files of a multipart body to temporary files in `dir`, other
+/// fields are kept in memory.
+pub async fn read_multipart(
+    body: Body,
+    boundary: String,
+    max_size: Option<u64>,
+    dir: &Path,
+) -> Result<Form> {
+    let mut limit = SizeLimit::new();
+    if let Some(max_size) = max_size {
+        limit = limit.whole_stream(max_size);
+    }
+    let mut multipart = Multipart::with_constraints(
+        body,
+        boundary,
+        Constraints::new().size_limit(limit),
+    );
+
+    let mut form = Form::default();
+    let result = async {
+        while let Some(mut field) = multipart.next_field().await? {
+            let name = field.name().unwrap_or_default().to_string();
+            let file_name = match field.file_name() {
+                Some(file_name) => file_name.to_string(),
+                None => {
+                    let value = field.text().await?;
+                    form.fields.push(FormField { name, value });
+                    continue;
+                }
+            };
+            let mime = field
+                .content_type()
+                .map(|mime| mime.to_string())
+                .unwrap_or_default();
+
+            if file_name.is_empty() {
+                while field.chunk().await?.is_some() {}
+                form.files.push(UploadedFile {
+                    field: name,
+                    name: file_name,
+                    mime,
+                    tmp_name: String::new(),
+                    size: 0,
+                    error: UPLOAD_ERR_NO_FILE,
+                });
+                continue;
+            }
+
+            let temp = tempfile::Builder::new()
+                .prefix("coyote-upload-")
+                .tempfile_in(dir)?;
+            let mut file = tokio::fs::File::from_std(temp.reopen()?);
+            let temp = temp.into_temp_path();
+            let tmp_name = temp.to_string_lossy().into_owned();
+            form.temp.push(temp);
+
+            let mut size = 0;
+            while let Some(chunk) = field.chunk().await? {
+                size += chunk.len() as u64;
+                file.write_all(&chunk).await?;
+            }
+            file.flush().await?;
+
+            form.files.push(UploadedFile {
+                field: name,
+                name: file_name,
+                mime,
+                tmp_name,
+                size,
+                error: 0,
+            });
+        }
+        Ok::<_, anyhow::Error>(())
+    };
+
+    match result.await {
+        Ok(()) => Ok(form),
+        Err(err) => match err.downcast_ref().and_then(exceeded_limit) {
+            Some(limit) => Err(PayloadTooLarge { limit }.into()),
+            None => Err(err),
+        },
+    }
+}
+
+/// The size limit behind `err`, multer wraps it when hit while reading a
+/// field.
+fn exceeded_limit(err: &multer::Error) -> Option<u64> {
+    match err {
+        multer::Error::StreamSizeExceeded { limit } => Some(*limit),
+        multer::Error::StreamReadFailed(err) => {
+            err.downcast_ref().and_then(exceeded_limit)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &str = concat!(
+        "--XYZ\r\n",
+        "Content-Disposition: form-data; name=\"title\"\r\n",
+        "\r\n",
+        "holiday\r\n",
+        "--XYZ\r\n",
+        "Content-Disposition: form-data; name=\"photo\"; ",
+        "filename=\"beach.txt\"\r\n",
+        "Content-Type: text/plain\r\n",
+        "\r\n",
+        "sand and sea\r\n",
+        "--XYZ\r\n",
+        "Content-Disposition: form-data; name=\"other\"; filename=\"\"\r\n",
+        "Content-Type: application/octet-stream\r\n",
+        "\r\n",
+        "\r\n",
+        "--XYZ--\r\n",
+    );
+
+    #[tokio::test]
+    async fn limiting_body_size() -> Result<()> {
+        let body = read(Body::from("hello"), Some(5)).await?;
+        assert_eq!(body, "hello");
+
+        let err = read(Body::from("hello"), Some(4)).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<PayloadTooLarge>(),
+            Some(&PayloadTooLarge { limit: 4 })
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn streaming_uploads_to_disk() -> Result<()> {
+        let dir = std::env::temp_dir();
+        let form =
+            read_multipart(Body::from(BODY), "XYZ".into(), None, &dir).await?;
+
+        assert_eq!(form.fields, vec![FormField {
+            name:  "title".into(),
+            value: "holiday".into(),
+        }]);
+        assert_eq!(form.files.len(), 2);
+
+        let photo = &form.files[0];
+        assert_eq!(photo.field, "photo");
+        assert_eq!(photo.name, "beach.txt");
+        assert_eq!(photo.mime, "text/plain");
+        assert_eq!(photo.size, 12);
+        assert_eq!(std::fs::read_to_string(&photo.tmp_name)?, "sand and sea");
+        assert_eq!(form.files[1].error, UPLOAD_ERR_NO_FILE);
+
+        let tmp_name = photo.tmp_name.clone();
+        drop(form);
+        assert!(!Path::new(&tmp_name).exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn limiting_upload_size() -> Result<()> {
+        let dir = std::env::temp_dir();
+        let body = Body::from(BODY);
+        let err = read_multipart(body, "XYZ".into(), Some(64), &dir)
+            .await
+            .unwrap_err();
+
+        assert_eq!(
+            err.downcast_ref::<PayloadTooLarge>(),
+            Some(&PayloadTooLarge { limit: 64 })
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,379 @@
+use std::io;
+
+use anyhow::{
+    bail,
+    Result,
+};
+use async_compression::{
+    tokio::bufread::{
+        BrotliEncoder,
+        GzipEncoder,
+        ZstdEncoder,
+    },
+    Level,
+};
+use futures::TryStreamExt;
+use hyper::{
+    header,
+    Body,
+    HeaderMap,
+    Method,
+    Request,
+    Response,
+    StatusCode,
+};
+use tokio_util::io::{
+    ReaderStream,
+    StreamReader,
+};
+
+/// Brotli's default quality is too slow for compressing on the fly.
+const BROTLI_DEFAULT_LEVEL: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    Zstd,
+}
+
+/// Compresses responses with an encoding accepted by the client.
+#[derive(Clone)]
+pub struct Compression {
+    /// Encodings in order of preference.
+    encodings:  Vec<Encoding>,
+    /// Responses with a known length below this aren't compressed.
+    min_size:   u64,
+    /// Compressed content types, `text/*` matches any subtype.
+    mime_types: Vec<String>,
+    level:      Option<u32>,
+}
+
+impl Encoding {
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "br" => Ok(Encoding::Brotli),
+            "gzip" => Ok(Encoding::Gzip),
+            "zstd" => Ok(Encoding::Zstd),
+            _ => bail!("unsupported encoding: {}", name),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Zstd => "zstd",
+        }
+    }
+}
+
+impl Compression {
+    pub fn new(
+        encodings: Vec<Encoding>,
+        min_size: u64,
+        mime_types: Vec<String>,
+        level: Option<u32>,
+    ) -> Self {
+        Self {
+            encodings,
+            min_size,
+            mime_types,
+            level,
+        }
+    }
+
+    /// Picks the preferred encoding accepted by the client, must be called
+    /// before the request is consumed.
+    pub fn negotiate(
+        &self,
+        req: &Request<Body>,
+    ) -> Option<Encoding> {
+        if req.method() == Method::HEAD {
+            return None;
+        }
+
+        self.encodings
+            .iter()
+            .copied()
+            .find(|encoding| accepts_encoding(req.headers(), encoding.name()))
+    }
+
+    /// Compresses the response body unless it is already encoded, partial,
+    /// too small or not of a compressible type.
+    pub fn compress(
+        &self,
+        encoding: Encoding,
+        mut response: Response<Body>,
+    ) -> Response<Body> {
+        if !self.is_compressible(&response) {
+            return response;
+        }
+
+        let level = match (self.level, encoding) {
+            (Some(level), _) => Level::Precise(level),
+            (None, Encoding::Brotli) => Level::Precise(BROTLI_DEFAULT_LEVEL),
+            (None, _) => Level::Default,
+        };
+
+        let headers = response.headers_mut();
+        headers.remove(header::CONTENT_LENGTH);
+        headers.remove(header::ACCEPT_RANGES);
+        headers.insert(
+            header::CONTENT_ENCODING,
+            header::HeaderValue::from_static(encoding.name()),
+        );
+        if !varies_on_encoding(headers) {
+            headers.append(
+                header::VARY,
+                header::HeaderValue::from_static("accept-encoding"),
+            );
+        }
+        // Compressed body is no longer byte-for-byte identical.
+        if let Some(etag) = headers.get(header::ETAG) {
+            if !etag.as_bytes().starts_with(b"W/") {
+                let mut weak = b"W/".to_vec();
+                weak.extend_from_slice(etag.as_bytes());
+                if let Ok(weak) = header::HeaderValue::from_bytes(&weak) {
+                    headers.insert(header::ETAG, weak);
+                }
+            }
+        }
+
+        let body = std::mem::take(response.body_mut());
+        let reader = StreamReader::new(body.map_err(io::Error::other));
+        *response.body_mut() = match encoding {
+            Encoding::Brotli => Body::wrap_stream(ReaderStream::new(
+                BrotliEncoder::with_quality(reader, level),
+            )),
+            Encoding::Gzip => Body::wrap_stream(ReaderStream::new(
+                GzipEncoder::with_quality(reader, level),
+            )),
+            Encoding::Zstd => Body::wrap_stream(ReaderStream::new(
+                ZstdEncoder::with_quality(reader, level),
+            )),
+        };
+
+        response
+    }
+
+    fn is_compressible(
+        &self,
+        response: &Response<Body>,
+    ) -> bool {
+        let status = response.status();
+        if status.is_informational() ||
+            status == StatusCode::NO_CONTENT ||
+            status == StatusCode::NOT_MODIFIED ||
+            status == StatusCode::PARTIAL_CONTENT
+        {
+            return false;
+        }
+
+        let headers = response.headers();
+        if headers.contains_key(header::CONTENT_ENCODING) ||
+            headers.contains_key(header::CONTENT_RANGE)
+        {
+            return false;
+        }
+
+        let no_transform = headers
+            .get_all(header::CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|directive| directive.trim() == "no-transform");
+        if no_transform {
+            return false;
+        }
+
+        // Worker responses don't set a Content-Length, their bodies are
+        // fully buffered though.
+        let len = headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|len| len.to_str().ok())
+            .and_then(|len| len.parse::<u64>().ok())
+            .or_else(|| {
+                hyper::body::HttpBody::size_hint(response.body()).exact()
+            });
+        if matches!(len, Some(len) if len < self.min_size) {
+            return false;
+        }
+
+        let mime = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|mime| mime.to_str().ok())
+            .and_then(|mime| mime.split(';').next())
+            .map(|mime| mime.trim().to_lowercase());
+        match mime {
+            Some(mime) => self.mime_types.iter().any(|allowed| {
+                match allowed.strip_suffix("/*") {
+                    Some(ty) => mime.split('/').next() == Some(ty),
+                    None => *allowed == mime,
+                }
+            }),
+            None => false,
+        }
+    }
+}
+
+fn varies_on_encoding(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|name| {
+            let name = name.trim();
+            name == "*" || name.eq_ignore_ascii_case("accept-encoding")
+        })
+}
+
+/// Whether `Accept-Encoding` lists `encoding` without `q=0`.
+pub fn accepts_encoding(
+    headers: &HeaderMap,
+    encoding: &str,
+) -> bool {
+    headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|candidate| {
+            let mut params = candidate.split(';').map(str::trim);
+            let name = params.next().unwrap_or_default();
+            let rejected = params.any(|param| {
+                let q = param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok());
+                matches!(q, Some(q) if q == 0.0)
+            });
+            name.eq_ignore_ascii_case(encoding) && !rejected
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+
+    use super::*;
+    use crate::http::ResponseEnvelope;
+
+    fn compression() -> Compression {
+        Compression::new(
+            vec![Encoding::Brotli, Encoding::Gzip],
+            16,
+            vec!["text/*".into(), "application/json".into()],
+            None,
+        )
+    }
+
+    fn response(
+        content_type: &str,
+        body: &str,
+    ) -> Response<Body> {
+        Response::builder()
+            .header(header::CONTENT_TYPE, content_type)
+            .header(header::CONTENT_LENGTH, body.len())
+            .header(header::ETAG, r#""abc""#)
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    #[test]
+    fn negotiating_encodings() -> Result<()> {
+        let compression = compression();
+        let negotiate = |accept_encoding: &str| {
+            let req = Request::get("/")
+                .header(header::ACCEPT_ENCODING, accept_encoding)
+                .body(Body::empty())
+                .unwrap();
+            compression.negotiate(&req)
+        };
+
+        assert_eq!(negotiate("gzip, br"), Some(Encoding::Brotli));
+        assert_eq!(negotiate("gzip, br;q=0"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("zstd"), None);
+        assert_eq!(negotiate("identity"), None);
+
+        let req = Request::head("/")
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .body(Body::empty())?;
+        assert_eq!(compression.negotiate(&req), None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compressing_responses() -> Result<()> {
+        let body = r#"{"hello":"world","hello again":"world"}"#;
+        let response = compression()
+            .compress(Encoding::Gzip, response("application/json", body));
+
+        let headers = response.headers();
+        assert_eq!(headers[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(headers[header::VARY], "accept-encoding");
+        assert_eq!(headers[header::ETAG], r#"W/"abc""#);
+        assert!(!headers.contains_key(header::CONTENT_LENGTH));
+
+        let compressed = hyper::body::to_bytes(response.into_body()).await?;
+        let mut decompressed = String::new();
+        GzDecoder::new(&compressed[..]).read_to_string(&mut decompressed)?;
+        assert_eq!(decompressed, body);
+
+        Ok(())
+    }
+
+    #[test]
+    fn skipping_incompressible_responses() {
+        let compression = compression();
+        let body = "long enough to be compressed";
+
+        let small = response("text/plain", "tiny");
+        let image = response("image/png", body);
+        let mut encoded = response("text/html", body);
+        encoded
+            .headers_mut()
+            .insert(header::CONTENT_ENCODING, "br".parse().unwrap());
+        let mut no_transform = response("text/html", body);
+        no_transform
+            .headers_mut()
+            .insert(header::CACHE_CONTROL, "no-transform".parse().unwrap());
+        let mut not_modified = response("text/html", body);
+        *not_modified.status_mut() = StatusCode::NOT_MODIFIED;
+
+        for response in [small, image, no_transform, not_modified] {
+            let response = compression.compress(Encoding::Gzip, response);
+            assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+        }
+
+        let response = compression.compress(Encoding::Gzip, encoded);
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "br");
+    }
+
+    #[test]
+    fn skipping_small_worker_responses() -> Result<()> {
+        let compression = compression();
+        let worker_response = |body: &str| {
+            let envelope = serde_json::json!({
+                "headers": {"content-type": "text/plain"},
+                "body": body,
+            });
+            ResponseEnvelope::from_slice(envelope.to_string().as_bytes())?
+                .into_response()
+        };
+
+        let response =
+            compression.compress(Encoding::Gzip, worker_response("tiny")?);
+        assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+
+        let body = "long enough to be compressed";
+        let response =
+            compression.compress(Encoding::Gzip, worker_response(body)?);
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+
+        Ok(())
+    }
+}
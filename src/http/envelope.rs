@@ -0,0 +1,362 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{
+    anyhow,
+    Result,
+};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use hyper::{
+    header::{
+        self,
+        HeaderName,
+        HeaderValue,
+    },
+    Body,
+    Request,
+    Response,
+    StatusCode,
+    Version,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+use super::body::{
+    self,
+    FormField,
+    PayloadTooLarge,
+    UploadedFile,
+};
+use crate::telemetry::Propagation;
+use crate::websocket::Commands;
+
+/// HTTP request as sent to PHP workers.
+#[derive(Debug, Default, Serialize)]
+pub struct RequestEnvelope {
+    pub method:   String,
+    pub uri:      String,
+    pub protocol: String,
+    pub headers:  BTreeMap<String, Vec<String>>,
+    /// Body, base64 encoded if `binary`.
+    pub body:     String,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub binary:   bool,
+    /// Fields of multipart forms whose files were streamed to disk, the
+    /// body is left empty then.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub form:     Vec<FormField>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub files:    Vec<UploadedFile>,
+    /// Uploaded files, removed once the envelope is dropped.
+    #[serde(skip)]
+    pub uploads:  Vec<tempfile::TempPath>,
+    /// W3C trace context of the worker execution span.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trace:    Option<Propagation>,
+}
+
+/// HTTP response as sent back by PHP workers.
+#[derive(Debug, Deserialize)]
+pub struct ResponseEnvelope {
+    #[serde(default = "default_status")]
+    pub status:    u16,
+    #[serde(default)]
+    pub headers:   BTreeMap<String, HeaderValues>,
+    /// Body, base64 encoded if `binary`.
+    #[serde(default)]
+    pub body:      String,
+    #[serde(default)]
+    pub binary:    bool,
+    /// Commands for WebSocket connections held by coyote.
+    pub websocket: Option<Commands>,
+}
+
+/// Header values, PHP side may send a single value as a plain string.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum HeaderValues {
+    One(String),
+    Many(Vec<String>),
+}
+
+fn default_status() -> u16 {
+    200
+}
+
+impl RequestEnvelope {
+    /// Reads at most `max_size` bytes of body, failing with
+    /// [`PayloadTooLarge`]. Files of multipart forms are streamed to
+    /// `upload_dir` if given.
+    pub async fn from_request_limited(
+        req: Request<Body>,
+        max_size: Option<u64>,
+        upload_dir: Option<&Path>,
+    ) -> Result<Self> {
+        let (parts, body) = req.into_parts();
+
+        let content_length = parts
+            .headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|len| len.to_str().ok())
+            .and_then(|len| len.parse::<u64>().ok());
+        if let (Some(len), Some(limit)) = (content_length, max_size) {
+            if len > limit {
+                return Err(PayloadTooLarge { limit }.into());
+            }
+        }
+
+        let mut headers = BTreeMap::<_, Vec<_>>::new();
+        for (name, value) in &parts.headers {
+            headers
+                .entry(name.as_str().to_string())
+                .or_default()
+                .push(String::from_utf8_lossy(value.as_bytes()).into_owned());
+        }
+
+        let mut envelope = Self {
+            method: parts.method.to_string(),
+            uri: parts.uri.to_string(),
+            protocol: protocol(parts.version).to_string(),
+            headers,
+            ..Self::default()
+        };
+
+        let boundary = parts
+            .headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .filter(|content_type| {
+                content_type.starts_with("multipart/form-data")
+            })
+            .and_then(|content_type| multer::parse_boundary(content_type).ok());
+        match (boundary, upload_dir) {
+            (Some(boundary), Some(dir)) => {
+                let form =
+                    body::read_multipart(body, boundary, max_size, dir).await?;
+                envelope.form = form.fields;
+                envelope.files = form.files;
+                envelope.uploads = form.temp;
+            }
+            _ => {
+                let body = body::read(body, max_size).await?;
+                envelope.set_body(&body);
+            }
+        }
+
+        Ok(envelope)
+    }
+
+    /// Sets the body, base64 encoded if it isn't valid UTF-8.
+    pub fn set_body(
+        &mut self,
+        body: &[u8],
+    ) {
+        match std::str::from_utf8(body) {
+            Ok(body) => {
+                self.body = body.to_string();
+                self.binary = false;
+            }
+            Err(_) => {
+                self.body = BASE64.encode(body);
+                self.binary = true;
+            }
+        }
+    }
+
+    pub fn to_vec(&self) -> Result<Vec<u8>> {
+        serde_json::to_vec(self)
+            .map_err(|err| anyhow!("could not encode request: {}", err))
+    }
+}
+
+impl ResponseEnvelope {
+    pub fn from_slice(buf: &[u8]) -> Result<Self> {
+        serde_json::from_slice(buf)
+            .map_err(|err| anyhow!("could not decode worker response: {}", err))
+    }
+
+    pub fn into_response(self) -> Result<Response<Body>> {
+        let body = match self.binary {
+            true => BASE64.decode(&self.body).map_err(|err| {
+                anyhow!("could not decode worker response body: {}", err)
+            })?,
+            false => self.body.into_bytes(),
+        };
+        let mut response = Response::new(Body::from(body));
+        *response.status_mut() = StatusCode::from_u16(self.status)?;
+
+        let headers = response.headers_mut();
+        for (name, values) in self.headers {
+            let name = HeaderName::from_bytes(name.as_bytes())?;
+            let values = match values {
+                HeaderValues::One(value) => vec![value],
+                HeaderValues::Many(values) => values,
+            };
+            for value in values {
+                headers.append(&name, HeaderValue::from_str(&value)?);
+            }
+        }
+
+        Ok(response)
+    }
+}
+
+fn protocol(version: Version) -> &'static str {
+    match version {
+        Version::HTTP_09 => "HTTP/0.9",
+        Version::HTTP_10 => "HTTP/1.0",
+        Version::HTTP_2 => "HTTP/2.0",
+        Version::HTTP_3 => "HTTP/3.0",
+        _ => "HTTP/1.1",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn encoding_requests() -> Result<()> {
+        let req = Request::post("/hello/world?foo=bar")
+            .header(header::HOST, "example.com")
+            .header(header::ACCEPT, "text/html")
+            .header(header::ACCEPT, "application/json")
+            .body(Body::from("hello"))?;
+
+        let envelope =
+            RequestEnvelope::from_request_limited(req, None, None).await?;
+
+        assert_eq!(envelope.method, "POST");
+        assert_eq!(envelope.uri, "/hello/world?foo=bar");
+        assert_eq!(envelope.protocol, "HTTP/1.1");
+        assert_eq!(envelope.headers["host"], vec!["example.com"]);
+        assert_eq!(envelope.headers["accept"], vec![
+            "text/html",
+            "application/json"
+        ]);
+        assert_eq!(envelope.body, "hello");
+        assert!(!envelope.binary);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn passing_binary_bodies() -> Result<()> {
+        let bytes = vec![0x1f, 0x8b, 0x08, 0x00, 0xff];
+        let req = Request::post("/").body(Body::from(bytes.clone()))?;
+
+        let envelope =
+            RequestEnvelope::from_request_limited(req, None, None).await?;
+        let json = serde_json::to_value(&envelope)?;
+
+        assert_eq!(json["body"], "H4sIAP8=");
+        assert_eq!(json["binary"], true);
+
+        let response = ResponseEnvelope::from_slice(
+            br#"{"body": "H4sIAP8=", "binary": true}"#,
+        )?
+        .into_response()?;
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+
+        assert_eq!(body, bytes);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn passing_uploads() -> Result<()> {
+        let req = Request::post("/upload")
+            .header(header::CONTENT_TYPE, "multipart/form-data; boundary=X")
+            .body(Body::from(concat!(
+                "--X\r\n",
+                "Content-Disposition: form-data; name=\"a\"; ",
+                "filename=\"a.txt\"\r\n",
+                "\r\n",
+                "hello\r\n",
+                "--X--\r\n",
+            )))?;
+        let dir = std::env::temp_dir();
+
+        let envelope =
+            RequestEnvelope::from_request_limited(req, None, Some(&dir))
+                .await?;
+        let json = serde_json::to_value(&envelope)?;
+
+        assert_eq!(envelope.body, "");
+        assert_eq!(json["files"][0]["name"], "a.txt");
+        assert_eq!(json["files"][0]["size"], 5);
+        assert_eq!(json["files"][0]["tmp_name"], envelope.files[0].tmp_name);
+        assert!(json.get("form").is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejecting_large_bodies() -> Result<()> {
+        let req = Request::post("/")
+            .header(header::CONTENT_LENGTH, "5")
+            .body(Body::from("hello"))?;
+
+        let err = RequestEnvelope::from_request_limited(req, Some(4), None)
+            .await
+            .unwrap_err();
+
+        assert!(err.downcast_ref::<PayloadTooLarge>().is_some());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reporting_protocol_version() -> Result<()> {
+        let req = Request::get("/")
+            .version(Version::HTTP_2)
+            .body(Body::empty())?;
+
+        let envelope =
+            RequestEnvelope::from_request_limited(req, None, None).await?;
+
+        assert_eq!(envelope.protocol, "HTTP/2.0");
+
+        Ok(())
+    }
+
+    #[test]
+    fn decoding_responses() -> Result<()> {
+        let response = ResponseEnvelope::from_slice(
+            br#"{
+                "status": 201,
+                "headers": {
+                    "content-type": "application/json",
+                    "set-cookie": ["a=1", "b=2"]
+                },
+                "body": "{\"hello\":\"world\"}"
+            }"#,
+        )?
+        .into_response()?;
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/json"
+        );
+        assert_eq!(
+            response.headers().get_all(header::SET_COOKIE).iter().count(),
+            2
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn decoding_responses_with_defaults() -> Result<()> {
+        let response = ResponseEnvelope::from_slice(b"{}")?.into_response()?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().is_empty());
+
+        Ok(())
+    }
+}
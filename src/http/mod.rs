@@ -0,0 +1,339 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use anyhow::Result;
+use hyper::{
+    header::{
+        self,
+        HeaderValue,
+    },
+    Body,
+    Request,
+    Response,
+    StatusCode,
+};
+use ring::rand::{
+    SecureRandom,
+    SystemRandom,
+};
+use tracing::Instrument;
+
+mod access_log;
+mod body;
+mod compression;
+mod envelope;
+mod router;
+mod static_files;
+
+pub use access_log::{
+    AccessLog,
+    Entry,
+    RemoteAddr,
+};
+pub use body::PayloadTooLarge;
+pub use compression::{
+    Compression,
+    Encoding,
+};
+pub use envelope::{
+    RequestEnvelope,
+    ResponseEnvelope,
+};
+pub use router::{
+    match_host,
+    strip_port,
+    Route,
+    Router,
+    Upstream,
+};
+pub use static_files::StaticFiles;
+use crate::events::Events;
+use crate::health::Health;
+use crate::telemetry::{
+    RequestTrace,
+    Tracer,
+};
+use crate::websocket::WebSocket;
+use crate::worker::pool::Overloaded;
+
+#[derive(Clone)]
+pub struct Handler {
+    pub router:       Router,
+    /// Files served before falling through to PHP.
+    pub static_files: Option<StaticFiles>,
+    pub compression:  Option<Compression>,
+    /// WebSocket endpoint bridged to PHP workers.
+    pub websocket:    Option<Arc<WebSocket>>,
+    /// Server-Sent Events and long-polling endpoint.
+    pub events:       Option<Arc<Events>>,
+    pub access_log:   Option<Arc<AccessLog>>,
+    /// Exports OpenTelemetry spans of requests.
+    pub tracer:       Option<Arc<Tracer>>,
+    /// Liveness and readiness endpoints.
+    pub health:       Option<Arc<Health>>,
+}
+
+/// Correlates a request across coyote's logs, the access log and PHP.
+const X_REQUEST_ID: &str = "x-request-id";
+
+pub async fn handle(
+    mut req: Request<Body>,
+    handler: Arc<Handler>,
+) -> Result<Response<Body>> {
+    let id = request_id(&req);
+    req.headers_mut().insert(X_REQUEST_ID, id.clone());
+    let span = tracing::info_span!(
+        "request",
+        id = id.to_str().unwrap_or_default(),
+        method = %req.method(),
+        path = req.uri().path(),
+        pid = tracing::field::Empty,
+    );
+
+    let mut response = serve(req, &handler).instrument(span).await?;
+    response.headers_mut().insert(X_REQUEST_ID, id);
+    Ok(response)
+}
+
+async fn serve(
+    mut req: Request<Body>,
+    handler: &Handler,
+) -> Result<Response<Body>> {
+    let start = Instant::now();
+    let trace = handler.tracer.as_ref().map(|tracer| tracer.start(&mut req));
+    let entry = handler.access_log.as_ref().map(|_| Entry::new(&req));
+    let encoding = handler
+        .compression
+        .as_ref()
+        .and_then(|compression| compression.negotiate(&req));
+
+    let response = respond(req, handler).await?;
+
+    let mut response = match (&handler.compression, encoding) {
+        (Some(compression), Some(encoding)) => {
+            compression.compress(encoding, response)
+        }
+        _ => response,
+    };
+    if let (Some(access_log), Some(mut entry)) = (&handler.access_log, entry)
+    {
+        entry.finish(&response, start.elapsed());
+        response = access_log.log(entry, response);
+    }
+    if let (Some(tracer), Some(trace)) = (&handler.tracer, trace) {
+        tracer.finish(trace, &response);
+    }
+    Ok(response)
+}
+
+async fn respond(
+    req: Request<Body>,
+    handler: &Handler,
+) -> Result<Response<Body>> {
+    let host = req
+        .uri()
+        .host()
+        .or_else(|| {
+            req.headers()
+                .get(header::HOST)
+                .and_then(|host| host.to_str().ok())
+        })
+        .map(String::from);
+
+    if let Some(health) = &handler.health {
+        if health.matches(&req) {
+            return health.respond(&req);
+        }
+    }
+
+    if let Some(websocket) = &handler.websocket {
+        if websocket.matches(&req) {
+            let host = host.as_deref();
+            return websocket.upgrade(req, host, &handler.router).await;
+        }
+    }
+
+    if let Some(events) = &handler.events {
+        if events.matches(&req) {
+            let host = host.as_deref();
+            return events.subscribe(req, host, &handler.router).await;
+        }
+    }
+
+    if let Some(static_files) = &handler.static_files {
+        if let Some(response) = static_files.serve(&req).await? {
+            return Ok(response);
+        }
+    }
+
+    let path = req.uri().path();
+    let route = match handler.router.route(host.as_deref(), path) {
+        Some(route) => route,
+        None => return Ok(status(StatusCode::NOT_FOUND)),
+    };
+
+    let upstream = &route.upstream;
+    let upload_dir = upstream.upload_dir.as_deref();
+    let trace = req.extensions().get::<RequestTrace>().cloned();
+    let mut envelope = match RequestEnvelope::from_request_limited(
+        req,
+        route.max_body_size(),
+        upload_dir,
+    )
+    .await
+    {
+        Ok(envelope) => envelope,
+        Err(err) => match err.downcast_ref::<PayloadTooLarge>() {
+            Some(reason) => {
+                log::debug!(
+                    "rejecting request to pool {}: {}",
+                    upstream.name,
+                    reason
+                );
+                return Ok(status(StatusCode::PAYLOAD_TOO_LARGE));
+            }
+            None => return Err(err),
+        },
+    };
+    envelope.trace = trace.map(|trace| trace.propagation());
+    dispatch(upstream, envelope).await
+}
+
+/// Executes `envelope` on `upstream`'s pool, overload is reported with 503.
+pub async fn dispatch(
+    upstream: &Upstream,
+    envelope: RequestEnvelope,
+) -> Result<Response<Body>> {
+    let req = envelope.to_vec()?.into();
+    let (response, execution) = match upstream.pool.exec_traced(req).await {
+        Ok(traced) => traced,
+        Err(err) => match err.downcast_ref::<Overloaded>() {
+            Some(reason) => {
+                log::warn!(
+                    "rejecting request to pool {}: {} (queue length: {})",
+                    upstream.name,
+                    reason,
+                    upstream.pool.queue_len()
+                );
+                return Ok(service_unavailable(upstream.retry_after));
+            }
+            None => return Err(err),
+        },
+    };
+
+    let mut envelope = ResponseEnvelope::from_slice(&response.0)?;
+    if let Some(commands) = envelope.websocket.take() {
+        upstream.hub.apply(commands);
+    }
+    let mut response = envelope.into_response()?;
+    if let Some(pid) = execution.pid {
+        tracing::Span::current().record("pid", pid);
+    }
+    response.extensions_mut().insert(execution);
+    Ok(response)
+}
+
+pub fn status(status: StatusCode) -> Response<Body> {
+    let mut response = Response::default();
+    *response.status_mut() = status;
+    response
+}
+
+pub fn service_unavailable(retry_after: u64) -> Response<Body> {
+    let mut response = status(StatusCode::SERVICE_UNAVAILABLE);
+    response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, retry_after.into());
+    response
+}
+
+/// The client's `X-Request-Id` if it looks sane, a random one otherwise.
+fn request_id(req: &Request<Body>) -> HeaderValue {
+    if let Some(id) = req.headers().get(X_REQUEST_ID) {
+        if !id.is_empty() && id.len() <= 128 && id.to_str().is_ok() {
+            return id.clone();
+        }
+    }
+
+    let mut bytes = [0u8; 8];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("system random number generator failed");
+    let id: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    HeaderValue::from_str(&id).expect("hex is a valid header value")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn generating_request_ids() {
+        let req = Request::get("/")
+            .header(X_REQUEST_ID, "abc-123")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(request_id(&req), "abc-123");
+
+        let req = Request::get("/").body(Body::empty()).unwrap();
+        let (first, second) = (request_id(&req), request_id(&req));
+        assert_eq!(first.len(), 16);
+        assert_ne!(first, second);
+    }
+
+    #[tokio::test]
+    async fn logging_bytes_sent() -> Result<()> {
+        let root = std::env::temp_dir().join("coyote.test.access_log");
+        fs::create_dir_all(&root)?;
+        fs::write(root.join("hello.txt"), "hello static files")?;
+        fs::write(root.join("style.css"), "body { color: red; }\n".repeat(50))?;
+        let log = root.join("access.log");
+        let _ = fs::remove_file(&log);
+
+        let handler = Handler {
+            router:       Router::new(vec![]),
+            static_files: Some(StaticFiles::new(&root, None, vec![], false)),
+            compression:  Some(Compression::new(
+                vec![Encoding::Gzip],
+                16,
+                vec!["text/*".into()],
+                None,
+            )),
+            websocket:    None,
+            events:       None,
+            access_log:   Some(Arc::new(AccessLog::new(
+                Some(log.clone()),
+                "{path} {bytes}".parse()?,
+            )?)),
+            tracer:       None,
+            health:       None,
+        };
+        let get = |path, encoding| {
+            Request::get(path)
+                .header(header::ACCEPT_ENCODING, encoding)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = serve(get("/hello.txt", "identity"), &handler).await?;
+        let plain = hyper::body::to_bytes(response.into_body()).await?;
+        assert_eq!(plain, "hello static files");
+
+        let response = serve(get("/style.css", "gzip"), &handler).await?;
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        let compressed = hyper::body::to_bytes(response.into_body()).await?;
+
+        assert_eq!(
+            fs::read_to_string(&log)?,
+            format!(
+                "/hello.txt {}\n/style.css {}\n",
+                plain.len(),
+                compressed.len()
+            )
+        );
+
+        Ok(())
+    }
+}
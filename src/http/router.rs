@@ -0,0 +1,191 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::websocket::Hub;
+use crate::worker::pool::Pool;
+
+/// A named worker pool requests can be routed to.
+pub struct Upstream {
+    pub name:          String,
+    pub pool:          Arc<dyn Pool>,
+    /// `Retry-After` seconds sent with 503 responses when overloaded.
+    pub retry_after:   u64,
+    /// Applies WebSocket commands workers send with responses.
+    pub hub:           Arc<Hub>,
+    /// Largest request body in bytes, unlimited if `None`.
+    pub max_body_size: Option<u64>,
+    /// Directory multipart uploads are streamed to, bodies are passed as
+    /// is if `None`.
+    pub upload_dir:    Option<PathBuf>,
+}
+
+#[derive(Clone)]
+pub struct Route {
+    /// Host to match, `*.example.com` matches any subdomain.
+    pub host:          Option<String>,
+    /// Path prefix to match.
+    pub path:          Option<String>,
+    pub upstream:      Arc<Upstream>,
+    /// Overrides the upstream's body size limit.
+    pub max_body_size: Option<u64>,
+}
+
+/// Maps requests to upstreams by host and path prefix, the first matching
+/// route wins.
+#[derive(Clone)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    pub fn new(routes: Vec<Route>) -> Self {
+        Self { routes }
+    }
+
+    pub fn route(
+        &self,
+        host: Option<&str>,
+        path: &str,
+    ) -> Option<&Route> {
+        self.routes.iter().find(|route| route.matches(host, path))
+    }
+}
+
+impl Route {
+    /// Largest request body accepted on this route.
+    pub fn max_body_size(&self) -> Option<u64> {
+        self.max_body_size.or(self.upstream.max_body_size)
+    }
+
+    fn matches(
+        &self,
+        host: Option<&str>,
+        path: &str,
+    ) -> bool {
+        let host_matches = match (&self.host, host) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(pattern), Some(host)) => match_host(pattern, host),
+        };
+        let path_matches = match &self.path {
+            None => true,
+            Some(prefix) => path.starts_with(prefix.as_str()),
+        };
+
+        host_matches && path_matches
+    }
+}
+
+/// Matches `host` against `pattern`, `*.example.com` matches any
+/// subdomain.
+pub fn match_host(
+    pattern: &str,
+    host: &str,
+) -> bool {
+    // Ports are not part of the routing rules.
+    let host = strip_port(host);
+
+    match pattern.strip_prefix("*.") {
+        Some(domain) => {
+            let split = host.len().checked_sub(domain.len());
+            matches!(
+                split.and_then(|i| host.split_at_checked(i)),
+                Some((sub, suffix)) if sub.len() > 1 &&
+                    sub.ends_with('.') &&
+                    suffix.eq_ignore_ascii_case(domain)
+            )
+        }
+        None => pattern.eq_ignore_ascii_case(host),
+    }
+}
+
+/// Removes the port from a `Host` header value.
+pub fn strip_port(host: &str) -> &str {
+    match host.rfind(':') {
+        Some(i) if !host.ends_with(']') => &host[..i],
+        _ => host,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::worker::pool::tests::{
+        noop,
+        Stub,
+    };
+
+    fn upstream(name: &str) -> Arc<Upstream> {
+        Arc::new(Upstream {
+            name:          name.to_string(),
+            pool:          Arc::new(Stub(noop)),
+            retry_after:   1,
+            hub:           Arc::new(Hub::new()),
+            max_body_size: Some(1024),
+            upload_dir:    None,
+        })
+    }
+
+    fn route(
+        host: Option<&str>,
+        path: Option<&str>,
+        upstream: &Arc<Upstream>,
+    ) -> Route {
+        Route {
+            host:          host.map(String::from),
+            path:          path.map(String::from),
+            upstream:      upstream.clone(),
+            max_body_size: None,
+        }
+    }
+
+    #[test]
+    fn routing_by_host_and_path() {
+        let api = upstream("api");
+        let reports = upstream("reports");
+        let admin = upstream("admin");
+        let router = Router::new(vec![
+            route(Some("admin.example.com"), None, &admin),
+            route(None, Some("/reports/"), &reports),
+            route(Some("*.example.com"), Some("/api/"), &api),
+        ]);
+
+        let find = |host, path| {
+            let route = router.route(host, path);
+            route.map(|route| &route.upstream.name[..])
+        };
+
+        assert_eq!(find(Some("admin.example.com:8080"), "/"), Some("admin"));
+        assert_eq!(find(Some("admin.example.com"), "/reports/"), Some("admin"));
+        assert_eq!(find(None, "/reports/monthly"), Some("reports"));
+        assert_eq!(find(Some("www.example.com"), "/api/users"), Some("api"));
+        assert_eq!(find(Some("example.com"), "/api/users"), None);
+        assert_eq!(find(Some("www.example.com"), "/"), None);
+    }
+
+    #[test]
+    fn matching_hosts_ignoring_case() {
+        assert!(match_host("Admin.Example.com", "admin.example.COM"));
+        assert!(match_host("*.example.com", "WWW.Example.Com:443"));
+        assert!(match_host("*.EXAMPLE.com", "www.example.com"));
+        assert!(!match_host("*.example.com", "Example.com"));
+        assert!(!match_host("*.example.com", "www.example.org"));
+    }
+
+    #[test]
+    fn overriding_body_size_limits() {
+        let app = upstream("app");
+        let router = Router::new(vec![
+            Route {
+                max_body_size: Some(1 << 20),
+                ..route(None, Some("/upload/"), &app)
+            },
+            route(None, None, &app),
+        ]);
+
+        let limit = |path| router.route(None, path).unwrap().max_body_size();
+
+        assert_eq!(limit("/upload/avatar"), Some(1 << 20));
+        assert_eq!(limit("/"), Some(1024));
+    }
+}
@@ -0,0 +1,513 @@
+use std::io::SeekFrom;
+use std::path::{
+    Component,
+    Path,
+    PathBuf,
+};
+use std::time::{
+    Duration,
+    SystemTime,
+    UNIX_EPOCH,
+};
+
+use anyhow::Result;
+use hyper::{
+    header,
+    Body,
+    HeaderMap,
+    Method,
+    Request,
+    Response,
+    StatusCode,
+};
+use percent_encoding::percent_decode_str;
+use tokio::fs::{
+    self,
+    File,
+};
+use tokio::io::{
+    AsyncReadExt,
+    AsyncSeekExt,
+};
+use tokio_util::io::ReaderStream;
+
+use super::compression::accepts_encoding;
+
+/// Serves files under a document root, requests for files that don't exist
+/// or aren't allowed fall through to PHP.
+#[derive(Clone)]
+pub struct StaticFiles {
+    root:          PathBuf,
+    /// Extensions allowed to be served, everything if `None`.
+    allow:         Option<Vec<String>>,
+    /// Extensions never served, takes precedence over `allow`.
+    deny:          Vec<String>,
+    /// Serve `.br`/`.gz` siblings of files if the client accepts them.
+    precompressed: bool,
+}
+
+/// A file on disk chosen to answer a request.
+struct Representation {
+    path:     PathBuf,
+    len:      u64,
+    modified: SystemTime,
+    encoding: Option<&'static str>,
+}
+
+impl StaticFiles {
+    pub fn new(
+        root: impl Into<PathBuf>,
+        allow: Option<Vec<String>>,
+        deny: Vec<String>,
+        precompressed: bool,
+    ) -> Self {
+        let lowercase = |exts: Vec<String>| {
+            exts.into_iter().map(|ext| ext.to_lowercase()).collect()
+        };
+
+        Self {
+            root: root.into(),
+            allow: allow.map(lowercase),
+            deny: lowercase(deny),
+            precompressed,
+        }
+    }
+
+    /// Returns `None` if the request should be handled by PHP.
+    pub async fn serve(
+        &self,
+        req: &Request<Body>,
+    ) -> Result<Option<Response<Body>>> {
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            return Ok(None);
+        }
+
+        let path = match self.resolve(req.uri().path()) {
+            Some(path) if self.is_allowed(&path) => path,
+            _ => return Ok(None),
+        };
+        let file = match self.representation(&path, req.headers()).await {
+            Some(file) => file,
+            None => return Ok(None),
+        };
+
+        let etag = file.etag();
+        let last_modified = httpdate::fmt_http_date(file.modified);
+
+        let mut response = Response::new(Body::empty());
+        let headers = response.headers_mut();
+        headers.insert(header::ETAG, etag.parse()?);
+        headers.insert(header::LAST_MODIFIED, last_modified.parse()?);
+        if self.precompressed {
+            headers.insert(header::VARY, "accept-encoding".parse()?);
+        }
+
+        if is_not_modified(req.headers(), &etag, file.modified) {
+            *response.status_mut() = StatusCode::NOT_MODIFIED;
+            return Ok(Some(response));
+        }
+
+        let mime = mime_guess::from_path(&path).first_or_octet_stream();
+        let headers = response.headers_mut();
+        headers.insert(header::CONTENT_TYPE, mime.as_ref().parse()?);
+        if let Some(encoding) = file.encoding {
+            headers.insert(header::CONTENT_ENCODING, encoding.parse()?);
+        } else {
+            headers.insert(header::ACCEPT_RANGES, "bytes".parse()?);
+        }
+
+        let range = match file.encoding {
+            Some(_) => None,
+            None => requested_range(req.headers(), &etag, &last_modified)
+                .and_then(|range| parse_range(range, file.len)),
+        };
+        let (start, len) = match range {
+            Some(Ok((start, end))) => {
+                *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+                response.headers_mut().insert(
+                    header::CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", start, end, file.len).parse()?,
+                );
+                (start, end - start + 1)
+            }
+            Some(Err(())) => {
+                *response.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+                response.headers_mut().insert(
+                    header::CONTENT_RANGE,
+                    format!("bytes */{}", file.len).parse()?,
+                );
+                return Ok(Some(response));
+            }
+            None => (0, file.len),
+        };
+
+        response
+            .headers_mut()
+            .insert(header::CONTENT_LENGTH, len.into());
+        if req.method() == Method::GET {
+            let mut f = File::open(&file.path).await?;
+            f.seek(SeekFrom::Start(start)).await?;
+            *response.body_mut() =
+                Body::wrap_stream(ReaderStream::new(f.take(len)));
+        }
+
+        Ok(Some(response))
+    }
+
+    /// Maps a request path to a file under root, rejecting paths escaping
+    /// root and dotfiles.
+    fn resolve(
+        &self,
+        path: &str,
+    ) -> Option<PathBuf> {
+        let path = percent_decode_str(path).decode_utf8().ok()?;
+        if path.contains('\0') {
+            return None;
+        }
+
+        let mut resolved = self.root.clone();
+        for component in Path::new(path.as_ref()).components() {
+            match component {
+                Component::Normal(part) => {
+                    if part.to_str()?.starts_with('.') {
+                        return None;
+                    }
+                    resolved.push(part);
+                }
+                Component::RootDir | Component::CurDir => {}
+                Component::ParentDir | Component::Prefix(_) => return None,
+            }
+        }
+
+        Some(resolved)
+    }
+
+    fn is_allowed(
+        &self,
+        path: &Path,
+    ) -> bool {
+        let ext = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .unwrap_or_default()
+            .to_lowercase();
+
+        if self.deny.contains(&ext) {
+            return false;
+        }
+
+        match &self.allow {
+            Some(allow) => allow.contains(&ext),
+            None => true,
+        }
+    }
+
+    /// Picks the file to serve, preferring precompressed variants accepted by
+    /// the client. Range requests are always served from the original file.
+    async fn representation(
+        &self,
+        path: &Path,
+        headers: &HeaderMap,
+    ) -> Option<Representation> {
+        let original = Representation::open(path, None).await?;
+        if !self.precompressed || headers.contains_key(header::RANGE) {
+            return Some(original);
+        }
+
+        for (encoding, ext) in &[("br", "br"), ("gzip", "gz")] {
+            if !accepts_encoding(headers, encoding) {
+                continue;
+            }
+
+            let mut variant = path.as_os_str().to_owned();
+            variant.push(".");
+            variant.push(ext);
+            if let Some(file) =
+                Representation::open(Path::new(&variant), Some(encoding)).await
+            {
+                return Some(file);
+            }
+        }
+
+        Some(original)
+    }
+}
+
+impl Representation {
+    async fn open(
+        path: &Path,
+        encoding: Option<&'static str>,
+    ) -> Option<Self> {
+        let meta = fs::metadata(path).await.ok()?;
+        if !meta.is_file() {
+            return None;
+        }
+
+        Some(Self {
+            path: path.to_path_buf(),
+            len: meta.len(),
+            modified: truncate_to_secs(meta.modified().ok()?),
+            encoding,
+        })
+    }
+
+    fn etag(&self) -> String {
+        let mtime = self
+            .modified
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        match self.encoding {
+            Some(encoding) => {
+                format!(r#""{:x}-{:x}-{}""#, self.len, mtime, encoding)
+            }
+            None => format!(r#""{:x}-{:x}""#, self.len, mtime),
+        }
+    }
+}
+
+/// HTTP dates have a resolution of seconds.
+fn truncate_to_secs(time: SystemTime) -> SystemTime {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+fn is_not_modified(
+    headers: &HeaderMap,
+    etag: &str,
+    modified: SystemTime,
+) -> bool {
+    if let Some(if_none_match) = headers.get(header::IF_NONE_MATCH) {
+        let if_none_match = if_none_match.to_str().unwrap_or_default();
+        return if_none_match.split(',').map(str::trim).any(|candidate| {
+            candidate == "*" || candidate.trim_start_matches("W/") == etag
+        });
+    }
+
+    let since = headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|since| since.to_str().ok())
+        .and_then(|since| httpdate::parse_http_date(since).ok());
+    matches!(since, Some(since) if modified <= since)
+}
+
+/// Returns the `Range` header unless an `If-Range` precondition fails.
+fn requested_range<'a>(
+    headers: &'a HeaderMap,
+    etag: &str,
+    last_modified: &str,
+) -> Option<&'a str> {
+    if let Some(if_range) = headers.get(header::IF_RANGE) {
+        if if_range != etag && if_range != last_modified {
+            return None;
+        }
+    }
+
+    headers.get(header::RANGE)?.to_str().ok()
+}
+
+/// Parses a single `bytes=` range into inclusive offsets.
+///
+/// Returns `None` for ranges that should be ignored (unknown units, multiple
+/// ranges, malformed) and `Some(Err(()))` for unsatisfiable ranges.
+fn parse_range(
+    range: &str,
+    len: u64,
+) -> Option<Result<(u64, u64), ()>> {
+    let range = range.trim().strip_prefix("bytes=")?;
+    if range.contains(',') {
+        return None;
+    }
+
+    let (start, end) = {
+        let mut parts = range.splitn(2, '-').map(str::trim);
+        (parts.next()?, parts.next()?)
+    };
+
+    let (start, end) = match (start, end) {
+        ("", "") => return None,
+        ("", suffix) => {
+            let suffix = suffix.parse::<u64>().ok()?;
+            if suffix == 0 {
+                return Some(Err(()));
+            }
+            (len.saturating_sub(suffix), len.saturating_sub(1))
+        }
+        (start, "") => (start.parse().ok()?, len.saturating_sub(1)),
+        (start, end) => {
+            let (start, end) = (start.parse().ok()?, end.parse::<u64>().ok()?);
+            if end < start {
+                return None;
+            }
+            (start, end.min(len.saturating_sub(1)))
+        }
+    };
+
+    if len == 0 || start >= len {
+        return Some(Err(()));
+    }
+
+    Some(Ok((start, end)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn document_root(name: &str) -> Result<PathBuf> {
+        let root = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("css"))?;
+        fs::write(root.join("css/app.css"), "body { color: red; }")?;
+        fs::write(root.join("css/app.css.gz"), "gzipped")?;
+        fs::write(root.join("index.php"), "<?php echo 1;")?;
+        fs::write(root.join(".env"), "SECRET=1")?;
+        Ok(root)
+    }
+
+    fn get(path: &str) -> hyper::http::request::Builder {
+        Request::get(path)
+    }
+
+    async fn body(response: Response<Body>) -> Result<String> {
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        Ok(String::from_utf8(body.to_vec())?)
+    }
+
+    #[tokio::test]
+    async fn serving_files() -> Result<()> {
+        let root = document_root("coyote.test.static.1")?;
+        let files = StaticFiles::new(&root, None, vec!["php".into()], true);
+
+        let response = files
+            .serve(&get("/css/app.css").body(Body::empty())?)
+            .await?
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/css");
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "20");
+        assert!(response.headers().contains_key(header::ETAG));
+        assert!(response.headers().contains_key(header::LAST_MODIFIED));
+        assert_eq!(body(response).await?, "body { color: red; }");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn falling_through_to_php() -> Result<()> {
+        let root = document_root("coyote.test.static.2")?;
+        let files = StaticFiles::new(&root, None, vec!["php".into()], true);
+
+        let paths = ["/missing.css", "/index.php", "/.env", "/css", "/../etc"];
+        for path in &paths {
+            let req = get(path).body(Body::empty())?;
+            assert!(files.serve(&req).await?.is_none(), "{}", path);
+        }
+
+        let req = Request::post("/css/app.css").body(Body::empty())?;
+        assert!(files.serve(&req).await?.is_none());
+
+        let files =
+            StaticFiles::new(&root, Some(vec!["js".into()]), vec![], true);
+        let req = get("/css/app.css").body(Body::empty())?;
+        assert!(files.serve(&req).await?.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn conditional_requests() -> Result<()> {
+        let root = document_root("coyote.test.static.3")?;
+        let files = StaticFiles::new(&root, None, vec![], false);
+
+        let response = files
+            .serve(&get("/css/app.css").body(Body::empty())?)
+            .await?
+            .unwrap();
+        let etag = response.headers()[header::ETAG].clone();
+        let last_modified = response.headers()[header::LAST_MODIFIED].clone();
+
+        let req = get("/css/app.css")
+            .header(header::IF_NONE_MATCH, etag)
+            .body(Body::empty())?;
+        let response = files.serve(&req).await?.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        let req = get("/css/app.css")
+            .header(header::IF_MODIFIED_SINCE, last_modified)
+            .body(Body::empty())?;
+        let response = files.serve(&req).await?.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        let req = get("/css/app.css")
+            .header(header::IF_NONE_MATCH, r#""other""#)
+            .body(Body::empty())?;
+        let response = files.serve(&req).await?.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn range_requests() -> Result<()> {
+        let root = document_root("coyote.test.static.4")?;
+        let files = StaticFiles::new(&root, None, vec![], true);
+
+        let req = get("/css/app.css")
+            .header(header::RANGE, "bytes=0-3")
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .body(Body::empty())?;
+        let response = files.serve(&req).await?.unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes 0-3/20");
+        assert_eq!(body(response).await?, "body");
+
+        let req = get("/css/app.css")
+            .header(header::RANGE, "bytes=-3")
+            .body(Body::empty())?;
+        let response = files.serve(&req).await?.unwrap();
+        assert_eq!(
+            response.headers()[header::CONTENT_RANGE],
+            "bytes 17-19/20"
+        );
+        assert_eq!(body(response).await?, "; }");
+
+        let req = get("/css/app.css")
+            .header(header::RANGE, "bytes=100-")
+            .body(Body::empty())?;
+        let response = files.serve(&req).await?.unwrap();
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn serving_precompressed_files() -> Result<()> {
+        let root = document_root("coyote.test.static.5")?;
+        let files = StaticFiles::new(&root, None, vec![], true);
+
+        let req = get("/css/app.css")
+            .header(header::ACCEPT_ENCODING, "br;q=0, gzip")
+            .body(Body::empty())?;
+        let response = files.serve(&req).await?.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/css");
+        assert_eq!(response.headers()[header::VARY], "accept-encoding");
+        assert_eq!(body(response).await?, "gzipped");
+
+        let req = get("/css/app.css")
+            .header(header::ACCEPT_ENCODING, "br")
+            .body(Body::empty())?;
+        let response = files.serve(&req).await?.unwrap();
+        assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+
+        Ok(())
+    }
+}
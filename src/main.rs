@@ -1,86 +1,513 @@
 #![feature(test)]
 
 use std::{
-    convert::Infallible,
+    collections::{
+        BTreeMap,
+        HashMap,
+    },
+    net::SocketAddr,
+    path::PathBuf,
     sync::Arc,
+    time::Duration,
 };
 
-use anyhow::Result;
-use env_logger::Env;
-use hyper::{
-    header,
-    Body,
-    Method,
-    Request,
-    Response,
-    Server,
+use anyhow::{
+    anyhow,
+    bail,
+    Result,
 };
-use hyper::{
-    service::{
-        make_service_fn,
-        service_fn,
-    },
-    StatusCode,
+use futures::future::{
+    join_all,
+    select_all,
 };
+use tokio::signal::unix::{
+    signal,
+    SignalKind,
+};
+use tracing_subscriber::EnvFilter;
 
 #[macro_use]
 extern crate num_derive;
 extern crate test;
 
+mod admin;
+mod config;
+mod control;
+mod events;
+mod fastcgi;
+mod health;
+mod http;
 mod opt;
+mod quic;
+mod server;
+mod telemetry;
+mod tls;
+mod watch;
+mod websocket;
 mod worker;
 
-async fn handle(
-    req: Request<Body>,
-    pool: Arc<impl worker::pool::Pool>,
-) -> Result<Response<Body>> {
-    match (req.method(), req.uri().path()) {
-        (&Method::GET, path) if path.starts_with("/hello/") => {
-            let name = path.trim_start_matches("/hello/");
-            let response = pool
-                .exec(format!(r#"{{"name":"{}"}}"#, name).as_str().into())
-                .await?;
-            let mut response = Response::new(Body::from(response.0));
-            response
-                .headers_mut()
-                .insert(header::CONTENT_TYPE, "application/json".parse()?);
-            Ok(response)
+use config::{
+    Config,
+    LogFormat,
+};
+
+async fn pools(
+    config: &Config,
+    hub: &Arc<websocket::Hub>,
+) -> Result<BTreeMap<String, Arc<worker::pool::Static>>> {
+    let mut pools = BTreeMap::new();
+    for (name, pool) in &config.pools {
+        log::info!("Starting pool {} with {} workers", name, pool.size);
+        let limits = worker::pool::QueueLimits {
+            max_len:  pool.max_queue_len,
+            max_wait: pool.max_queue_wait.map(Duration::from_millis),
+        };
+        let backoff = match &pool.backoff {
+            Some(backoff) => worker::pool::SpawnBackoff {
+                initial:      Duration::from_millis(backoff.initial),
+                max:          Duration::from_millis(backoff.max),
+                max_failures: backoff.max_failures,
+            },
+            None => worker::pool::SpawnBackoff::default(),
+        };
+        let static_ = async {
+            worker::pool::Static::new(
+                &pool.socket(name),
+                &pool.script,
+                pool.size,
+                isolation(name, pool)?,
+                backoff,
+            )
+            .await
+        };
+        let mut static_ = static_
+            .await
+            .map_err(|err| anyhow!("could not start pool {}: {}", name, err))?
+        .with_queue_limits(limits)
+        .with_publisher(hub.publisher())
+        .with_grace(Duration::from_millis(pool.grace_period));
+        if let Some(interval) = pool.ping_interval {
+            static_ = static_.with_ping(
+                Duration::from_millis(interval),
+                Duration::from_millis(pool.ping_timeout),
+            );
         }
-        _ => {
-            let mut not_found = Response::default();
-            *not_found.status_mut() = StatusCode::NOT_FOUND;
-            Ok(not_found)
+
+        let static_ = Arc::new(static_);
+
+        if let Some(watch) = &pool.watch {
+            log::info!("Restarting {} when {:?} change", name, watch.paths);
+            watch::Watcher::new(
+                name.clone(),
+                static_.clone(),
+                watch.paths.iter().map(PathBuf::from).collect(),
+                &watch.patterns,
+                Duration::from_millis(watch.debounce),
+            )?
+            .spawn()?;
         }
+
+        pools.insert(name.clone(), static_);
+    }
+
+    Ok(pools)
+}
+
+fn isolation(
+    name: &str,
+    pool: &config::PoolConfig,
+) -> Result<worker::Isolation> {
+    let limits = pool.limits.as_ref().map(|limits| worker::Limits {
+        address_space: limits.address_space,
+        cpu_time:      limits.cpu_time,
+        open_files:    limits.open_files,
+        core_size:     limits.core_size,
+    });
+    let cgroup = match (&pool.cgroup, pool.cgroup_path(name)) {
+        (Some(cgroup), Some(path)) => Some(Arc::new(worker::Cgroup::create(
+            path.as_ref(),
+            cgroup.memory_max,
+            cgroup.cpu_max,
+        )?)),
+        _ => None,
+    };
+
+    Ok(worker::Isolation {
+        credentials: worker::Credentials::lookup(
+            pool.user.as_deref(),
+            pool.group.as_deref(),
+        )?,
+        limits: limits.unwrap_or_default(),
+        cgroup,
+    })
+}
+
+fn upstreams(
+    config: &Config,
+    pools: &BTreeMap<String, Arc<worker::pool::Static>>,
+    hub: &Arc<websocket::Hub>,
+) -> HashMap<String, Arc<http::Upstream>> {
+    config
+        .pools
+        .iter()
+        .map(|(name, pool)| {
+            let upstream = http::Upstream {
+                name:          name.clone(),
+                pool:          pools[name].clone(),
+                retry_after:   pool.retry_after,
+                hub:           hub.clone(),
+                max_body_size: pool.max_body_size,
+                upload_dir:    pool.upload_dir.as_ref().map(PathBuf::from),
+            };
+            (name.clone(), Arc::new(upstream))
+        })
+        .collect()
+}
+
+fn router(
+    config: &Config,
+    upstreams: &HashMap<String, Arc<http::Upstream>>,
+) -> http::Router {
+    let routes = config
+        .routes
+        .iter()
+        .map(|route| http::Route {
+            host:          route.host.clone(),
+            path:          route.path.clone(),
+            upstream:      upstreams[&route.pool].clone(),
+            max_body_size: route.max_body_size,
+        })
+        .collect();
+
+    http::Router::new(routes)
+}
+
+/// Router sending every request to `upstream`.
+fn pool_router(upstream: &Arc<http::Upstream>) -> http::Router {
+    http::Router::new(vec![http::Route {
+        host:          None,
+        path:          None,
+        upstream:      upstream.clone(),
+        max_body_size: None,
+    }])
+}
+
+/// Health endpoints covering every pool, with periodic checks if
+/// configured.
+fn health(
+    config: &config::HealthConfig,
+    pools: &BTreeMap<String, Arc<worker::pool::Static>>,
+    pool_configs: &BTreeMap<String, config::PoolConfig>,
+    upstreams: &HashMap<String, Arc<http::Upstream>>,
+) -> Arc<health::Health> {
+    let mut health = health::Health::new(
+        config.health_path.clone(),
+        config.ready_path.clone(),
+        config.min_workers,
+    );
+    for (name, pool) in pools {
+        health = health.with_pool(
+            upstreams[name].clone(),
+            pool.clone(),
+            config.max_queue_len.or(pool_configs[name].max_queue_len),
+        );
+    }
+
+    let health = Arc::new(health);
+    if let Some(check) = &config.check {
+        health.clone().check_periodically(health::HealthCheck {
+            path:     check.path.clone(),
+            interval: Duration::from_secs(check.interval),
+            timeout:  Duration::from_secs(check.timeout),
+        });
+    }
+    health
+}
+
+fn cert_resolver(
+    certificates: &[config::CertificateConfig],
+    reload_interval: u64,
+) -> Result<Arc<tls::CertResolver>> {
+    let resolver = tls::CertResolver::new(
+        certificates
+            .iter()
+            .map(|cert| tls::CertificateFiles {
+                cert:  cert.cert.clone(),
+                key:   cert.key.clone(),
+                hosts: cert.hosts.clone(),
+            })
+            .collect(),
+        tls::provider(),
+    )?;
+    resolver
+        .clone()
+        .watch(Duration::from_secs(reload_interval));
+    Ok(resolver)
+}
+
+/// Installs the `tracing` subscriber, `log` records are forwarded to it.
+fn init_logging(format: LogFormat) {
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new("info"));
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+    match format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .init(),
     }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let opts = opt::Opt::args();
-    env_logger::Builder::from_env(Env::default().default_filter_or("info"))
-        .init();
-
-    let pool = Arc::new(
-        worker::pool::Static::new(
-            &opts.unix_socket,
-            &opts.worker_script,
-            opts.worker_count,
-        )
-        .await?,
-    );
-    let addr = opts.http_listen.parse()?;
-
-    let make_svc = make_service_fn(move |_| {
-        let pool = pool.clone();
-        async move {
-            Ok::<_, Infallible>(service_fn(move |req| {
-                handle(req, pool.clone())
-            }))
-        }
+    match opt::Command::args() {
+        opt::Command::Serve(opts) => serve(opts).await,
+        command => control::run(command).await,
+    }
+}
+
+async fn serve(opts: opt::Opt) -> Result<()> {
+    let config = Config::load(&opts)?;
+    init_logging(config.log.format);
+
+    let hub = Arc::new(websocket::Hub::new());
+    let pools = pools(&config, &hub).await?;
+    let upstreams = upstreams(&config, &pools, &hub);
+    let health = config.health.as_ref().map(|health_config| {
+        let health = health(health_config, &pools, &config.pools, &upstreams);
+        (health, health_config.listen.as_ref())
+    });
+    let handler = Arc::new(http::Handler {
+        router:       router(&config, &upstreams),
+        static_files: config.static_files.as_ref().map(|static_files| {
+            http::StaticFiles::new(
+                &static_files.root,
+                static_files.allow.clone(),
+                static_files.deny.clone(),
+                static_files.precompressed,
+            )
+        }),
+        compression:  match &config.compression {
+            Some(compression) => Some(http::Compression::new(
+                compression
+                    .encodings
+                    .iter()
+                    .map(|encoding| http::Encoding::from_name(encoding))
+                    .collect::<Result<_>>()?,
+                compression.min_size,
+                compression.mime_types.clone(),
+                compression.level,
+            )),
+            None => None,
+        },
+        websocket:    config.websocket.as_ref().map(|websocket| {
+            Arc::new(websocket::WebSocket::new(
+                websocket.path.clone(),
+                websocket.pool.as_ref().map(|pool| upstreams[pool].clone()),
+                hub.clone(),
+                websocket.max_message_size,
+            ))
+        }),
+        events:       config.events.as_ref().map(|events| {
+            Arc::new(events::Events::new(
+                events.path.clone(),
+                events.pool.as_ref().map(|pool| upstreams[pool].clone()),
+                hub.clone(),
+                Duration::from_secs(events.keep_alive),
+                Duration::from_secs(events.poll_timeout),
+            ))
+        }),
+        access_log:   match &config.access_log {
+            Some(access_log) => {
+                let access_log = Arc::new(http::AccessLog::new(
+                    access_log.path().map(PathBuf::from),
+                    access_log.format.parse()?,
+                )?);
+                access_log.clone().reopen_on_signal()?;
+                Some(access_log)
+            }
+            None => None,
+        },
+        tracer:       match &config.telemetry {
+            Some(telemetry) => Some(Arc::new(telemetry::Tracer::new(
+                telemetry.endpoint.parse()?,
+                telemetry.service_name.clone(),
+                Duration::from_millis(telemetry.flush_interval),
+            )?)),
+            None => None,
+        },
+        health:       match &health {
+            Some((health, None)) => Some(health.clone()),
+            _ => None,
+        },
     });
+    let http2 = server::Http2 {
+        enabled:                config.http2.enabled,
+        h2c:                    config.http2.h2c,
+        max_concurrent_streams: config.http2.max_concurrent_streams,
+    };
+
+    let mut servers = vec![];
+    for listener in &config.listeners {
+        // Listeners bound to a pool send everything there.
+        let handler = match &listener.pool {
+            Some(pool) => Arc::new(http::Handler {
+                router: pool_router(&upstreams[pool]),
+                ..(*handler).clone()
+            }),
+            None => handler.clone(),
+        };
+
+        let server = match (listener.listen.parse()?, &listener.tls) {
+            (server::Addr::Tcp(addr), None) => tokio::spawn(
+                server::serve_http(server::bind_tcp(addr)?, handler, http2),
+            ),
+            (server::Addr::Tcp(addr), Some(tls)) => {
+                let resolver =
+                    cert_resolver(&tls.certificates, tls.reload_interval)?;
+                tokio::spawn(server::serve_tls(
+                    server::bind_tcp(addr)?,
+                    handler,
+                    tls::server_config(resolver, http2.alpn())?,
+                    http2,
+                    None,
+                ))
+            }
+            (server::Addr::Unix(path), None) => tokio::spawn(
+                server::serve_unix(server::bind_unix(&path)?, handler, http2),
+            ),
+            (server::Addr::Unix(_), Some(_)) => {
+                bail!("TLS is not supported on Unix sockets")
+            }
+        };
+        servers.push(server);
+    }
+    if let Some((health, Some(listen))) = health {
+        // Only the health endpoints are served here.
+        let handler = Arc::new(http::Handler {
+            router:       http::Router::new(vec![]),
+            static_files: None,
+            compression:  None,
+            websocket:    None,
+            events:       None,
+            access_log:   None,
+            tracer:       None,
+            health:       Some(health),
+        });
+        servers.push(match listen.parse()? {
+            server::Addr::Tcp(addr) => tokio::spawn(server::serve_http(
+                server::bind_tcp(addr)?,
+                handler,
+                http2,
+            )),
+            server::Addr::Unix(path) => tokio::spawn(server::serve_unix(
+                server::bind_unix(&path)?,
+                handler,
+                http2,
+            )),
+        });
+    }
+    if let Some(fastcgi) = &config.fastcgi {
+        let router = Arc::new(match &fastcgi.pool {
+            Some(pool) => pool_router(&upstreams[pool]),
+            None => handler.router.clone(),
+        });
+        servers.push(match fastcgi.listen.parse()? {
+            server::Addr::Tcp(addr) => tokio::spawn(fastcgi::serve_tcp(
+                server::bind_tcp(addr)?,
+                router,
+            )),
+            server::Addr::Unix(path) => tokio::spawn(fastcgi::serve_unix(
+                server::bind_unix(&path)?,
+                router,
+            )),
+        });
+    }
+
+    let mut admin = None;
+    if let Some(admin_config) = &config.admin {
+        let api = Arc::new(admin::Admin::new(
+            pools.clone(),
+            opts.clone(),
+            &config,
+        )?);
+        servers.push(match admin_config.listen.parse()? {
+            server::Addr::Tcp(addr) => tokio::spawn(admin::serve_tcp(
+                server::bind_tcp(addr)?,
+                api.clone(),
+            )),
+            server::Addr::Unix(path) => tokio::spawn(admin::serve_unix(
+                server::bind_unix(&path)?,
+                api.clone(),
+            )),
+        });
+        admin = Some(api);
+    }
+
+    let http3 = &config.http3;
+    if let Some(config) = &config.tls {
+        let resolver =
+            cert_resolver(&config.certificates, config.reload_interval)?;
+        let addr: SocketAddr = config.listen.parse()?;
+
+        let mut alt_svc = None;
+        if let Some(http3) = &http3 {
+            let udp_addr = match &http3.listen {
+                Some(listen) => listen.parse()?,
+                None => addr,
+            };
+            let tls = tls::server_config(
+                resolver.clone(),
+                vec![quic::ALPN.to_vec()],
+            )?;
+            let endpoint = quic::endpoint(udp_addr, tls)?;
+            alt_svc = Some(quic::alt_svc(udp_addr.port(), http3.max_age));
+            servers.push(tokio::spawn(quic::serve_h3(
+                endpoint,
+                handler.clone(),
+            )));
+        }
+
+        let tls = tls::server_config(resolver, http2.alpn())?;
+        let listener = server::bind_tcp(addr)?;
+        servers.push(tokio::spawn(server::serve_tls(
+            listener,
+            handler.clone(),
+            tls,
+            http2,
+            alt_svc,
+        )));
+
+        if let Some(redirect) = &config.redirect_listen {
+            servers.push(tokio::spawn(server::serve_redirect(
+                redirect.parse()?,
+                addr.port(),
+            )));
+        }
+    }
+
+    // Servers run until they fail, stop at the first one or when asked to.
+    let stopped = async {
+        match &admin {
+            Some(admin) => admin.stopped().await,
+            None => futures::future::pending().await,
+        }
+    };
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let result = tokio::select! {
+        (result, ..) = select_all(servers) => match result {
+            Ok(result) => result,
+            Err(err) => Err(err.into()),
+        },
+        _ = stopped => Ok(()),
+        _ = terminate.recv() => Ok(()),
+        _ = interrupt.recv() => Ok(()),
+    };
 
-    log::info!("Serving coyote on: {}", &addr);
-    let server = Server::bind(&addr).serve(make_svc);
-    server.await?;
-    Ok(())
+    // Lets workers clean up before exiting.
+    join_all(pools.values().map(|pool| pool.stop())).await;
+    result
 }
@@ -1,11 +1,72 @@
+use std::ffi::OsString;
+
 use structopt::StructOpt;
 
+use crate::config::LogFormat;
+
 #[derive(StructOpt, Debug)]
 #[structopt(name = "Coyote")]
+pub enum Command {
+    /// Serves PHP applications, the default without a subcommand.
+    Serve(Opt),
+    /// Reloads the configuration and replaces every worker.
+    Reload(Control),
+    /// Stops the server.
+    Stop(Control),
+    /// Prints uptime and pool stats.
+    Status(Control),
+    /// Prints every worker.
+    Workers(Control),
+}
+
+const SUBCOMMANDS: &[&str] = &[
+    "serve", "reload", "stop", "status", "workers", "help", "-h", "--help",
+    "-V", "--version",
+];
+
+impl Command {
+    pub fn args() -> Self {
+        Self::parse(std::env::args_os())
+    }
+
+    /// Parses `args`, serving if no subcommand is given.
+    fn parse(args: impl IntoIterator<Item = OsString>) -> Self {
+        let mut args = args.into_iter().collect::<Vec<_>>();
+        let subcommand = args.get(1).and_then(|arg| arg.to_str());
+        if !subcommand.is_some_and(|arg| SUBCOMMANDS.contains(&arg)) {
+            args.insert(1.min(args.len()), "serve".into());
+        }
+        Self::from_iter(args)
+    }
+}
+
+/// Reaches the admin API of a running server.
+#[derive(StructOpt, Debug)]
+pub struct Control {
+    /// Admin API address, `unix:<path>` for Unix domain sockets.
+    #[structopt(short, long, default_value = "unix:/tmp/coyote.admin.sock")]
+    pub admin: String,
+
+    /// Config file to read the admin address and token from.
+    #[structopt(short, long)]
+    pub config: Option<String>,
+
+    /// Admin API token.
+    #[structopt(long, env = "COYOTE_ADMIN_TOKEN", hide_env_values = true)]
+    pub token: Option<String>,
+}
+
+#[derive(StructOpt, Debug, Clone)]
 pub struct Opt {
-    /// Http handler's serving address.
+    /// Http handler's serving addresses, `unix:<path>` listens on a Unix
+    /// domain socket, may be repeated.
     #[structopt(short = "s", long, default_value = "127.0.0.1:3000")]
-    pub http_listen: String,
+    pub http_listen: Vec<String>,
+
+    /// Config file describing worker pools and routes, flags below are
+    /// ignored when given.
+    #[structopt(short, long)]
+    pub config: Option<String>,
 
     /// Unix socket to use.
     #[structopt(long, default_value = "/tmp/coyote.sock")]
@@ -18,10 +79,76 @@ pub struct Opt {
     /// PHP Worker count.
     #[structopt(long, default_value = "60")]
     pub worker_count: usize,
+
+    /// Maximum number of requests waiting for a free worker.
+    #[structopt(long)]
+    pub max_queue_len: Option<usize>,
+
+    /// Maximum time in milliseconds a request waits for a free worker.
+    #[structopt(long)]
+    pub max_queue_wait: Option<u64>,
+
+    /// Document root to serve static files from.
+    #[structopt(long)]
+    pub document_root: Option<String>,
+
+    /// FastCGI serving address, `unix:<path>` listens on a Unix domain
+    /// socket.
+    #[structopt(long)]
+    pub fastcgi_listen: Option<String>,
+
+    /// Log output, `text` or `json`.
+    #[structopt(long, default_value = "text")]
+    pub log_format: LogFormat,
+
+    /// Admin API address, `unix:<path>` listens on a Unix domain socket.
+    #[structopt(long, default_value = "unix:/tmp/coyote.admin.sock")]
+    pub admin_listen: String,
+
+    /// Access log file in Combined Log Format, `-` for stdout.
+    #[structopt(long)]
+    pub access_log: Option<String>,
+
+    /// Compress responses with default settings.
+    #[structopt(long)]
+    pub compress: bool,
+
+    /// `Retry-After` seconds sent with 503 responses when overloaded.
+    #[structopt(long, default_value = "1")]
+    pub retry_after: u64,
+
+    /// Directories to watch for changed PHP files, workers are restarted
+    /// when one changes. Meant for development, may be repeated.
+    #[structopt(long)]
+    pub watch: Vec<String>,
 }
 
-impl Opt {
-    pub fn args() -> Self {
-        Opt::from_args()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Command {
+        Command::parse(args.iter().map(OsString::from))
+    }
+
+    #[test]
+    fn parsing_subcommands() {
+        match parse(&["coyote", "--worker-count", "4"]) {
+            Command::Serve(opt) => assert_eq!(opt.worker_count, 4),
+            command => panic!("unexpected command: {:?}", command),
+        }
+        match parse(&["coyote", "serve", "-c", "coyote.toml"]) {
+            Command::Serve(opt) => {
+                assert_eq!(opt.config.as_deref(), Some("coyote.toml"))
+            }
+            command => panic!("unexpected command: {:?}", command),
+        }
+        match parse(&["coyote", "workers", "--admin", "127.0.0.1:9001"]) {
+            Command::Workers(control) => {
+                assert_eq!(control.admin, "127.0.0.1:9001")
+            }
+            command => panic!("unexpected command: {:?}", command),
+        }
+        assert!(matches!(parse(&["coyote", "stop"]), Command::Stop(_)));
     }
 }
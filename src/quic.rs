@@ -0,0 +1,308 @@
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::Result;
+use bytes::{
+    Buf,
+    Bytes,
+};
+use h3::server::RequestResolver;
+use hyper::body::HttpBody;
+use hyper::{
+    header,
+    Body,
+    Request,
+    Version,
+};
+use quinn::crypto::rustls::QuicServerConfig;
+use quinn::Endpoint;
+use rustls::ServerConfig;
+
+use crate::http::{
+    self,
+    Handler,
+};
+
+/// ALPN protocol of HTTP/3.
+pub const ALPN: &[u8] = b"h3";
+
+type Resolver = RequestResolver<h3_quinn::Connection, Bytes>;
+
+/// Binds a QUIC endpoint on `addr` terminating TLS with `tls`, which must
+/// advertise [`ALPN`].
+pub fn endpoint(
+    addr: SocketAddr,
+    tls: Arc<ServerConfig>,
+) -> Result<Endpoint> {
+    let crypto = QuicServerConfig::try_from(tls)?;
+    let config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    Ok(Endpoint::server(config, addr)?)
+}
+
+/// `Alt-Svc` header value advertising HTTP/3 on `port` for `max_age`
+/// seconds.
+pub fn alt_svc(
+    port: u16,
+    max_age: u64,
+) -> header::HeaderValue {
+    header::HeaderValue::from_str(&format!(
+        "h3=\":{}\"; ma={}",
+        port, max_age
+    ))
+    .expect("alt-svc value is always valid")
+}
+
+/// Serves HTTP/3 on `endpoint`.
+pub async fn serve_h3(
+    endpoint: Endpoint,
+    handler: Arc<Handler>,
+) -> Result<()> {
+    log::info!("Serving coyote over HTTP/3 on: {}", endpoint.local_addr()?);
+
+    while let Some(incoming) = endpoint.accept().await {
+        let remote = incoming.remote_address();
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            let conn = match incoming.await {
+                Ok(conn) => conn,
+                Err(err) => {
+                    log::debug!(
+                        "QUIC handshake with {} failed: {}",
+                        remote,
+                        err
+                    );
+                    return;
+                }
+            };
+
+            if let Err(err) = serve_connection(conn, remote, handler).await {
+                log::debug!(
+                    "HTTP/3 connection with {} failed: {}",
+                    remote,
+                    err
+                );
+            }
+        });
+    }
+
+    Ok(())
+}
+
+async fn serve_connection(
+    conn: quinn::Connection,
+    remote: SocketAddr,
+    handler: Arc<Handler>,
+) -> Result<()> {
+    let mut conn = h3::server::Connection::<_, Bytes>::new(
+        h3_quinn::Connection::new(conn),
+    )
+    .await?;
+
+    while let Some(resolver) = conn.accept().await? {
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            if let Err(err) = serve_request(resolver, remote, handler).await {
+                log::debug!("HTTP/3 request failed: {}", err);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+async fn serve_request(
+    resolver: Resolver,
+    remote: SocketAddr,
+    handler: Arc<Handler>,
+) -> Result<()> {
+    let (req, stream) = resolver.resolve_request().await?;
+    let (mut stream, mut recv) = stream.split();
+
+    // The body is streamed so route body limits stop reading it early.
+    let (mut sender, body) = Body::channel();
+    let receiving = tokio::spawn(async move {
+        loop {
+            let data = match recv.recv_data().await {
+                Ok(Some(mut chunk)) => chunk.copy_to_bytes(chunk.remaining()),
+                Ok(None) => break,
+                Err(err) => {
+                    log::debug!("could not receive HTTP/3 body: {}", err);
+                    // Fails reading the body rather than ending it early.
+                    sender.abort();
+                    break;
+                }
+            };
+            if sender.send_data(data).await.is_err() {
+                // The body was dropped without being read to the end.
+                recv.stop_sending(h3::error::Code::H3_NO_ERROR);
+                break;
+            }
+        }
+    });
+
+    // NOTE: h3 speaks http 1.x while hyper is on 0.2, so requests and
+    // responses are rebuilt at this boundary.
+    let mut builder = Request::builder()
+        .method(req.method().as_str())
+        .uri(req.uri().to_string())
+        .version(Version::HTTP_3);
+    for (name, value) in req.headers() {
+        builder = builder.header(name.as_str(), value.as_bytes());
+    }
+    let mut req = builder.body(body)?;
+    req.extensions_mut().insert(http::RemoteAddr(remote));
+    let response = http::handle(req, handler).await?;
+
+    let (parts, mut body) = response.into_parts();
+    let mut builder = http1::Response::builder().status(parts.status.as_u16());
+    for (name, value) in &parts.headers {
+        // Connection specific headers are not allowed in HTTP/3.
+        if is_connection_specific(name) {
+            continue;
+        }
+        builder = builder.header(name.as_str(), value.as_bytes());
+    }
+    stream.send_response(builder.body(())?).await?;
+
+    while let Some(chunk) = body.data().await {
+        stream.send_data(chunk?).await?;
+    }
+    stream.finish().await?;
+    receiving.abort();
+
+    Ok(())
+}
+
+fn is_connection_specific(name: &header::HeaderName) -> bool {
+    name == header::CONNECTION ||
+        name == header::TRANSFER_ENCODING ||
+        name == header::UPGRADE ||
+        name == "keep-alive" ||
+        name == "proxy-connection"
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use bytes::BytesMut;
+    use quinn::crypto::rustls::QuicClientConfig;
+    use rustls::{
+        ClientConfig,
+        RootCertStore,
+    };
+
+    use super::*;
+    use crate::http::{
+        Route,
+        Router,
+        StaticFiles,
+        Upstream,
+    };
+    use crate::tls::{
+        self,
+        tests::generate,
+        CertResolver,
+    };
+    use crate::websocket::Hub;
+    use crate::worker::pool::tests::{
+        noop,
+        Stub,
+    };
+
+    #[test]
+    fn advertising_http3() {
+        assert_eq!(alt_svc(8443, 3600), r#"h3=":8443"; ma=3600"#);
+    }
+
+    #[tokio::test]
+    async fn serving_http3() -> Result<()> {
+        let root = std::env::temp_dir().join("coyote.test.quic.1");
+        fs::create_dir_all(&root)?;
+        fs::write(root.join("hello.txt"), "hello over quic")?;
+        let upstream = Arc::new(Upstream {
+            name:          "app".into(),
+            pool:          Arc::new(Stub(noop)),
+            retry_after:   1,
+            hub:           Arc::new(Hub::new()),
+            max_body_size: Some(4),
+            upload_dir:    None,
+        });
+        let handler = Arc::new(Handler {
+            router:       Router::new(vec![Route {
+                host: None,
+                path: Some("/app/".into()),
+                upstream,
+                max_body_size: None,
+            }]),
+            static_files: Some(StaticFiles::new(&root, None, vec![], false)),
+            compression:  None,
+            websocket:    None,
+            events:       None,
+            access_log:   None,
+            tracer:       None,
+            health:       None,
+        });
+
+        let (files, pem) = generate("coyote.test.quic.2", &["localhost"])?;
+        let resolver = CertResolver::new(vec![files], tls::provider())?;
+        let config = tls::server_config(resolver, vec![ALPN.to_vec()])?;
+        let endpoint = endpoint("127.0.0.1:0".parse()?, config)?;
+        let addr = endpoint.local_addr()?;
+        tokio::spawn(serve_h3(endpoint, handler));
+
+        let mut roots = RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut pem.as_bytes()) {
+            roots.add(cert?)?;
+        }
+        let mut client = ClientConfig::builder_with_provider(tls::provider())
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        client.alpn_protocols = vec![ALPN.to_vec()];
+        let mut endpoint = Endpoint::client("127.0.0.1:0".parse()?)?;
+        endpoint.set_default_client_config(quinn::ClientConfig::new(
+            Arc::new(QuicClientConfig::try_from(client)?),
+        ));
+
+        let conn = endpoint.connect(addr, "localhost")?.await?;
+        let (mut driver, mut sender) =
+            h3::client::new(h3_quinn::Connection::new(conn)).await?;
+        tokio::spawn(async move { driver.wait_idle().await });
+
+        let get = |path: &str| {
+            http1::Request::get(format!("https://localhost{}", path)).body(())
+        };
+
+        let mut stream = sender.send_request(get("/hello.txt")?).await?;
+        stream.finish().await?;
+        let response = stream.recv_response().await?;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.version(), http1::Version::HTTP_3);
+        let mut body = BytesMut::new();
+        while let Some(mut chunk) = stream.recv_data().await? {
+            body.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+        }
+        assert_eq!(&body[..], b"hello over quic");
+
+        let mut stream = sender.send_request(get("/missing")?).await?;
+        stream.finish().await?;
+        assert_eq!(stream.recv_response().await?.status(), 404);
+
+        let post = |path: &str| {
+            http1::Request::post(format!("https://localhost{}", path)).body(())
+        };
+        let mut stream = sender.send_request(post("/app/")?).await?;
+        stream.send_data(Bytes::from_static(b"ok")).await?;
+        stream.finish().await?;
+        assert_eq!(stream.recv_response().await?.status(), 200);
+
+        // Rejected once the limit is crossed, without reading the rest.
+        let mut stream = sender.send_request(post("/app/")?).await?;
+        stream.send_data(Bytes::from_static(b"hello")).await?;
+        assert_eq!(stream.recv_response().await?.status(), 413);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,522 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::{
+    anyhow,
+    Error,
+    Result,
+};
+use hyper::server::conn::Http;
+use hyper::service::{
+    make_service_fn,
+    service_fn,
+};
+use hyper::{
+    header::{
+        self,
+        HeaderValue,
+    },
+    Body,
+    Request,
+    Response,
+    Server,
+    StatusCode,
+};
+use rustls::ServerConfig;
+use socket2::{
+    Domain,
+    Socket,
+    Type,
+};
+use tokio::io::{
+    AsyncRead,
+    AsyncWrite,
+};
+use tokio::net::{
+    TcpListener,
+    UnixListener,
+};
+use tokio_rustls::TlsAcceptor;
+
+use crate::http::{
+    self,
+    Handler,
+};
+
+/// Listening address, `unix:<path>` for Unix domain sockets.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Addr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for Addr {
+    type Err = Error;
+
+    fn from_str(addr: &str) -> Result<Self> {
+        match addr.strip_prefix("unix:") {
+            Some(path) if !path.is_empty() => Ok(Addr::Unix(path.into())),
+            Some(_) => Err(anyhow!("missing Unix socket path: {}", addr)),
+            None => addr.parse().map(Addr::Tcp).map_err(|err| {
+                anyhow!("invalid listen address {}: {}", addr, err)
+            }),
+        }
+    }
+}
+
+/// Binds a TCP listener, IPv6 addresses don't accept IPv4 so `[::]` and
+/// `0.0.0.0` can be listened on side by side.
+pub fn bind_tcp(addr: SocketAddr) -> Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+/// Binds a Unix domain socket listener, replacing a stale socket file.
+pub fn bind_unix(path: &Path) -> Result<UnixListener> {
+    let _ = std::fs::remove_file(path);
+    Ok(UnixListener::bind(path)?)
+}
+
+/// HTTP/2 settings shared by listeners.
+#[derive(Debug, Clone, Copy)]
+pub struct Http2 {
+    /// Negotiate HTTP/2 over TLS via ALPN.
+    pub enabled:                bool,
+    /// Accept cleartext HTTP/2 with prior knowledge on plain listeners.
+    pub h2c:                    bool,
+    /// Maximum concurrent streams per connection, hyper's default if `None`.
+    pub max_concurrent_streams: Option<u32>,
+}
+
+impl Http2 {
+    /// ALPN protocols to advertise in order of preference.
+    pub fn alpn(&self) -> Vec<Vec<u8>> {
+        let mut protocols = vec![];
+        if self.enabled {
+            protocols.push(b"h2".to_vec());
+        }
+        protocols.push(b"http/1.1".to_vec());
+        protocols
+    }
+
+    fn http(
+        &self,
+        h2: bool,
+    ) -> Http {
+        let mut http = Http::new();
+        http.http2_max_concurrent_streams(self.max_concurrent_streams);
+        if h2 {
+            http.http2_only(true);
+        } else if !self.h2c {
+            http.http1_only(true);
+        }
+        http
+    }
+}
+
+/// Serves plain HTTP on `listener`.
+pub async fn serve_http(
+    listener: TcpListener,
+    handler: Arc<Handler>,
+    http2: Http2,
+) -> Result<()> {
+    log::info!("Serving coyote on: {}", listener.local_addr()?);
+
+    loop {
+        let (stream, remote) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                log::error!("could not accept new connection: {}", err);
+                continue;
+            }
+        };
+
+        tokio::spawn(serve_connection(
+            stream,
+            Some(remote),
+            handler.clone(),
+            http2.http(false),
+            None,
+        ));
+    }
+}
+
+/// Serves plain HTTP on a Unix domain socket `listener`.
+pub async fn serve_unix(
+    listener: UnixListener,
+    handler: Arc<Handler>,
+    http2: Http2,
+) -> Result<()> {
+    log::info!("Serving coyote on: {:?}", listener.local_addr()?);
+
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                log::error!("could not accept new connection: {}", err);
+                continue;
+            }
+        };
+
+        tokio::spawn(serve_connection(
+            stream,
+            None,
+            handler.clone(),
+            http2.http(false),
+            None,
+        ));
+    }
+}
+
+/// Serves HTTPS on `listener`, terminating TLS with `tls` and adding
+/// `alt_svc` to responses if given.
+pub async fn serve_tls(
+    listener: TcpListener,
+    handler: Arc<Handler>,
+    tls: Arc<ServerConfig>,
+    http2: Http2,
+    alt_svc: Option<HeaderValue>,
+) -> Result<()> {
+    let acceptor = TlsAcceptor::from(tls);
+    log::info!("Serving coyote over TLS on: {}", listener.local_addr()?);
+
+    loop {
+        let (stream, remote) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                log::error!("could not accept new connection: {}", err);
+                continue;
+            }
+        };
+
+        let acceptor = acceptor.clone();
+        let handler = handler.clone();
+        let alt_svc = alt_svc.clone();
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(err) => {
+                    log::debug!(
+                        "TLS handshake with {} failed: {}",
+                        remote,
+                        err
+                    );
+                    return;
+                }
+            };
+
+            let h2 = stream.get_ref().1.alpn_protocol() == Some(b"h2");
+            let http = http2.http(h2);
+            serve_connection(stream, Some(remote), handler, http, alt_svc)
+                .await;
+        });
+    }
+}
+
+/// Serves `stream`, `remote` is the peer address of TCP connections.
+async fn serve_connection(
+    stream: impl AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    remote: Option<SocketAddr>,
+    handler: Arc<Handler>,
+    http: Http,
+    alt_svc: Option<HeaderValue>,
+) {
+    let service = service_fn(move |mut req: Request<Body>| {
+        if let Some(remote) = remote {
+            req.extensions_mut().insert(http::RemoteAddr(remote));
+        }
+        let alt_svc = alt_svc.clone();
+        let response = http::handle(req, handler.clone());
+        async move {
+            let mut response = response.await?;
+            if let Some(alt_svc) = alt_svc {
+                response.headers_mut().insert(header::ALT_SVC, alt_svc);
+            }
+            Ok::<_, anyhow::Error>(response)
+        }
+    });
+    let conn = http.serve_connection(stream, service).with_upgrades();
+    if let Err(err) = conn.await {
+        log::debug!("connection with {:?} failed: {}", remote, err);
+    }
+}
+
+/// Redirects every request on `addr` to HTTPS on `https_port`.
+pub async fn serve_redirect(
+    addr: SocketAddr,
+    https_port: u16,
+) -> Result<()> {
+    let make_svc = make_service_fn(move |_| async move {
+        Ok::<_, Infallible>(service_fn(move |req| async move {
+            Ok::<_, Infallible>(redirect(&req, https_port))
+        }))
+    });
+
+    log::info!("Redirecting to HTTPS on: {}", &addr);
+    Server::bind(&addr).serve(make_svc).await?;
+    Ok(())
+}
+
+fn redirect(
+    req: &Request<Body>,
+    https_port: u16,
+) -> Response<Body> {
+    let host = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .map(http::strip_port);
+
+    let mut response = Response::default();
+    let host = match host {
+        Some(host) => host,
+        None => {
+            *response.status_mut() = StatusCode::BAD_REQUEST;
+            return response;
+        }
+    };
+
+    let path = req
+        .uri()
+        .path_and_query()
+        .map_or("/", |path_and_query| path_and_query.as_str());
+    let location = match https_port {
+        443 => format!("https://{}{}", host, path),
+        port => format!("https://{}:{}{}", host, port, path),
+    };
+
+    match location.parse() {
+        Ok(location) => {
+            *response.status_mut() = StatusCode::PERMANENT_REDIRECT;
+            response.headers_mut().insert(header::LOCATION, location);
+        }
+        Err(_) => *response.status_mut() = StatusCode::BAD_REQUEST,
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use hyper::Version;
+    use rustls::pki_types::ServerName;
+    use rustls::{
+        ClientConfig,
+        RootCertStore,
+    };
+    use tokio::net::{
+        TcpStream,
+        UnixStream,
+    };
+    use tokio_rustls::TlsConnector;
+
+    use super::*;
+    use crate::http::Router;
+    use crate::tls::{
+        self,
+        tests::generate,
+        CertResolver,
+    };
+
+    #[test]
+    fn redirecting_to_https() {
+        let req = Request::get("/users?page=2")
+            .header(header::HOST, "example.com:8080")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = redirect(&req, 443);
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(
+            response.headers()[header::LOCATION],
+            "https://example.com/users?page=2"
+        );
+
+        let response = redirect(&req, 8443);
+        assert_eq!(
+            response.headers()[header::LOCATION],
+            "https://example.com:8443/users?page=2"
+        );
+
+        let req = Request::get("/").body(Body::empty()).unwrap();
+        assert_eq!(redirect(&req, 443).status(), StatusCode::BAD_REQUEST);
+    }
+
+    const HTTP2: Http2 = Http2 {
+        enabled:                true,
+        h2c:                    true,
+        max_concurrent_streams: Some(10),
+    };
+
+    fn handler() -> Arc<Handler> {
+        Arc::new(Handler {
+            router:       Router::new(vec![]),
+            static_files: None,
+            compression:  None,
+            websocket:    None,
+            events:       None,
+            access_log:   None,
+            tracer:       None,
+            health:       None,
+        })
+    }
+
+    /// Sends a request over `stream` and returns the response.
+    async fn request(
+        stream: impl AsyncRead + AsyncWrite + Unpin + Send + 'static,
+        h2: bool,
+    ) -> Result<Response<Body>> {
+        let (mut sender, conn) = hyper::client::conn::Builder::new()
+            .http2_only(h2)
+            .handshake(stream)
+            .await?;
+        tokio::spawn(conn);
+        let response = sender
+            .send_request(Request::get("/").body(Body::empty())?)
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        Ok(response)
+    }
+
+    async fn connect_tls(
+        alpn: &[&[u8]],
+    ) -> Result<tokio_rustls::client::TlsStream<TcpStream>> {
+        let (files, pem) = generate("coyote.test.server.1", &["localhost"])?;
+        let resolver = CertResolver::new(vec![files], tls::provider())?;
+        let config = tls::server_config(resolver, HTTP2.alpn())?;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(serve_tls(
+            listener,
+            handler(),
+            config,
+            HTTP2,
+            Some(HeaderValue::from_static(r#"h3=":443"; ma=60"#)),
+        ));
+
+        let mut roots = RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut pem.as_bytes()) {
+            roots.add(cert?)?;
+        }
+        let mut client = ClientConfig::builder_with_provider(tls::provider())
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        client.alpn_protocols = alpn.iter().map(|p| p.to_vec()).collect();
+
+        let stream = TlsConnector::from(Arc::new(client))
+            .connect(
+                ServerName::try_from("localhost")?,
+                TcpStream::connect(addr).await?,
+            )
+            .await?;
+        Ok(stream)
+    }
+
+    #[tokio::test]
+    async fn serving_over_tls() -> Result<()> {
+        let stream = connect_tls(&[b"http/1.1"]).await?;
+        let response = request(stream, false).await?;
+        assert_eq!(response.version(), Version::HTTP_11);
+        assert_eq!(response.headers()[header::ALT_SVC], r#"h3=":443"; ma=60"#);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn negotiating_http2_via_alpn() -> Result<()> {
+        let stream = connect_tls(&[b"h2", b"http/1.1"]).await?;
+        assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+        assert_eq!(request(stream, true).await?.version(), Version::HTTP_2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn serving_h2c_with_prior_knowledge() -> Result<()> {
+        for h2c in &[true, false] {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let addr = listener.local_addr()?;
+            let http2 = Http2 { h2c: *h2c, ..HTTP2 };
+            tokio::spawn(serve_http(listener, handler(), http2));
+
+            let h2 = request(TcpStream::connect(addr).await?, true).await;
+            assert_eq!(h2.is_ok(), *h2c);
+
+            let h1 = request(TcpStream::connect(addr).await?, false).await?;
+            assert_eq!(h1.version(), Version::HTTP_11);
+            assert!(!h1.headers().contains_key(header::ALT_SVC));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn parsing_listen_addresses() {
+        let parse = |addr: &str| addr.parse::<Addr>().ok();
+
+        assert_eq!(
+            parse("127.0.0.1:3000"),
+            Some(Addr::Tcp("127.0.0.1:3000".parse().unwrap()))
+        );
+        assert_eq!(
+            parse("[::1]:3000"),
+            Some(Addr::Tcp("[::1]:3000".parse().unwrap()))
+        );
+        assert_eq!(
+            parse("unix:/run/coyote.sock"),
+            Some(Addr::Unix("/run/coyote.sock".into()))
+        );
+        assert_eq!(parse("unix:"), None);
+        assert_eq!(parse("localhost:3000"), None);
+    }
+
+    #[tokio::test]
+    async fn listening_on_ipv4_and_ipv6() -> Result<()> {
+        let v4 = bind_tcp("127.0.0.1:0".parse()?)?;
+        let port = v4.local_addr()?.port();
+        // Skip where IPv6 is unavailable.
+        let v6 = match bind_tcp(SocketAddr::from(([0; 16], port))) {
+            Ok(v6) => v6,
+            Err(_) => return Ok(()),
+        };
+        tokio::spawn(serve_http(v4, handler(), HTTP2));
+        tokio::spawn(serve_http(v6, handler(), HTTP2));
+
+        let stream = TcpStream::connect(("127.0.0.1", port)).await?;
+        request(stream, false).await?;
+        let stream = TcpStream::connect(("::1", port)).await?;
+        request(stream, false).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn serving_on_unix_sockets() -> Result<()> {
+        let path = std::env::temp_dir().join("coyote.test.server.2.sock");
+        let listener = bind_unix(&path)?;
+        tokio::spawn(serve_unix(listener, handler(), HTTP2));
+
+        let stream = UnixStream::connect(&path).await?;
+        assert_eq!(request(stream, false).await?.version(), Version::HTTP_11);
+
+        Ok(())
+    }
+}
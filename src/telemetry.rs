@@ -0,0 +1,561 @@
+use std::time::{
+    Duration,
+    SystemTime,
+    UNIX_EPOCH,
+};
+
+use anyhow::{
+    anyhow,
+    Result,
+};
+use hyper::client::HttpConnector;
+use hyper::{
+    header,
+    Body,
+    Client,
+    HeaderMap,
+    Request,
+    Response,
+    Uri,
+};
+use ring::rand::{
+    SecureRandom,
+    SystemRandom,
+};
+use serde::Serialize;
+use serde_json::{
+    json,
+    Value,
+};
+use tokio::sync::mpsc;
+use tokio::time::interval;
+
+use crate::http::RemoteAddr;
+use crate::worker::pool::Execution;
+
+/// Spans buffered for export, newer ones are dropped once full.
+const QUEUE_SIZE: usize = 4096;
+
+/// Spans sent per export request.
+const MAX_BATCH: usize = 512;
+
+const SPAN_KIND_INTERNAL: u8 = 1;
+const SPAN_KIND_SERVER: u8 = 2;
+const STATUS_CODE_ERROR: u8 = 2;
+
+/// W3C trace context of a span, as carried by `traceparent` and
+/// `tracestate`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpanContext {
+    pub trace_id: [u8; 16],
+    pub span_id:  [u8; 8],
+    pub sampled:  bool,
+    pub state:    Option<String>,
+}
+
+impl SpanContext {
+    /// Parses a version 00 `traceparent`, invalid ones start a new trace.
+    pub fn parse(
+        traceparent: &str,
+        tracestate: Option<&str>,
+    ) -> Option<Self> {
+        let mut parts = traceparent.trim().split('-');
+        let (version, trace_id, span_id, flags) =
+            (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+        if version != "00" || parts.next().is_some() {
+            return None;
+        }
+
+        let mut context = Self {
+            trace_id: [0; 16],
+            span_id:  [0; 8],
+            sampled:  u8::from_str_radix(flags, 16).ok()? & 1 == 1,
+            state:    tracestate.map(String::from),
+        };
+        decode_hex(trace_id, &mut context.trace_id)?;
+        decode_hex(span_id, &mut context.span_id)?;
+        if flags.len() != 2 ||
+            context.trace_id == [0; 16] ||
+            context.span_id == [0; 8]
+        {
+            return None;
+        }
+
+        Some(context)
+    }
+
+    /// A span of the same trace.
+    pub fn child(&self) -> Self {
+        Self {
+            span_id: random_id(),
+            ..self.clone()
+        }
+    }
+
+    pub fn traceparent(&self) -> String {
+        format!(
+            "00-{}-{}-{:02x}",
+            hex(&self.trace_id),
+            hex(&self.span_id),
+            self.sampled as u8
+        )
+    }
+}
+
+/// Trace context handed to PHP with the request envelope.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Propagation {
+    pub traceparent: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tracestate:  Option<String>,
+}
+
+/// Trace of a request, set as a request extension while it's handled.
+#[derive(Debug, Clone)]
+pub struct RequestTrace {
+    /// Caller's span, `None` for new traces.
+    pub parent:  Option<SpanContext>,
+    pub request: SpanContext,
+    /// Span of the worker execution, PHP spans nest under it.
+    pub worker:  SpanContext,
+}
+
+impl RequestTrace {
+    /// Continues the trace of `headers` or starts a new one.
+    pub fn new(headers: &HeaderMap) -> Self {
+        let header = |name| {
+            headers.get(name).and_then(|value| value.to_str().ok())
+        };
+        let state = header("tracestate");
+        let parent = header("traceparent")
+            .and_then(|parent| SpanContext::parse(parent, state));
+        let request = match &parent {
+            Some(parent) => parent.child(),
+            None => SpanContext {
+                trace_id: random_id(),
+                span_id:  random_id(),
+                sampled:  true,
+                state:    None,
+            },
+        };
+        let worker = request.child();
+
+        Self {
+            parent,
+            request,
+            worker,
+        }
+    }
+
+    pub fn propagation(&self) -> Propagation {
+        Propagation {
+            traceparent: self.worker.traceparent(),
+            tracestate:  self.worker.state.clone(),
+        }
+    }
+}
+
+/// A request being traced.
+pub struct RequestSpan {
+    trace:      RequestTrace,
+    start:      SystemTime,
+    attributes: Vec<(&'static str, Value)>,
+}
+
+#[derive(Debug)]
+struct Span {
+    trace_id:   [u8; 16],
+    span_id:    [u8; 8],
+    parent_id:  Option<[u8; 8]>,
+    name:       String,
+    kind:       u8,
+    start:      SystemTime,
+    end:        SystemTime,
+    attributes: Vec<(&'static str, Value)>,
+    error:      bool,
+}
+
+/// Records spans of requests and exports them to an OTLP/HTTP collector
+/// in batches.
+pub struct Tracer {
+    spans: mpsc::Sender<Span>,
+}
+
+impl Tracer {
+    /// Exports to `endpoint`, e.g. `http://localhost:4318/v1/traces`,
+    /// every `flush_interval`.
+    pub fn new(
+        endpoint: Uri,
+        service_name: String,
+        flush_interval: Duration,
+    ) -> Result<Self> {
+        if endpoint.scheme_str() != Some("http") {
+            return Err(anyhow!("OTLP endpoint must be http: {}", endpoint));
+        }
+
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(export(rx, endpoint, service_name, flush_interval));
+        Ok(Self { spans: tx })
+    }
+
+    /// Starts tracing `req`, setting its [`RequestTrace`] extension.
+    pub fn start(
+        &self,
+        req: &mut Request<Body>,
+    ) -> RequestSpan {
+        let trace = RequestTrace::new(req.headers());
+        req.extensions_mut().insert(trace.clone());
+
+        let mut attributes = vec![
+            ("http.request.method", json!(req.method().as_str())),
+            ("url.path", json!(req.uri().path())),
+        ];
+        if let Some(remote) = req.extensions().get::<RemoteAddr>() {
+            attributes.push(("client.address", json!(remote.0.ip())));
+        }
+
+        RequestSpan {
+            trace,
+            start: SystemTime::now(),
+            attributes,
+        }
+    }
+
+    /// Records the request span, plus queue wait and worker execution
+    /// spans if a worker served it.
+    pub fn finish(
+        &self,
+        span: RequestSpan,
+        response: &Response<Body>,
+    ) {
+        let RequestSpan {
+            trace,
+            start,
+            mut attributes,
+        } = span;
+        if !trace.request.sampled {
+            return;
+        }
+
+        let end = SystemTime::now();
+        let status = response.status();
+        attributes.push(("http.response.status_code", json!(status.as_u16())));
+        let method = attributes[0].1.as_str().unwrap_or_default().to_string();
+        self.record(Span {
+            trace_id: trace.request.trace_id,
+            span_id: trace.request.span_id,
+            parent_id: trace.parent.as_ref().map(|parent| parent.span_id),
+            name: method,
+            kind: SPAN_KIND_SERVER,
+            start,
+            end,
+            attributes,
+            error: status.is_server_error(),
+        });
+
+        let execution = match response.extensions().get::<Execution>() {
+            Some(execution) => execution,
+            None => return,
+        };
+        let worker_start = end - execution.exec_time;
+        self.record(Span {
+            trace_id:   trace.request.trace_id,
+            span_id:    trace.request.child().span_id,
+            parent_id:  Some(trace.request.span_id),
+            name:       "queue wait".into(),
+            kind:       SPAN_KIND_INTERNAL,
+            start:      worker_start - execution.queue_time,
+            end:        worker_start,
+            attributes: vec![],
+            error:      false,
+        });
+        let mut attributes = vec![];
+        if let Some(pid) = execution.pid {
+            attributes.push(("process.pid", json!(pid)));
+        }
+        self.record(Span {
+            trace_id: trace.worker.trace_id,
+            span_id: trace.worker.span_id,
+            parent_id: Some(trace.request.span_id),
+            name: "worker execution".into(),
+            kind: SPAN_KIND_INTERNAL,
+            start: worker_start,
+            end,
+            attributes,
+            error: false,
+        });
+    }
+
+    fn record(
+        &self,
+        span: Span,
+    ) {
+        if self.spans.try_send(span).is_err() {
+            log::debug!("dropping span, export queue is full");
+        }
+    }
+}
+
+/// Sends batches of spans until every [`Tracer`] is dropped.
+async fn export(
+    mut spans: mpsc::Receiver<Span>,
+    endpoint: Uri,
+    service_name: String,
+    flush_interval: Duration,
+) {
+    let client = Client::new();
+    let mut ticker = interval(flush_interval);
+    let mut batch = vec![];
+
+    loop {
+        let closed = tokio::select! {
+            span = spans.recv() => match span {
+                Some(span) => {
+                    batch.push(span);
+                    if batch.len() < MAX_BATCH {
+                        continue;
+                    }
+                    false
+                }
+                None => true,
+            },
+            _ = ticker.tick() => false,
+        };
+
+        if !batch.is_empty() {
+            let body = encode(&service_name, &batch);
+            batch.clear();
+            if let Err(err) = send(&client, &endpoint, body).await {
+                log::warn!("could not export spans: {}", err);
+            }
+        }
+        if closed {
+            break;
+        }
+    }
+}
+
+async fn send(
+    client: &Client<HttpConnector>,
+    endpoint: &Uri,
+    body: Value,
+) -> Result<()> {
+    let req = Request::post(endpoint.clone())
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec(&body)?))?;
+    let response = client.request(req).await?;
+    if !response.status().is_success() {
+        return Err(anyhow!("collector answered {}", response.status()));
+    }
+    Ok(())
+}
+
+/// Encodes an OTLP `ExportTraceServiceRequest` in its JSON mapping.
+fn encode(
+    service_name: &str,
+    spans: &[Span],
+) -> Value {
+    let spans = spans
+        .iter()
+        .map(|span| {
+            let mut encoded = json!({
+                "traceId": hex(&span.trace_id),
+                "spanId": hex(&span.span_id),
+                "name": span.name,
+                "kind": span.kind,
+                "startTimeUnixNano": nanos(span.start),
+                "endTimeUnixNano": nanos(span.end),
+                "attributes": attributes(&span.attributes),
+            });
+            if let Some(parent_id) = &span.parent_id {
+                encoded["parentSpanId"] = json!(hex(parent_id));
+            }
+            if span.error {
+                encoded["status"] = json!({ "code": STATUS_CODE_ERROR });
+            }
+            encoded
+        })
+        .collect::<Vec<_>>();
+
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": attributes(&[
+                    ("service.name", json!(service_name)),
+                ]),
+            },
+            "scopeSpans": [{
+                "scope": { "name": "coyote" },
+                "spans": spans,
+            }],
+        }],
+    })
+}
+
+fn attributes(attributes: &[(&str, Value)]) -> Value {
+    attributes
+        .iter()
+        .map(|(key, value)| {
+            // 64 bit integers are strings in OTLP's JSON mapping.
+            let value = match value {
+                Value::Number(n) if n.is_u64() || n.is_i64() => {
+                    json!({ "intValue": n.to_string() })
+                }
+                Value::String(s) => json!({ "stringValue": s }),
+                other => json!({ "stringValue": other.to_string() }),
+            };
+            json!({ "key": key, "value": value })
+        })
+        .collect()
+}
+
+fn nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+fn random_id<const N: usize>() -> [u8; N] {
+    let mut id = [0; N];
+    SystemRandom::new()
+        .fill(&mut id)
+        .expect("system random number generator failed");
+    id
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(
+    hex: &str,
+    out: &mut [u8],
+) -> Option<()> {
+    if hex.len() != out.len() * 2 {
+        return None;
+    }
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use hyper::service::{
+        make_service_fn,
+        service_fn,
+    };
+    use hyper::Server;
+
+    use super::*;
+
+    const TRACEPARENT: &str =
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn parsing_traceparent() {
+        let context =
+            SpanContext::parse(TRACEPARENT, Some("vendor=1")).unwrap();
+        assert_eq!(hex(&context.trace_id), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(hex(&context.span_id), "00f067aa0ba902b7");
+        assert!(context.sampled);
+        assert_eq!(context.traceparent(), TRACEPARENT);
+
+        for invalid in &[
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+        ] {
+            assert_eq!(SpanContext::parse(invalid, None), None);
+        }
+    }
+
+    #[test]
+    fn propagating_to_workers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("traceparent", TRACEPARENT.parse().unwrap());
+        headers.insert("tracestate", "vendor=1".parse().unwrap());
+
+        let trace = RequestTrace::new(&headers);
+        let propagation = trace.propagation();
+
+        assert_eq!(trace.request.trace_id, trace.parent.unwrap().trace_id);
+        assert!(propagation
+            .traceparent
+            .starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+        assert_ne!(propagation.traceparent, TRACEPARENT);
+        assert_eq!(propagation.tracestate.as_deref(), Some("vendor=1"));
+
+        let trace = RequestTrace::new(&HeaderMap::new());
+        assert!(trace.parent.is_none());
+        assert_eq!(trace.worker.trace_id, trace.request.trace_id);
+    }
+
+    #[tokio::test]
+    async fn exporting_spans() -> Result<()> {
+        // Stands in for an OpenTelemetry collector.
+        let (tx, mut received) = mpsc::unbounded_channel();
+        let make_svc = make_service_fn(move |_| {
+            let tx = tx.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let tx = tx.clone();
+                    async move {
+                        let body = req.into_body();
+                        let body = hyper::body::to_bytes(body).await?;
+                        let _ = tx.send(serde_json::from_slice::<Value>(&body));
+                        Ok::<_, hyper::Error>(Response::new(Body::empty()))
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse()?).serve(make_svc);
+        let endpoint = format!("http://{}/v1/traces", server.local_addr());
+        tokio::spawn(server);
+
+        let tracer = Tracer::new(
+            endpoint.parse()?,
+            "app".into(),
+            Duration::from_millis(10),
+        )?;
+        let mut req = Request::get("/hello")
+            .header("traceparent", TRACEPARENT)
+            .body(Body::empty())?;
+        let span = tracer.start(&mut req);
+        let trace = req.extensions().get::<RequestTrace>().unwrap().clone();
+        let mut response = Response::new(Body::empty());
+        response.extensions_mut().insert(Execution {
+            pid:        Some(42),
+            queue_time: Duration::from_millis(1),
+            exec_time:  Duration::from_millis(2),
+        });
+        tracer.finish(span, &response);
+
+        let export = received.recv().await.unwrap()?;
+        let resource = &export["resourceSpans"][0];
+        assert_eq!(
+            resource["resource"]["attributes"][0]["value"]["stringValue"],
+            "app"
+        );
+        let spans = resource["scopeSpans"][0]["spans"].as_array().unwrap();
+        assert_eq!(spans.len(), 3);
+
+        let (request, queue, worker) = (&spans[0], &spans[1], &spans[2]);
+        assert_eq!(request["name"], "GET");
+        assert_eq!(request["kind"], SPAN_KIND_SERVER);
+        assert_eq!(request["parentSpanId"], "00f067aa0ba902b7");
+        assert_eq!(request["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(queue["name"], "queue wait");
+        assert_eq!(queue["parentSpanId"], request["spanId"]);
+        assert_eq!(worker["spanId"], hex(&trace.worker.span_id));
+        assert_eq!(worker["parentSpanId"], request["spanId"]);
+        assert_eq!(worker["attributes"][0]["value"]["intValue"], "42");
+
+        Ok(())
+    }
+}
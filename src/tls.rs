@@ -0,0 +1,280 @@
+use std::fs::{
+    self,
+    File,
+};
+use std::io::BufReader;
+use std::sync::{
+    Arc,
+    RwLock,
+};
+use std::time::{
+    Duration,
+    SystemTime,
+};
+
+use anyhow::{
+    anyhow,
+    bail,
+    Result,
+};
+use rustls::crypto::CryptoProvider;
+use rustls::server::{
+    ClientHello,
+    ResolvesServerCert,
+};
+use rustls::sign::CertifiedKey;
+use rustls::ServerConfig;
+
+use crate::http::match_host;
+
+/// PEM files of a certificate and the hosts it is served for.
+#[derive(Debug, Clone)]
+pub struct CertificateFiles {
+    pub cert:  String,
+    pub key:   String,
+    /// Server names selecting this certificate via SNI, `*.example.com`
+    /// matches any subdomain.
+    pub hosts: Vec<String>,
+}
+
+/// Selects certificates by SNI, falling back to the first one, and reloads
+/// them when their files change.
+#[derive(Debug)]
+pub struct CertResolver {
+    files:    Vec<CertificateFiles>,
+    provider: Arc<CryptoProvider>,
+    loaded:   RwLock<Loaded>,
+}
+
+#[derive(Debug)]
+struct Loaded {
+    keys:     Vec<Arc<CertifiedKey>>,
+    modified: Vec<Option<SystemTime>>,
+}
+
+impl CertResolver {
+    pub fn new(
+        files: Vec<CertificateFiles>,
+        provider: Arc<CryptoProvider>,
+    ) -> Result<Arc<Self>> {
+        if files.is_empty() {
+            bail!("at least one certificate must be configured");
+        }
+
+        let loaded = load(&files, &provider)?;
+        Ok(Arc::new(Self {
+            files,
+            provider,
+            loaded: RwLock::new(loaded),
+        }))
+    }
+
+    /// Reloads certificates if any of their files changed, keeping the old
+    /// ones if loading fails.
+    pub fn reload_if_changed(&self) -> Result<bool> {
+        let modified = modified(&self.files);
+        {
+            let loaded = self.loaded.read().expect("cert lock is poisoned");
+            if loaded.modified == modified {
+                return Ok(false);
+            }
+        }
+
+        let loaded = load(&self.files, &self.provider)?;
+        *self.loaded.write().expect("cert lock is poisoned") = loaded;
+        Ok(true)
+    }
+
+    /// Periodically checks certificate files for changes.
+    pub fn watch(
+        self: Arc<Self>,
+        interval: Duration,
+    ) {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                match self.reload_if_changed() {
+                    Ok(true) => log::info!("Reloaded TLS certificates"),
+                    Ok(false) => {}
+                    Err(err) => log::error!(
+                        "could not reload TLS certificates: {}",
+                        err
+                    ),
+                }
+            }
+        });
+    }
+
+    fn find(
+        &self,
+        server_name: Option<&str>,
+    ) -> Option<Arc<CertifiedKey>> {
+        let loaded = self.loaded.read().expect("cert lock is poisoned");
+        let index = server_name
+            .and_then(|name| {
+                self.files.iter().position(|files| {
+                    files.hosts.iter().any(|host| match_host(host, name))
+                })
+            })
+            .unwrap_or(0);
+
+        loaded.keys.get(index).cloned()
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(
+        &self,
+        client_hello: ClientHello<'_>,
+    ) -> Option<Arc<CertifiedKey>> {
+        self.find(client_hello.server_name())
+    }
+}
+
+/// Builds a server config using `resolver` and advertising `alpn` protocols.
+pub fn server_config(
+    resolver: Arc<CertResolver>,
+    alpn: Vec<Vec<u8>>,
+) -> Result<Arc<ServerConfig>> {
+    let mut config =
+        ServerConfig::builder_with_provider(resolver.provider.clone())
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(resolver);
+    config.alpn_protocols = alpn;
+
+    Ok(Arc::new(config))
+}
+
+pub fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn load(
+    files: &[CertificateFiles],
+    provider: &CryptoProvider,
+) -> Result<Loaded> {
+    // NOTE: modification times are taken before reading so a change while
+    // loading is picked up by the next check.
+    let modified = modified(files);
+    let keys = files
+        .iter()
+        .map(|files| load_certified_key(files, provider).map(Arc::new))
+        .collect::<Result<_>>()?;
+
+    Ok(Loaded { keys, modified })
+}
+
+fn load_certified_key(
+    files: &CertificateFiles,
+    provider: &CryptoProvider,
+) -> Result<CertifiedKey> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(
+        File::open(&files.cert)
+            .map_err(|err| anyhow!("could not open {}: {}", files.cert, err))?,
+    ))
+    .collect::<Result<Vec<_>, _>>()
+    .map_err(|err| anyhow!("could not read {}: {}", files.cert, err))?;
+    if certs.is_empty() {
+        bail!("no certificates found in {}", files.cert);
+    }
+
+    let key = rustls_pemfile::private_key(&mut BufReader::new(
+        File::open(&files.key)
+            .map_err(|err| anyhow!("could not open {}: {}", files.key, err))?,
+    ))
+    .map_err(|err| anyhow!("could not read {}: {}", files.key, err))?
+    .ok_or_else(|| anyhow!("no private key found in {}", files.key))?;
+
+    CertifiedKey::from_der(certs, key, provider).map_err(|err| {
+        anyhow!("invalid certificate {}: {}", files.cert, err)
+    })
+}
+
+fn modified(files: &[CertificateFiles]) -> Vec<Option<SystemTime>> {
+    files
+        .iter()
+        .flat_map(|files| vec![&files.cert, &files.key])
+        .map(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok())
+        .collect()
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::path::PathBuf;
+
+    use rcgen::CertifiedKey as Generated;
+
+    use super::*;
+
+    /// Writes a self-signed certificate for `hosts` and returns the files
+    /// with the certificate in PEM.
+    pub fn generate(
+        dir: &str,
+        hosts: &[&str],
+    ) -> Result<(CertificateFiles, String)> {
+        let dir = std::env::temp_dir().join(dir);
+        fs::create_dir_all(&dir)?;
+
+        let Generated { cert, key_pair } = rcgen::generate_simple_self_signed(
+            hosts.iter().map(|host| host.to_string()).collect::<Vec<_>>(),
+        )?;
+        let path = |name: &str| -> PathBuf { dir.join(name) };
+        fs::write(path("cert.pem"), cert.pem())?;
+        fs::write(path("key.pem"), key_pair.serialize_pem())?;
+
+        let files = CertificateFiles {
+            cert:  path("cert.pem").to_string_lossy().into_owned(),
+            key:   path("key.pem").to_string_lossy().into_owned(),
+            hosts: hosts.iter().map(|host| host.to_string()).collect(),
+        };
+        Ok((files, cert.pem()))
+    }
+
+    fn leaf(key: &CertifiedKey) -> Vec<u8> {
+        key.cert[0].to_vec()
+    }
+
+    #[test]
+    fn selecting_certificates_by_sni() -> Result<()> {
+        let (example, _) = generate("coyote.test.tls.1", &["example.com"])?;
+        let (wildcard, _) = generate("coyote.test.tls.2", &["*.example.org"])?;
+        let resolver = CertResolver::new(vec![example, wildcard], provider())?;
+
+        let example = leaf(&resolver.find(Some("example.com")).unwrap());
+        let wildcard = leaf(&resolver.find(Some("www.example.org")).unwrap());
+        let fallback = leaf(&resolver.find(Some("other.net")).unwrap());
+        let no_sni = leaf(&resolver.find(None).unwrap());
+
+        assert_ne!(example, wildcard);
+        assert_eq!(fallback, example);
+        assert_eq!(no_sni, example);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reloading_changed_certificates() -> Result<()> {
+        let (files, _) = generate("coyote.test.tls.3", &["example.com"])?;
+        let resolver = CertResolver::new(vec![files], provider())?;
+        let before = leaf(&resolver.find(None).unwrap());
+
+        assert!(!resolver.reload_if_changed()?);
+
+        generate("coyote.test.tls.3", &["example.com"])?;
+        // Make sure the modification time changes on coarse filesystems.
+        let bump = SystemTime::now() + Duration::from_secs(1);
+        for name in &["cert.pem", "key.pem"] {
+            let path = std::env::temp_dir().join("coyote.test.tls.3");
+            File::options()
+                .write(true)
+                .open(path.join(name))?
+                .set_modified(bump)?;
+        }
+
+        assert!(resolver.reload_if_changed()?);
+        assert_ne!(leaf(&resolver.find(None).unwrap()), before);
+
+        Ok(())
+    }
+}
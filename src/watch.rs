@@ -0,0 +1,261 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{
+    anyhow,
+    Result,
+};
+use globset::{
+    Glob,
+    GlobSet,
+    GlobSetBuilder,
+};
+use inotify::{
+    EventMask,
+    EventStream,
+    Inotify,
+    WatchDescriptor,
+    WatchMask,
+    Watches,
+};
+use tokio::time::timeout;
+use tokio_stream::StreamExt;
+
+use crate::worker::pool::Static;
+
+/// Restarts a pool's workers when files under watched directories change,
+/// so they pick up new code during development.
+pub struct Watcher {
+    name:     String,
+    pool:     Arc<Static>,
+    paths:    Vec<PathBuf>,
+    patterns: GlobSet,
+    /// Time without further changes before workers are restarted.
+    debounce: Duration,
+}
+
+/// A watched directory and the watched path it is part of.
+struct Dir {
+    root: PathBuf,
+    path: PathBuf,
+}
+
+impl Watcher {
+    pub fn new(
+        name: String,
+        pool: Arc<Static>,
+        paths: Vec<PathBuf>,
+        patterns: &[String],
+        debounce: Duration,
+    ) -> Result<Self> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in patterns {
+            builder.add(Glob::new(pattern)?);
+        }
+
+        Ok(Self {
+            name,
+            pool,
+            paths,
+            patterns: builder.build()?,
+            debounce,
+        })
+    }
+
+    /// Watches every directory below the watched paths, then restarts the
+    /// pool in the background whenever a matching file changes.
+    pub fn spawn(self) -> Result<()> {
+        let inotify = Inotify::init()?;
+        let mut watches = inotify.watches();
+        let mut dirs = HashMap::new();
+        for path in &self.paths {
+            self.add_tree(&mut watches, &mut dirs, path, path)?;
+        }
+        let mut events = inotify.into_event_stream(vec![0; 4096])?;
+
+        tokio::spawn(async move {
+            if let Err(err) = self.run(&mut events, &mut dirs).await {
+                log::error!("could not watch files of {}: {}", self.name, err);
+            }
+        });
+        Ok(())
+    }
+
+    async fn run(
+        &self,
+        events: &mut EventStream<Vec<u8>>,
+        dirs: &mut HashMap<WatchDescriptor, Dir>,
+    ) -> Result<()> {
+        loop {
+            let changed = self.next_change(events, dirs).await?;
+            let mut others = changed.len() - 1;
+            // Saving several files shouldn't restart workers several times.
+            while let Ok(more) =
+                timeout(self.debounce, self.next_change(events, dirs)).await
+            {
+                others += more?.len();
+            }
+
+            match others {
+                0 => log::info!(
+                    "{} changed, restarting workers of {}",
+                    changed[0].display(),
+                    self.name
+                ),
+                _ => log::info!(
+                    "{} and {} other files changed, restarting workers of {}",
+                    changed[0].display(),
+                    others,
+                    self.name
+                ),
+            }
+            self.pool.reset();
+        }
+    }
+
+    /// Waits until files matching the patterns change.
+    async fn next_change(
+        &self,
+        events: &mut EventStream<Vec<u8>>,
+        dirs: &mut HashMap<WatchDescriptor, Dir>,
+    ) -> Result<Vec<PathBuf>> {
+        let mut watches = events.watches();
+        while let Some(event) = events.next().await {
+            let event = event?;
+            if event.mask.contains(EventMask::IGNORED) {
+                dirs.remove(&event.wd);
+                continue;
+            }
+            let (dir, name) = match (dirs.get(&event.wd), &event.name) {
+                (Some(dir), Some(name)) => (dir, name),
+                _ => continue,
+            };
+            let root = dir.root.clone();
+            let path = dir.path.join(name);
+
+            let created = EventMask::CREATE | EventMask::MOVED_TO;
+            let changed = if event.mask.contains(EventMask::ISDIR) {
+                if !event.mask.intersects(created) {
+                    continue;
+                }
+                // Files may have been written before the directory was
+                // watched.
+                match self.add_tree(&mut watches, dirs, &root, &path) {
+                    Ok(files) => files,
+                    Err(err) => {
+                        log::warn!("{}", err);
+                        continue;
+                    }
+                }
+            } else if self.matches(&root, &path) {
+                vec![path]
+            } else {
+                vec![]
+            };
+            if !changed.is_empty() {
+                return Ok(changed);
+            }
+        }
+
+        Err(anyhow!("file watcher stopped"))
+    }
+
+    /// Watches `dir` and its subdirectories, returns matching files found
+    /// in them.
+    fn add_tree(
+        &self,
+        watches: &mut Watches,
+        dirs: &mut HashMap<WatchDescriptor, Dir>,
+        root: &Path,
+        dir: &Path,
+    ) -> Result<Vec<PathBuf>> {
+        let mask = WatchMask::CLOSE_WRITE |
+            WatchMask::CREATE |
+            WatchMask::DELETE |
+            WatchMask::MOVED_FROM |
+            WatchMask::MOVED_TO |
+            WatchMask::ONLYDIR;
+        let wd = watches.add(dir, mask).map_err(|err| {
+            anyhow!("could not watch {}: {}", dir.display(), err)
+        })?;
+        dirs.insert(wd, Dir {
+            root: root.to_path_buf(),
+            path: dir.to_path_buf(),
+        });
+
+        let mut files = vec![];
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if entry.file_type()?.is_dir() {
+                files.extend(self.add_tree(watches, dirs, root, &path)?);
+            } else if self.matches(root, &path) {
+                files.push(path);
+            }
+        }
+        Ok(files)
+    }
+
+    fn matches(
+        &self,
+        root: &Path,
+        path: &Path,
+    ) -> bool {
+        let relative = path.strip_prefix(root).unwrap_or(path);
+        self.patterns.is_match(relative)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::worker::pool::SpawnBackoff;
+    use crate::worker::Isolation;
+
+    #[tokio::test]
+    async fn restarting_workers_on_change() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let pool = Arc::new(
+            Static::new(
+                "/tmp/coyote.test.sock.21",
+                "./src/worker/test_data/echo_worker.php",
+                1,
+                Isolation::default(),
+                SpawnBackoff::default(),
+            )
+            .await?,
+        );
+        let pid = || pool.status().workers.first().map(|worker| worker.pid);
+        let started = pid();
+
+        Watcher::new(
+            "app".into(),
+            pool.clone(),
+            vec![dir.path().to_path_buf()],
+            &["*.php".to_string()],
+            Duration::from_millis(20),
+        )?
+        .spawn()?;
+
+        fs::write(dir.path().join("notes.txt"), "hello")?;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(pid(), started);
+
+        fs::create_dir(dir.path().join("lib"))?;
+        fs::write(dir.path().join("lib/app.php"), "<?php")?;
+        let restarted = async {
+            while pid().is_none() || pid() == started {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        timeout(Duration::from_secs(5), restarted).await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,200 @@
+use std::convert::TryInto;
+use std::io;
+
+use anyhow::{
+    anyhow,
+    bail,
+    Result,
+};
+use num_traits::FromPrimitive;
+use tokio::io::{
+    AsyncRead,
+    AsyncReadExt,
+    AsyncWrite,
+    AsyncWriteExt,
+};
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, FromPrimitive)]
+pub enum Opcode {
+    Continuation = 0x0,
+    Text = 0x1,
+    Binary = 0x2,
+    Close = 0x8,
+    Ping = 0x9,
+    Pong = 0xa,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub fin:     bool,
+    pub opcode:  Opcode,
+    pub payload: Vec<u8>,
+}
+
+impl Opcode {
+    pub fn is_control(self) -> bool {
+        self as u8 & 0x8 != 0
+    }
+}
+
+impl Frame {
+    pub fn new(
+        opcode: Opcode,
+        payload: Vec<u8>,
+    ) -> Self {
+        Self {
+            fin: true,
+            opcode,
+            payload,
+        }
+    }
+
+    /// Reads the next client frame, `None` if the connection was closed
+    /// between frames.
+    pub async fn read_from(
+        mut src: impl AsyncRead + Unpin,
+        max_size: usize,
+    ) -> Result<Option<Self>> {
+        let mut header = [0u8; 2];
+        match src.read_exact(&mut header).await {
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                return Ok(None)
+            }
+            Err(err) => return Err(err.into()),
+        }
+
+        let fin = header[0] & 0x80 != 0;
+        if header[0] & 0x70 != 0 {
+            bail!("reserved bits are set without a negotiated extension");
+        }
+        let opcode = Opcode::from_u8(header[0] & 0x0f)
+            .ok_or_else(|| anyhow!("unknown opcode: {}", header[0] & 0x0f))?;
+        // NOTE: clients must mask every frame they send.
+        if header[1] & 0x80 == 0 {
+            bail!("client frame is not masked");
+        }
+
+        let len = match header[1] & 0x7f {
+            126 => src.read_u16().await? as u64,
+            127 => src.read_u64().await?,
+            len => len as u64,
+        };
+        if opcode.is_control() && (len > 125 || !fin) {
+            bail!("invalid control frame");
+        }
+        if len > max_size as u64 {
+            bail!("frame of {} bytes exceeds the limit", len);
+        }
+
+        let mut mask = [0u8; 4];
+        src.read_exact(&mut mask).await?;
+        let mut payload = vec![0; len.try_into()?];
+        src.read_exact(&mut payload).await?;
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+
+        Ok(Some(Self {
+            fin,
+            opcode,
+            payload,
+        }))
+    }
+
+    /// Writes an unmasked server frame, callers are expected to flush.
+    pub async fn write_to(
+        &self,
+        mut dst: impl AsyncWrite + Unpin,
+    ) -> Result<()> {
+        let mut header = Vec::with_capacity(10);
+        header.push((self.fin as u8) << 7 | self.opcode as u8);
+        match self.payload.len() {
+            len if len < 126 => header.push(len as u8),
+            len if len <= u16::MAX as usize => {
+                header.push(126);
+                header.extend(&(len as u16).to_be_bytes());
+            }
+            len => {
+                header.push(127);
+                header.extend(&(len as u64).to_be_bytes());
+            }
+        }
+        dst.write_all(&header).await?;
+        dst.write_all(&self.payload).await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use tokio::io::duplex;
+
+    use super::*;
+
+    /// Encodes a masked client frame.
+    pub fn client_frame(
+        opcode: Opcode,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut buf = vec![0x80 | opcode as u8];
+        match payload.len() {
+            len if len < 126 => buf.push(0x80 | len as u8),
+            len => {
+                buf.push(0x80 | 126);
+                buf.extend(&(len as u16).to_be_bytes());
+            }
+        }
+        buf.extend(&mask);
+        buf.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        buf
+    }
+
+    #[tokio::test]
+    async fn reading_client_frames() -> Result<()> {
+        let long = vec![b'x'; 300];
+        let (mut client, mut server) = duplex(1024);
+        client.write_all(&client_frame(Opcode::Text, b"hello")).await?;
+        client.write_all(&client_frame(Opcode::Binary, &long)).await?;
+        drop(client);
+
+        let frame = Frame::read_from(&mut server, 1024).await?.unwrap();
+        assert_eq!(frame, Frame::new(Opcode::Text, b"hello".to_vec()));
+        let frame = Frame::read_from(&mut server, 1024).await?.unwrap();
+        assert_eq!(frame, Frame::new(Opcode::Binary, long));
+        assert_eq!(Frame::read_from(&mut server, 1024).await?, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejecting_invalid_frames() -> Result<()> {
+        let (mut client, mut server) = duplex(1024);
+        client.write_all(&client_frame(Opcode::Text, &[0; 300])).await?;
+        assert!(Frame::read_from(&mut server, 256).await.is_err());
+
+        let (mut client, mut server) = duplex(1024);
+        // Unmasked.
+        client.write_all(&[0x81, 0x02, b'h', b'i']).await?;
+        assert!(Frame::read_from(&mut server, 256).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn writing_server_frames() -> Result<()> {
+        let (mut server, mut client) = duplex(1024);
+        Frame::new(Opcode::Text, vec![b'x'; 200])
+            .write_to(&mut server)
+            .await?;
+
+        let mut header = [0u8; 4];
+        client.read_exact(&mut header).await?;
+        assert_eq!(header, [0x81, 126, 0, 200]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,315 @@
+use std::collections::{
+    HashMap,
+    HashSet,
+};
+use std::sync::atomic::{
+    AtomicU64,
+    Ordering,
+};
+use std::sync::{
+    Arc,
+    Mutex,
+};
+
+use serde::Deserialize;
+use tokio::sync::mpsc;
+
+use crate::worker::ipc::Publisher;
+
+pub type ConnectionId = u64;
+
+/// Messages delivered to a connection.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outgoing {
+    Message {
+        /// Event name, used by Server-Sent Events only.
+        event:  Option<String>,
+        data:   String,
+        /// `data` is base64 encoded, sent as a binary WebSocket frame.
+        binary: bool,
+    },
+    Close,
+}
+
+/// Commands PHP workers send back with responses.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Commands {
+    #[serde(default)]
+    pub send:      Vec<Send>,
+    #[serde(default)]
+    pub broadcast: Vec<Broadcast>,
+    #[serde(default)]
+    pub join:      Vec<Membership>,
+    #[serde(default)]
+    pub leave:     Vec<Membership>,
+    #[serde(default)]
+    pub close:     Vec<ConnectionId>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Send {
+    pub connection: ConnectionId,
+    pub data:       String,
+    /// `data` is base64 encoded binary.
+    #[serde(default)]
+    pub binary:     bool,
+}
+
+/// A message for every connection in a channel, also what workers publish
+/// through the relay.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Broadcast {
+    pub channel: String,
+    pub data:    String,
+    pub event:   Option<String>,
+    /// `data` is base64 encoded binary.
+    #[serde(default)]
+    pub binary:  bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Membership {
+    pub connection: ConnectionId,
+    pub channel:    String,
+}
+
+/// Connections held by coyote and the channels they joined, channels are
+/// shared by WebSockets and event streams.
+#[derive(Debug, Default)]
+pub struct Hub {
+    next_id: AtomicU64,
+    state:   Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    connections: HashMap<ConnectionId, mpsc::UnboundedSender<Outgoing>>,
+    channels:    HashMap<String, HashSet<ConnectionId>>,
+}
+
+impl Hub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a connection, messages for it are received from the returned
+    /// channel.
+    pub fn register(
+        &self
+    ) -> (ConnectionId, mpsc::UnboundedReceiver<Outgoing>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.state().connections.insert(id, tx);
+        (id, rx)
+    }
+
+    /// Removes a connection from the hub and every channel it joined.
+    pub fn unregister(
+        &self,
+        id: ConnectionId,
+    ) {
+        let mut state = self.state();
+        state.connections.remove(&id);
+        state.channels.retain(|_, members| {
+            members.remove(&id);
+            !members.is_empty()
+        });
+    }
+
+    /// Whether the connection joined any channel.
+    pub fn joined(
+        &self,
+        id: ConnectionId,
+    ) -> bool {
+        let state = self.state();
+        state.channels.values().any(|members| members.contains(&id))
+    }
+
+    pub fn publish(
+        &self,
+        broadcast: Broadcast,
+    ) {
+        self.state().broadcast(broadcast);
+    }
+
+    /// Returns a publisher for pools, messages workers publish are
+    /// broadcast as they arrive.
+    pub fn publisher(self: &Arc<Self>) -> Publisher {
+        let (tx, mut rx): (Publisher, _) = mpsc::unbounded_channel();
+        let hub = self.clone();
+        tokio::spawn(async move {
+            while let Some(publish) = rx.recv().await {
+                match serde_json::from_slice(&publish.0) {
+                    Ok(broadcast) => hub.publish(broadcast),
+                    Err(err) => log::error!(
+                        "could not decode published message: {}",
+                        err
+                    ),
+                }
+            }
+        });
+        tx
+    }
+
+    pub fn apply(
+        &self,
+        commands: Commands,
+    ) {
+        let mut state = self.state();
+        for membership in commands.join {
+            state.join(membership.connection, membership.channel);
+        }
+        for membership in commands.leave {
+            if let Some(members) = state.channels.get_mut(&membership.channel)
+            {
+                members.remove(&membership.connection);
+                if members.is_empty() {
+                    state.channels.remove(&membership.channel);
+                }
+            }
+        }
+        for send in commands.send {
+            state.deliver(send.connection, Outgoing::Message {
+                event:  None,
+                data:   send.data,
+                binary: send.binary,
+            });
+        }
+        for broadcast in commands.broadcast {
+            state.broadcast(broadcast);
+        }
+        for id in commands.close {
+            state.deliver(id, Outgoing::Close);
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("hub lock is poisoned")
+    }
+}
+
+impl State {
+    fn join(
+        &mut self,
+        id: ConnectionId,
+        channel: String,
+    ) {
+        if self.connections.contains_key(&id) {
+            self.channels.entry(channel).or_default().insert(id);
+        }
+    }
+
+    fn broadcast(
+        &self,
+        broadcast: Broadcast,
+    ) {
+        let members = match self.channels.get(&broadcast.channel) {
+            Some(members) => members,
+            None => return,
+        };
+        for id in members {
+            self.deliver(*id, Outgoing::Message {
+                event:  broadcast.event.clone(),
+                data:   broadcast.data.clone(),
+                binary: broadcast.binary,
+            });
+        }
+    }
+
+    fn deliver(
+        &self,
+        id: ConnectionId,
+        message: Outgoing,
+    ) {
+        // Connections going away are unregistered by their own tasks.
+        if let Some(tx) = self.connections.get(&id) {
+            let _ = tx.send(message);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn commands(json: &str) -> Commands {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn message(data: &str) -> Outgoing {
+        Outgoing::Message {
+            event:  None,
+            data:   data.into(),
+            binary: false,
+        }
+    }
+
+    #[test]
+    fn sending_and_broadcasting() {
+        let hub = Hub::new();
+        let (a, mut a_rx) = hub.register();
+        let (b, mut b_rx) = hub.register();
+        assert_ne!(a, b);
+
+        hub.apply(commands(&format!(
+            r#"{{
+                "join": [
+                    {{"connection": {a}, "channel": "room"}},
+                    {{"connection": {b}, "channel": "room"}}
+                ],
+                "send": [{{"connection": {a}, "data": "hi a"}}],
+                "broadcast": [{{"channel": "room", "data": "hi all"}}]
+            }}"#,
+            a = a,
+            b = b
+        )));
+
+        assert_eq!(a_rx.try_recv(), Ok(message("hi a")));
+        assert_eq!(a_rx.try_recv(), Ok(message("hi all")));
+        assert_eq!(b_rx.try_recv(), Ok(message("hi all")));
+        assert!(b_rx.try_recv().is_err());
+
+        hub.unregister(a);
+        hub.apply(commands(&format!(
+            r#"{{
+                "broadcast": [{{"channel": "room", "data": "bye"}}],
+                "close": [{}]
+            }}"#,
+            b
+        )));
+        assert_eq!(b_rx.try_recv(), Ok(message("bye")));
+        assert_eq!(b_rx.try_recv(), Ok(Outgoing::Close));
+
+        hub.unregister(b);
+        assert!(hub.state().channels.is_empty());
+    }
+
+    #[tokio::test]
+    async fn publishing_through_the_relay() {
+        let hub = Arc::new(Hub::new());
+        let (id, mut rx) = hub.register();
+        hub.apply(commands(&format!(
+            r#"{{"join": [{{"connection": {}, "channel": "news"}}]}}"#,
+            id
+        )));
+        assert!(hub.joined(id));
+
+        let publisher = hub.publisher();
+        publisher
+            .send(r#"{"channel":"news","event":"post","data":"1"}"#.into())
+            .unwrap();
+
+        assert_eq!(
+            rx.recv().await,
+            Some(Outgoing::Message {
+                event:  Some("post".into()),
+                data:   "1".into(),
+                binary: false,
+            })
+        );
+    }
+}
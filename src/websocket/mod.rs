@@ -0,0 +1,615 @@
+use std::sync::Arc;
+
+use anyhow::{
+    anyhow,
+    bail,
+    Result,
+};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use hyper::upgrade::Upgraded;
+use hyper::{
+    header,
+    Body,
+    Method,
+    Request,
+    Response,
+    StatusCode,
+    Version,
+};
+use serde::Serialize;
+use tokio::io::{
+    AsyncRead,
+    AsyncWrite,
+    AsyncWriteExt,
+    BufReader,
+};
+use tokio::sync::mpsc;
+
+mod frame;
+mod hub;
+
+use frame::{
+    Frame,
+    Opcode,
+};
+pub use hub::{
+    Commands,
+    ConnectionId,
+    Hub,
+    Outgoing,
+};
+
+use crate::http::{
+    self,
+    PayloadTooLarge,
+    RequestEnvelope,
+    Router,
+    Upstream,
+};
+use crate::worker::pool::Overloaded;
+
+/// Appended to the client's key to compute `Sec-WebSocket-Accept`.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Normal closure status code.
+const CLOSE_NORMAL: u16 = 1000;
+
+/// Messages of a connection waiting for a worker before reading pauses.
+const EVENT_QUEUE_LEN: usize = 32;
+
+/// Event delivered to PHP workers, answered with [`Commands`].
+#[derive(Debug, Serialize)]
+struct EventEnvelope<'a> {
+    event:      &'a str,
+    connection: ConnectionId,
+    /// Upgrade request, sent with `open` events.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request:    Option<&'a RequestEnvelope>,
+    /// Message, base64 encoded if `binary`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data:       Option<String>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    binary:     bool,
+}
+
+/// Accepts WebSocket upgrades under a path and bridges their events to PHP
+/// workers, connections are held here so workers stay free.
+pub struct WebSocket {
+    /// Path prefix accepting upgrades.
+    path:             String,
+    /// Pool receiving events, routes are used if `None`.
+    upstream:         Option<Arc<Upstream>>,
+    hub:              Arc<Hub>,
+    /// Largest message accepted from clients.
+    max_message_size: usize,
+}
+
+impl WebSocket {
+    pub fn new(
+        path: String,
+        upstream: Option<Arc<Upstream>>,
+        hub: Arc<Hub>,
+        max_message_size: usize,
+    ) -> Self {
+        Self {
+            path,
+            upstream,
+            hub,
+            max_message_size,
+        }
+    }
+
+    /// Whether `req` asks for a WebSocket upgrade on this endpoint.
+    pub fn matches(
+        &self,
+        req: &Request<Body>,
+    ) -> bool {
+        let upgrade = req
+            .headers()
+            .get(header::UPGRADE)
+            .and_then(|upgrade| upgrade.to_str().ok())
+            .is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"));
+
+        upgrade && req.uri().path().starts_with(self.path.as_str())
+    }
+
+    /// Completes the handshake and serves the connection once hyper hands
+    /// it over.
+    pub async fn upgrade(
+        self: &Arc<Self>,
+        mut req: Request<Body>,
+        host: Option<&str>,
+        router: &Router,
+    ) -> Result<Response<Body>> {
+        let key = match handshake_key(&req) {
+            Some(key) => key,
+            None => return Ok(http::status(StatusCode::BAD_REQUEST)),
+        };
+        let (upstream, max_body_size) = match &self.upstream {
+            Some(upstream) => (upstream.clone(), upstream.max_body_size),
+            None => match router.route(host, req.uri().path()) {
+                Some(route) => (route.upstream.clone(), route.max_body_size()),
+                None => return Ok(http::status(StatusCode::NOT_FOUND)),
+            },
+        };
+
+        let on_upgrade = hyper::upgrade::on(&mut req);
+        let request = match RequestEnvelope::from_request_limited(
+            req,
+            max_body_size,
+            None,
+        )
+        .await
+        {
+            Ok(request) => request,
+            Err(err) if err.downcast_ref::<PayloadTooLarge>().is_some() => {
+                return Ok(http::status(StatusCode::PAYLOAD_TOO_LARGE));
+            }
+            Err(err) => return Err(err),
+        };
+        let websocket = self.clone();
+        tokio::spawn(async move {
+            match on_upgrade.await {
+                Ok(upgraded) => {
+                    websocket.serve(upgraded, &upstream, &request).await
+                }
+                Err(err) => log::debug!("WebSocket upgrade failed: {}", err),
+            }
+        });
+
+        let mut response = http::status(StatusCode::SWITCHING_PROTOCOLS);
+        let headers = response.headers_mut();
+        headers.insert(header::UPGRADE, "websocket".parse()?);
+        headers.insert(header::CONNECTION, "upgrade".parse()?);
+        headers.insert(header::SEC_WEBSOCKET_ACCEPT, accept_key(&key).parse()?);
+        Ok(response)
+    }
+
+    async fn serve(
+        &self,
+        upgraded: Upgraded,
+        upstream: &Upstream,
+        request: &RequestEnvelope,
+    ) {
+        let (id, outgoing) = self.hub.register();
+        let (reader, writer) = tokio::io::split(upgraded);
+        let (control_tx, control_rx) = mpsc::unbounded_channel();
+        let writer = tokio::spawn(write_frames(writer, outgoing, control_rx));
+
+        // Frames keep being read while workers handle events, so a slow
+        // worker doesn't hold up pings and closes.
+        let (events_tx, events_rx) = mpsc::channel(EVENT_QUEUE_LEN);
+        let reader = BufReader::new(reader);
+        let control = &control_tx;
+        let read = async move {
+            let result = self.read_frames(reader, id, &events_tx, control);
+            if let Err(err) = result.await {
+                log::debug!("WebSocket connection {} failed: {}", id, err);
+            }
+        };
+        tokio::join!(read, self.deliver(upstream, request, id, events_rx));
+
+        self.hub.unregister(id);
+        drop(control_tx);
+        let _ = writer.await;
+
+        self.event(upstream, EventEnvelope {
+            event:      "close",
+            connection: id,
+            request:    None,
+            data:       None,
+            binary:     false,
+        })
+        .await;
+    }
+
+    /// Delivers the `open` event, then queued events in order until the
+    /// connection's frames are read.
+    async fn deliver(
+        &self,
+        upstream: &Upstream,
+        request: &RequestEnvelope,
+        id: ConnectionId,
+        mut events: mpsc::Receiver<EventEnvelope<'static>>,
+    ) {
+        self.event(upstream, EventEnvelope {
+            event:      "open",
+            connection: id,
+            request:    Some(request),
+            data:       None,
+            binary:     false,
+        })
+        .await;
+
+        while let Some(event) = events.recv().await {
+            self.event(upstream, event).await;
+        }
+    }
+
+    async fn read_frames(
+        &self,
+        mut reader: impl AsyncRead + Unpin,
+        id: ConnectionId,
+        events: &mpsc::Sender<EventEnvelope<'static>>,
+        control: &mpsc::UnboundedSender<Frame>,
+    ) -> Result<()> {
+        let mut message: Option<(Opcode, Vec<u8>)> = None;
+
+        while let Some(frame) =
+            Frame::read_from(&mut reader, self.max_message_size).await?
+        {
+            match frame.opcode {
+                Opcode::Ping => {
+                    let pong = Frame::new(Opcode::Pong, frame.payload);
+                    let _ = control.send(pong);
+                    continue;
+                }
+                Opcode::Pong => continue,
+                Opcode::Close => {
+                    // Echo the status code back to complete the closing
+                    // handshake.
+                    let code = frame.payload.get(..2).unwrap_or_default();
+                    let close = Frame::new(Opcode::Close, code.to_vec());
+                    let _ = control.send(close);
+                    return Ok(());
+                }
+                Opcode::Continuation => {
+                    let (_, buf) = message
+                        .as_mut()
+                        .ok_or_else(|| anyhow!("unexpected continuation"))?;
+                    if buf.len() + frame.payload.len() > self.max_message_size {
+                        bail!("message exceeds the limit");
+                    }
+                    buf.extend(frame.payload);
+                }
+                Opcode::Text | Opcode::Binary => {
+                    if message.is_some() {
+                        bail!("expected a continuation frame");
+                    }
+                    message = Some((frame.opcode, frame.payload));
+                }
+            }
+
+            if !frame.fin {
+                continue;
+            }
+            let (data, binary) = match message.take() {
+                Some((Opcode::Text, payload)) => {
+                    (String::from_utf8(payload)?, false)
+                }
+                Some((_, payload)) => (BASE64.encode(payload), true),
+                None => continue,
+            };
+            let event = EventEnvelope {
+                event: "message",
+                connection: id,
+                request: None,
+                data: Some(data),
+                binary,
+            };
+            events
+                .send(event)
+                .await
+                .map_err(|_| anyhow!("event delivery stopped"))?;
+        }
+
+        Ok(())
+    }
+
+    /// Delivers `event` to a worker and applies the returned commands.
+    async fn event(
+        &self,
+        upstream: &Upstream,
+        event: EventEnvelope<'_>,
+    ) {
+        let result = async {
+            let req = serde_json::to_vec(&event)?;
+            let response = upstream.pool.exec(req.into()).await?;
+            let commands = serde_json::from_slice::<Commands>(&response.0)
+                .map_err(|err| anyhow!("could not decode commands: {}", err))?;
+            self.hub.apply(commands);
+            Ok::<_, anyhow::Error>(())
+        };
+
+        if let Err(err) = result.await {
+            match err.downcast_ref::<Overloaded>() {
+                Some(reason) => log::warn!(
+                    "dropping WebSocket {} event for pool {}: {}",
+                    event.event,
+                    upstream.name,
+                    reason
+                ),
+                None => log::error!(
+                    "WebSocket {} event for connection {} failed: {}",
+                    event.event,
+                    event.connection,
+                    err
+                ),
+            }
+        }
+    }
+}
+
+/// Writes messages from the hub and control frames until the connection
+/// is closed.
+async fn write_frames(
+    mut writer: impl AsyncWrite + Unpin,
+    mut outgoing: mpsc::UnboundedReceiver<Outgoing>,
+    mut control: mpsc::UnboundedReceiver<Frame>,
+) -> Result<()> {
+    loop {
+        let frame = tokio::select! {
+            Some(frame) = control.recv() => frame,
+            Some(message) = outgoing.recv() => match message {
+                Outgoing::Message { data, binary: false, .. } => {
+                    Frame::new(Opcode::Text, data.into_bytes())
+                }
+                Outgoing::Message { data, binary: true, .. } => {
+                    match BASE64.decode(&data) {
+                        Ok(payload) => Frame::new(Opcode::Binary, payload),
+                        Err(err) => {
+                            log::error!(
+                                "could not decode binary WebSocket message: {}",
+                                err
+                            );
+                            continue;
+                        }
+                    }
+                }
+                Outgoing::Close => Frame::new(
+                    Opcode::Close,
+                    CLOSE_NORMAL.to_be_bytes().to_vec(),
+                ),
+            },
+            else => break,
+        };
+
+        let close = frame.opcode == Opcode::Close;
+        frame.write_to(&mut writer).await?;
+        writer.flush().await?;
+        if close {
+            break;
+        }
+    }
+
+    writer.shutdown().await?;
+    Ok(())
+}
+
+/// The client's key if `req` is a valid opening handshake.
+fn handshake_key(req: &Request<Body>) -> Option<String> {
+    let headers = req.headers();
+    let connection_upgrade = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("upgrade"));
+    let version = headers
+        .get(header::SEC_WEBSOCKET_VERSION)
+        .is_some_and(|version| version == "13");
+
+    if req.method() != Method::GET ||
+        req.version() != Version::HTTP_11 ||
+        !connection_upgrade ||
+        !version
+    {
+        return None;
+    }
+
+    headers
+        .get(header::SEC_WEBSOCKET_KEY)
+        .and_then(|key| key.to_str().ok())
+        .map(String::from)
+}
+
+fn accept_key(key: &str) -> String {
+    let digest = ring::digest::digest(
+        &ring::digest::SHA1_FOR_LEGACY_USE_ONLY,
+        format!("{}{}", key, GUID).as_bytes(),
+    );
+    BASE64.encode(digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::AsyncReadExt;
+    use tokio::net::{
+        TcpListener,
+        TcpStream,
+    };
+    use tokio::time::timeout;
+
+    use super::frame::tests::client_frame;
+    use super::*;
+    use crate::http::Handler;
+    use crate::server::{
+        self,
+        Http2,
+    };
+    use crate::worker::pool::tests::Stub;
+
+    /// Joins connections to a room on open and broadcasts their messages.
+    async fn chat(event: serde_json::Value) -> Result<serde_json::Value> {
+        let connection = &event["connection"];
+        let commands = match event["event"].as_str() {
+            Some("open") => serde_json::json!({
+                "join": [{ "connection": connection, "channel": "room" }],
+                "send": [{
+                    "connection": connection,
+                    "data": event["request"]["uri"],
+                }],
+            }),
+            Some("message") => {
+                if event["data"] == "slow" {
+                    tokio::time::sleep(Duration::from_millis(300)).await;
+                }
+                let binary = event["binary"].as_bool().unwrap_or(false);
+                serde_json::json!({
+                    "broadcast": [{
+                        "channel": "room",
+                        "data": event["data"],
+                        "binary": binary,
+                    }],
+                })
+            }
+            _ => serde_json::json!({}),
+        };
+        Ok(commands)
+    }
+
+    async fn connect(addr: std::net::SocketAddr) -> Result<TcpStream> {
+        let mut stream = TcpStream::connect(addr).await?;
+        stream
+            .write_all(
+                b"GET /ws/chat HTTP/1.1\r\nHost: localhost\r\nUpgrade: \
+                  websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: \
+                  dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: \
+                  13\r\n\r\n",
+            )
+            .await?;
+
+        // Byte by byte so frames following the response stay unread.
+        let mut head = vec![];
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(stream.read_u8().await?);
+        }
+        let head = String::from_utf8(head)?;
+        assert!(head.starts_with("HTTP/1.1 101"), "{}", head);
+        assert!(head
+            .to_lowercase()
+            .contains("sec-websocket-accept: s3pplmbitxaq9kygzzhzrbk+xoo="));
+
+        Ok(stream)
+    }
+
+    /// Reads a frame sent by the server, returns its first byte and
+    /// payload.
+    async fn receive_frame(stream: &mut TcpStream) -> Result<(u8, Vec<u8>)> {
+        let mut header = [0u8; 2];
+        stream.read_exact(&mut header).await?;
+        let mut payload = vec![0; header[1] as usize];
+        stream.read_exact(&mut payload).await?;
+        Ok((header[0], payload))
+    }
+
+    /// Reads a text frame sent by the server.
+    async fn receive(stream: &mut TcpStream) -> Result<String> {
+        let (first, payload) = receive_frame(stream).await?;
+        assert_eq!(first, 0x81);
+        Ok(String::from_utf8(payload)?)
+    }
+
+    #[test]
+    fn computing_accept_keys() {
+        // Example from RFC 6455.
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[tokio::test]
+    async fn rejecting_large_upgrade_requests() -> Result<()> {
+        let hub = Arc::new(Hub::new());
+        let upstream = Arc::new(Upstream {
+            name:          "chat".into(),
+            pool:          Arc::new(Stub(chat)),
+            retry_after:   1,
+            hub:           hub.clone(),
+            max_body_size: Some(4),
+            upload_dir:    None,
+        });
+        let websocket = Arc::new(WebSocket::new(
+            "/ws/".into(),
+            Some(upstream),
+            hub,
+            1024,
+        ));
+
+        let req = Request::get("/ws/chat")
+            .header(header::CONNECTION, "upgrade")
+            .header(header::UPGRADE, "websocket")
+            .header(header::SEC_WEBSOCKET_VERSION, "13")
+            .header(header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==")
+            .body(Body::from("hello"))?;
+        let response =
+            websocket.upgrade(req, None, &Router::new(vec![])).await?;
+
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bridging_events_to_workers() -> Result<()> {
+        let hub = Arc::new(Hub::new());
+        let upstream = Arc::new(Upstream {
+            name:          "chat".into(),
+            pool:          Arc::new(Stub(chat)),
+            retry_after:   1,
+            hub:           hub.clone(),
+            max_body_size: None,
+            upload_dir:    None,
+        });
+        let websocket =
+            WebSocket::new("/ws/".into(), Some(upstream), hub.clone(), 1024);
+        let handler = Arc::new(Handler {
+            router:       Router::new(vec![]),
+            static_files: None,
+            compression:  None,
+            websocket:    Some(Arc::new(websocket)),
+            events:       None,
+            access_log:   None,
+            tracer:       None,
+            health:       None,
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let http2 = Http2 {
+            enabled:                false,
+            h2c:                    false,
+            max_concurrent_streams: None,
+        };
+        tokio::spawn(server::serve_http(listener, handler, http2));
+
+        let mut alice = connect(addr).await?;
+        assert_eq!(receive(&mut alice).await?, "/ws/chat");
+        let mut bob = connect(addr).await?;
+        assert_eq!(receive(&mut bob).await?, "/ws/chat");
+
+        alice.write_all(&client_frame(Opcode::Text, b"hello")).await?;
+        assert_eq!(receive(&mut alice).await?, "hello");
+        assert_eq!(receive(&mut bob).await?, "hello");
+
+        // Pings are answered without involving workers.
+        bob.write_all(&client_frame(Opcode::Ping, b"p")).await?;
+        let mut pong = [0u8; 3];
+        bob.read_exact(&mut pong).await?;
+        assert_eq!(pong, [0x8a, 1, b'p']);
+
+        // Even while a worker is busy with an earlier message.
+        alice.write_all(&client_frame(Opcode::Text, b"slow")).await?;
+        alice.write_all(&client_frame(Opcode::Ping, b"p")).await?;
+        let pong = receive_frame(&mut alice);
+        let pong = timeout(Duration::from_millis(200), pong).await??;
+        assert_eq!(pong, (0x8a, b"p".to_vec()));
+        assert_eq!(receive(&mut alice).await?, "slow");
+        assert_eq!(receive(&mut bob).await?, "slow");
+
+        alice.write_all(&client_frame(Opcode::Binary, &[0, 255])).await?;
+        assert_eq!(receive_frame(&mut bob).await?, (0x82, vec![0, 255]));
+        assert_eq!(receive_frame(&mut alice).await?, (0x82, vec![0, 255]));
+
+        alice.write_all(&client_frame(Opcode::Close, &[0x03, 0xe8])).await?;
+        let mut close = [0u8; 4];
+        alice.read_exact(&mut close).await?;
+        assert_eq!(close, [0x88, 2, 0x03, 0xe8]);
+
+        Ok(())
+    }
+}
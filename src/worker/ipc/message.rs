@@ -21,6 +21,10 @@ enum MessageType {
     Identity,
     Request,
     Response,
+    Publish,
+    Ping,
+    Pong,
+    Stop,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -32,6 +36,12 @@ impl From<&str> for Request {
     }
 }
 
+impl From<Vec<u8>> for Request {
+    fn from(req: Vec<u8>) -> Self {
+        Self(req)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Response(pub Vec<u8>);
 
@@ -41,11 +51,27 @@ impl From<&str> for Response {
     }
 }
 
+/// Message a worker publishes to a channel while handling a request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Publish(pub Vec<u8>);
+
+impl From<&str> for Publish {
+    fn from(publish: &str) -> Self {
+        Self(publish.as_bytes().to_vec())
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Message {
     Identity(Pid),
     Request(Request),
     Response(Response),
+    Publish(Publish),
+    /// Checks an idle worker is still responsive.
+    Ping,
+    Pong,
+    /// Asks an idle worker to clean up and exit.
+    Stop,
 }
 
 impl Message {
@@ -69,6 +95,18 @@ impl Message {
             Message::Response(buf) => {
                 write_u8_vec(&mut dst, MessageType::Response, buf.0).await?;
             }
+            Message::Publish(buf) => {
+                write_u8_vec(&mut dst, MessageType::Publish, buf.0).await?;
+            }
+            Message::Ping => {
+                write_u8_vec(&mut dst, MessageType::Ping, vec![]).await?;
+            }
+            Message::Pong => {
+                write_u8_vec(&mut dst, MessageType::Pong, vec![]).await?;
+            }
+            Message::Stop => {
+                write_u8_vec(&mut dst, MessageType::Stop, vec![]).await?;
+            }
         };
 
         dst.flush().await?;
@@ -116,6 +154,13 @@ impl Message {
                 .await
                 .map(Response)
                 .map(Message::Response),
+            MessageType::Publish => read_u8_vec(size, src)
+                .await
+                .map(Publish)
+                .map(Message::Publish),
+            MessageType::Ping => Ok(Message::Ping),
+            MessageType::Pong => Ok(Message::Pong),
+            MessageType::Stop => Ok(Message::Stop),
         };
 
         async fn read_u8_vec(
@@ -155,5 +200,9 @@ mod tests {
         identity: Message::Identity(42),
         request: Message::Request("hello world req".into()),
         response: Message::Response("hello world res".into()),
+        publish: Message::Publish("hello world pub".into()),
+        ping: Message::Ping,
+        pong: Message::Pong,
+        stop: Message::Stop,
     }
 }
@@ -1,8 +1,7 @@
-mod message;
+pub(super) mod message;
 mod unix;
 
 pub use message::{
-    Message,
     Pid,
     Request,
     Response,
@@ -10,4 +9,5 @@ pub use message::{
 pub use unix::{
     listen,
     Connection,
+    Publisher,
 };
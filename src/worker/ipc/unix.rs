@@ -22,10 +22,14 @@ use tokio_stream::{
 use super::message::{
     Message,
     Pid,
+    Publish,
     Request,
     Response,
 };
 
+/// Receives messages workers publish while handling requests.
+pub type Publisher = mpsc::UnboundedSender<Publish>;
+
 #[derive(Debug)]
 pub struct Connection {
     pid:    Pid,
@@ -52,17 +56,44 @@ impl Connection {
         self.pid
     }
 
+    /// Sends `req` and waits for its response, messages published in the
+    /// meantime are forwarded to `publisher`.
     pub async fn round_trip(
         &mut self,
         req: Request,
+        publisher: Option<&Publisher>,
     ) -> Result<Response> {
         Message::Request(req).write_to(&mut self.stream).await?;
 
+        loop {
+            match Message::read_from(&mut self.stream).await? {
+                Message::Response(response) => return Ok(response),
+                Message::Publish(publish) => match publisher {
+                    Some(publisher) => {
+                        let _ = publisher.send(publish);
+                    }
+                    None => {
+                        log::warn!("dropping message published by {}", self.pid)
+                    }
+                },
+                message => bail!("unexpected message: {:?}", message),
+            }
+        }
+    }
+
+    /// Sends a ping and waits for the pong.
+    pub async fn ping(&mut self) -> Result<()> {
+        Message::Ping.write_to(&mut self.stream).await?;
         match Message::read_from(&mut self.stream).await? {
-            Message::Response(response) => Ok(response),
-            message => bail!("unexpected message: {:?}", message),
+            Message::Pong => Ok(()),
+            message => bail!("expected pong got {:?}", message),
         }
     }
+
+    /// Asks the worker to exit, it isn't usable afterwards.
+    pub async fn stop(&mut self) -> Result<()> {
+        Message::Stop.write_to(&mut self.stream).await
+    }
 }
 
 pub fn listen(path: &str) -> Result<impl Stream<Item = Connection> + Unpin> {
@@ -144,16 +175,41 @@ mod tests {
             .unwrap();
             assert_eq!(req, Message::Request("hello world req".into()));
 
+            Message::Publish("hello world pub".into())
+                .write_to(&mut client)
+                .await
+                .unwrap();
             Message::Response("hello world res".into())
                 .write_to(&mut client)
                 .await
                 .unwrap();
         });
 
-        let response =
-            conn.round_trip(Request("hello world req".into())).await?;
+        let (publisher, mut published) = mpsc::unbounded_channel();
+        let response = conn
+            .round_trip(Request("hello world req".into()), Some(&publisher))
+            .await?;
         assert_eq!(response, Response("hello world res".into()));
+        assert_eq!(published.try_recv()?, Publish("hello world pub".into()));
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn pinging_workers() -> Result<()> {
+        let socket = "/tmp/coyote.test.sock.16";
+        let mut connections = listen(socket)?;
+
+        let mut client = UnixStream::connect(socket).await?;
+        Message::Identity(42).write_to(&mut client).await?;
+        let mut conn = connections.next().await.unwrap();
+
+        tokio::spawn(async move {
+            let ping = Message::read_from(&mut client).await.unwrap();
+            assert_eq!(ping, Message::Ping);
+            Message::Pong.write_to(&mut client).await.unwrap();
+        });
+
+        conn.ping().await
+    }
 }
@@ -0,0 +1,428 @@
+use std::ffi::CString;
+use std::fs::{
+    self,
+    File,
+    OpenOptions,
+};
+use std::io;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::ptr;
+use std::sync::atomic::{
+    AtomicU64,
+    Ordering,
+};
+use std::sync::Arc;
+
+use anyhow::{
+    anyhow,
+    bail,
+    Result,
+};
+use tokio::process::Command;
+
+/// Period `cpu.max` quotas are expressed in, in microseconds.
+const CPU_PERIOD: u64 = 100_000;
+
+/// How worker processes of a pool are confined.
+#[derive(Debug, Clone, Default)]
+pub struct Isolation {
+    /// Runs workers as another user, coyote's own otherwise.
+    pub credentials: Option<Credentials>,
+    pub limits:      Limits,
+    /// cgroup workers are placed in, coyote's own otherwise.
+    pub cgroup:      Option<Arc<Cgroup>>,
+}
+
+impl Isolation {
+    /// Makes processes spawned by `command` join the cgroup, apply the
+    /// limits and switch credentials, in that order as joining a cgroup and
+    /// raising limits may need privileges dropped afterwards.
+    pub fn apply(
+        &self,
+        command: &mut Command,
+    ) -> Result<()> {
+        let procs = match &self.cgroup {
+            Some(cgroup) => Some(cgroup.procs()?),
+            None => None,
+        };
+        let limits = self.limits;
+        let credentials = self.credentials;
+
+        // SAFETY: the closure only makes async-signal-safe system calls.
+        unsafe {
+            command.pre_exec(move || {
+                if let Some(procs) = &procs {
+                    // Writing 0 moves the writing process.
+                    let written = libc::write(
+                        procs.as_raw_fd(),
+                        b"0".as_ptr().cast(),
+                        1,
+                    );
+                    if written != 1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                limits.apply()?;
+                if let Some(credentials) = credentials {
+                    credentials.apply()?;
+                }
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+}
+
+/// `setrlimit` limits of each worker process.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Limits {
+    /// Bytes of virtual memory.
+    pub address_space: Option<u64>,
+    /// Seconds of CPU time over the worker's lifetime, `SIGXCPU` is sent
+    /// once exceeded and `SIGKILL` a second later.
+    pub cpu_time:      Option<u64>,
+    pub open_files:    Option<u64>,
+    /// Largest core dump in bytes, 0 disables core dumps.
+    pub core_size:     Option<u64>,
+}
+
+impl Limits {
+    /// Applies the limits to the calling process.
+    fn apply(&self) -> io::Result<()> {
+        let cpu_time = self.cpu_time.map(|secs| (secs, secs + 1));
+        let same = |limit: Option<u64>| limit.map(|limit| (limit, limit));
+        let limits = [
+            (libc::RLIMIT_AS, same(self.address_space)),
+            (libc::RLIMIT_CPU, cpu_time),
+            (libc::RLIMIT_NOFILE, same(self.open_files)),
+            (libc::RLIMIT_CORE, same(self.core_size)),
+        ];
+        for (resource, limit) in limits.iter() {
+            if let Some((soft, hard)) = limit {
+                let limit = libc::rlimit {
+                    rlim_cur: *soft,
+                    rlim_max: *hard,
+                };
+                // SAFETY: `limit` outlives the call.
+                if unsafe { libc::setrlimit(*resource, &limit) } != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// cgroup v2 directory the workers of a pool are placed in.
+#[derive(Debug)]
+pub struct Cgroup {
+    path:      PathBuf,
+    /// OOM kills attributed to workers so far.
+    oom_kills: AtomicU64,
+}
+
+impl Cgroup {
+    /// Creates the cgroup at `path` unless it exists, limiting its memory to
+    /// `memory_max` bytes and its CPU usage to `cpu_max` CPUs. Controllers
+    /// needed for the limits are enabled in the parent cgroups.
+    pub fn create(
+        path: &Path,
+        memory_max: Option<u64>,
+        cpu_max: Option<f64>,
+    ) -> Result<Self> {
+        let mut controllers = vec![];
+        if memory_max.is_some() {
+            controllers.push("+memory");
+        }
+        if cpu_max.is_some() {
+            controllers.push("+cpu");
+        }
+
+        let mut missing = path
+            .ancestors()
+            .take_while(|dir| !dir.exists())
+            .collect::<Vec<_>>();
+        missing.reverse();
+        if missing.is_empty() {
+            missing.push(path);
+        }
+        for dir in missing {
+            let parent = dir
+                .parent()
+                .ok_or_else(|| anyhow!("invalid cgroup: {}", path.display()))?;
+            if !controllers.is_empty() {
+                let control = parent.join("cgroup.subtree_control");
+                fs::write(&control, controllers.join(" ")).map_err(|err| {
+                    anyhow!("could not enable {}: {}", control.display(), err)
+                })?;
+            }
+            if !dir.exists() {
+                fs::create_dir(dir).map_err(|err| {
+                    let dir = dir.display();
+                    anyhow!("could not create cgroup {}: {}", dir, err)
+                })?;
+            }
+        }
+
+        let cgroup = Self {
+            path:      path.to_path_buf(),
+            oom_kills: AtomicU64::new(0),
+        };
+        if let Some(bytes) = memory_max {
+            cgroup.write("memory.max", &bytes.to_string())?;
+        }
+        if let Some(cpus) = cpu_max {
+            let quota = (cpus * CPU_PERIOD as f64) as u64;
+            cgroup.write("cpu.max", &format!("{} {}", quota, CPU_PERIOD))?;
+        }
+        cgroup.oom_kills.store(cgroup.read_oom_kills(), Ordering::SeqCst);
+        Ok(cgroup)
+    }
+
+    fn write(
+        &self,
+        file: &str,
+        value: &str,
+    ) -> Result<()> {
+        let path = self.path.join(file);
+        fs::write(&path, value).map_err(|err| {
+            anyhow!("could not write {}: {}", path.display(), err)
+        })
+    }
+
+    /// `cgroup.procs`, writing to it moves processes into the cgroup.
+    fn procs(&self) -> Result<File> {
+        let path = self.path.join("cgroup.procs");
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .map_err(|err| {
+                anyhow!("could not open {}: {}", path.display(), err)
+            })
+    }
+
+    /// Whether a process was killed for exceeding the memory limit that no
+    /// earlier call accounted for. Each call claims one kill, so workers
+    /// killed together are all reported.
+    pub fn oom_killed(&self) -> bool {
+        let kills = self.read_oom_kills();
+        self.oom_kills
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |seen| {
+                (seen < kills).then_some(seen + 1)
+            })
+            .is_ok()
+    }
+
+    fn read_oom_kills(&self) -> u64 {
+        let events = fs::read_to_string(self.path.join("memory.events"));
+        events
+            .unwrap_or_default()
+            .lines()
+            .find_map(|line| line.strip_prefix("oom_kill "))
+            .and_then(|kills| kills.trim().parse().ok())
+            .unwrap_or_default()
+    }
+}
+
+/// User and group workers run as.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Credentials {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Credentials {
+    /// Looks up `user` and `group` by name or id, the user's primary group
+    /// is used if `group` is omitted and coyote's own user if `user` is.
+    pub fn lookup(
+        user: Option<&str>,
+        group: Option<&str>,
+    ) -> Result<Option<Self>> {
+        let (uid, primary_gid) = match user {
+            Some(user) => lookup_user(user)?,
+            None if group.is_some() => {
+                // SAFETY: getuid can't fail.
+                (unsafe { libc::getuid() }, None)
+            }
+            None => return Ok(None),
+        };
+        let gid = match (group, primary_gid) {
+            (Some(group), _) => lookup_group(group)?,
+            (None, Some(gid)) => gid,
+            (None, None) => {
+                bail!("user {} has no primary group, set a group", uid)
+            }
+        };
+
+        Ok(Some(Self { uid, gid }))
+    }
+
+    /// Fails unless coyote may start processes with these credentials,
+    /// only root may switch to another user or group.
+    pub fn check(&self) -> Result<()> {
+        // SAFETY: geteuid and getegid can't fail.
+        let (euid, egid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        if euid != 0 && (self.uid != euid || self.gid != egid) {
+            bail!(
+                "running workers as {}:{} requires root, coyote runs as {}:{}",
+                self.uid,
+                self.gid,
+                euid,
+                egid
+            );
+        }
+        Ok(())
+    }
+
+    /// Switches the calling process to these credentials, dropping
+    /// supplementary groups when running as root.
+    fn apply(&self) -> io::Result<()> {
+        // SAFETY: these calls only change process credentials.
+        unsafe {
+            if libc::geteuid() == 0 && libc::setgroups(0, ptr::null()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if libc::setgid(self.gid) != 0 || libc::setuid(self.uid) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Uid and primary gid of `user`, numeric users without a passwd entry
+/// have no primary group.
+fn lookup_user(user: &str) -> Result<(u32, Option<u32>)> {
+    // SAFETY: passwd is plain data, getpwnam_r and getpwuid_r only write
+    // strings to `buf`, which outlives their use.
+    let mut passwd = unsafe { mem::zeroed::<libc::passwd>() };
+    let mut buf = vec![0 as libc::c_char; 16 * 1024];
+    let mut found = ptr::null_mut();
+    let code = match user.parse::<u32>() {
+        Ok(uid) => unsafe {
+            libc::getpwuid_r(
+                uid,
+                &mut passwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut found,
+            )
+        },
+        Err(_) => {
+            let name = CString::new(user)?;
+            unsafe {
+                libc::getpwnam_r(
+                    name.as_ptr(),
+                    &mut passwd,
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut found,
+                )
+            }
+        }
+    };
+    if code != 0 {
+        let err = io::Error::from_raw_os_error(code);
+        bail!("could not look up user {}: {}", user, err);
+    }
+
+    match (found.is_null(), user.parse::<u32>()) {
+        (false, _) => Ok((passwd.pw_uid, Some(passwd.pw_gid))),
+        (true, Ok(uid)) => Ok((uid, None)),
+        (true, Err(_)) => Err(anyhow!("unknown user: {}", user)),
+    }
+}
+
+fn lookup_group(group: &str) -> Result<u32> {
+    if let Ok(gid) = group.parse::<u32>() {
+        return Ok(gid);
+    }
+
+    // SAFETY: see `lookup_user`.
+    let mut entry = unsafe { mem::zeroed::<libc::group>() };
+    let mut buf = vec![0 as libc::c_char; 16 * 1024];
+    let mut found = ptr::null_mut();
+    let name = CString::new(group)?;
+    let code = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut entry,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut found,
+        )
+    };
+    if code != 0 {
+        let err = io::Error::from_raw_os_error(code);
+        bail!("could not look up group {}: {}", group, err);
+    }
+    if found.is_null() {
+        bail!("unknown group: {}", group);
+    }
+
+    Ok(entry.gr_gid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn looking_up_credentials() -> Result<()> {
+        let root = Some(Credentials { uid: 0, gid: 0 });
+        assert_eq!(Credentials::lookup(None, None)?, None);
+        assert_eq!(Credentials::lookup(Some("root"), None)?, root);
+        assert_eq!(Credentials::lookup(Some("0"), Some("root"))?, root);
+        assert_eq!(
+            Credentials::lookup(Some("4242"), Some("4343"))?,
+            Some(Credentials {
+                uid: 4242,
+                gid: 4343,
+            })
+        );
+
+        let err = Credentials::lookup(Some("no-such-user"), None);
+        assert_eq!(err.unwrap_err().to_string(), "unknown user: no-such-user");
+        let err = Credentials::lookup(Some("root"), Some("no-such-group"));
+        assert_eq!(
+            err.unwrap_err().to_string(),
+            "unknown group: no-such-group"
+        );
+
+        // SAFETY: geteuid and getegid can't fail.
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        Credentials { uid, gid }.check()
+    }
+
+    #[test]
+    fn creating_cgroups() -> Result<()> {
+        let root = tempfile::tempdir()?;
+        let path = root.path().join("coyote/app");
+        let cgroup = Cgroup::create(&path, Some(256 << 20), Some(1.5))?;
+
+        let read = |path: PathBuf| fs::read_to_string(path).unwrap();
+        let control = "cgroup.subtree_control";
+        assert_eq!(read(root.path().join(control)), "+memory +cpu");
+        let coyote = root.path().join("coyote");
+        assert_eq!(read(coyote.join(control)), "+memory +cpu");
+        assert_eq!(read(path.join("memory.max")), "268435456");
+        assert_eq!(read(path.join("cpu.max")), "150000 100000");
+
+        assert!(!cgroup.oom_killed());
+        fs::write(path.join("memory.events"), "oom 1\noom_kill 1\n")?;
+        assert!(cgroup.oom_killed());
+        assert!(!cgroup.oom_killed());
+        // Two workers killed before either checked.
+        fs::write(path.join("memory.events"), "oom 3\noom_kill 3\n")?;
+        assert!(cgroup.oom_killed());
+        assert!(cgroup.oom_killed());
+        assert!(!cgroup.oom_killed());
+
+        Ok(())
+    }
+}
@@ -100,10 +100,8 @@ mod tests {
     };
 
     use super::*;
-    use crate::worker::ipc::{
-        listen,
-        Message,
-    };
+    use crate::worker::ipc::listen;
+    use crate::worker::ipc::message::Message;
 
     #[tokio::test]
     async fn waiting_connection_with_pid() -> Result<()> {
@@ -1,8 +1,15 @@
-mod ipc;
+pub mod ipc;
+mod isolation;
 mod linker;
 pub mod pool;
 #[allow(clippy::module_inception)]
 mod worker;
 
+pub use isolation::{
+    Cgroup,
+    Credentials,
+    Isolation,
+    Limits,
+};
 pub use linker::Linker;
 pub use worker::Worker;
@@ -1,18 +1,177 @@
+use std::fmt;
+use std::time::Duration;
+
 use anyhow::Result;
 use async_trait::async_trait;
+use serde::{
+    Deserialize,
+    Serialize,
+};
 
 mod static_;
 
 use super::ipc::{
+    Pid,
     Request,
     Response,
 };
 pub use static_::Static;
 
 #[async_trait]
-pub trait Pool {
+pub trait Pool: Send + Sync {
     async fn exec(
         &self,
         req: Request,
     ) -> Result<Response>;
+
+    /// Like [`Pool::exec`], also reporting how the request was executed.
+    async fn exec_traced(
+        &self,
+        req: Request,
+    ) -> Result<(Response, Execution)> {
+        Ok((self.exec(req).await?, Execution::default()))
+    }
+
+    /// Number of requests waiting for a free worker.
+    fn queue_len(&self) -> usize;
+}
+
+/// How a request was executed, for logging and tracing.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Execution {
+    /// Worker that handled the request.
+    pub pid:        Option<Pid>,
+    /// Time spent waiting for a free worker.
+    pub queue_time: Duration,
+    /// Time the worker spent on the request.
+    pub exec_time:  Duration,
+}
+
+/// State of a pool, as reported by the admin API.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PoolStatus {
+    /// Number of workers kept running.
+    pub size:      usize,
+    pub queue_len: usize,
+    pub workers:   Vec<WorkerStatus>,
+    /// Workers keep failing to start, requests are rejected.
+    #[serde(default)]
+    pub failing:   bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkerStatus {
+    pub pid:      Pid,
+    pub state:    WorkerState,
+    /// Seconds since the worker started.
+    pub uptime:   u64,
+    /// Requests served so far.
+    pub requests: u64,
+    /// Resident memory in bytes, if known.
+    pub memory:   Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WorkerState {
+    Idle,
+    Busy,
+    /// Replaced once its current request is done.
+    Retiring,
+}
+
+/// Limits for requests waiting for a free worker.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QueueLimits {
+    /// Maximum number of waiting requests, unbounded if `None`.
+    pub max_len:  Option<usize>,
+    /// Maximum time a request waits for a worker, unbounded if `None`.
+    pub max_wait: Option<Duration>,
+}
+
+/// Delays between attempts to start a worker after failed ones.
+#[derive(Debug, Clone, Copy)]
+pub struct SpawnBackoff {
+    /// Delay after the first failure, doubled after each further one.
+    pub initial:      Duration,
+    pub max:          Duration,
+    /// Consecutive failures after which requests are rejected until a
+    /// worker starts again.
+    pub max_failures: u32,
+}
+
+impl Default for SpawnBackoff {
+    fn default() -> Self {
+        Self {
+            initial:      Duration::from_millis(100),
+            max:          Duration::from_secs(30),
+            max_failures: 5,
+        }
+    }
+}
+
+/// Returned when a request is rejected because of [`QueueLimits`], or
+/// because the pool can't start workers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Overloaded {
+    QueueFull,
+    QueueTimeout,
+    /// Reached [`SpawnBackoff::max_failures`].
+    Failing,
+}
+
+impl fmt::Display for Overloaded {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        match self {
+            Overloaded::QueueFull => write!(f, "worker queue is full"),
+            Overloaded::QueueTimeout => {
+                write!(f, "timed out waiting for a free worker")
+            }
+            Overloaded::Failing => {
+                write!(f, "workers keep failing to start")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Overloaded {}
+
+#[cfg(test)]
+pub mod tests {
+    use std::future::Future;
+
+    use serde_json::Value;
+
+    use super::*;
+
+    /// Answers JSON requests with `F`'s result, never queueing.
+    pub struct Stub<F>(pub F);
+
+    #[async_trait]
+    impl<F, Fut> Pool for Stub<F>
+    where
+        F: Fn(Value) -> Fut + Send + Sync,
+        Fut: Future<Output = Result<Value>> + Send,
+    {
+        async fn exec(
+            &self,
+            req: Request,
+        ) -> Result<Response> {
+            let req = serde_json::from_slice(&req.0)?;
+            let response = (self.0)(req).await?;
+            Ok(Response(serde_json::to_vec(&response)?))
+        }
+
+        fn queue_len(&self) -> usize {
+            0
+        }
+    }
+
+    /// Answers every request with an empty object.
+    pub async fn noop(_req: Value) -> Result<Value> {
+        Ok(serde_json::json!({}))
+    }
 }
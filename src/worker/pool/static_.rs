@@ -1,63 +1,685 @@
+use std::collections::BTreeMap;
+use std::ops::{
+    Deref,
+    DerefMut,
+};
+use std::sync::atomic::{
+    AtomicUsize,
+    Ordering,
+};
+use std::sync::{
+    Arc,
+    Mutex,
+    MutexGuard,
+};
+use std::time::{
+    Duration,
+    Instant,
+};
+
 use anyhow::{
     anyhow,
     Result,
 };
 use async_trait::async_trait;
+use crossbeam_queue::SegQueue;
 use futures::future::join_all;
-use log::error;
 use tokio::sync::{
-    mpsc,
-    Mutex,
+    Notify,
+    Semaphore,
+    SemaphorePermit,
 };
+use tokio::time::timeout;
+use tracing::Instrument;
 
-use super::Pool;
+use super::{
+    Execution,
+    Overloaded,
+    Pool,
+    PoolStatus,
+    QueueLimits,
+    SpawnBackoff,
+    WorkerState,
+    WorkerStatus,
+};
 use crate::worker::{
     ipc::{
         listen,
+        Pid,
+        Publisher,
         Request,
         Response,
     },
+    Isolation,
     Linker,
     Worker,
 };
 
+/// Time stopping workers get to exit unless configured otherwise.
+const DEFAULT_GRACE: Duration = Duration::from_secs(5);
+
 pub struct Static {
-    worker_tx: mpsc::Sender<Worker>,
-    worker_rx: Mutex<mpsc::Receiver<Worker>>,
+    workers:   Arc<Workers>,
+    waiting:   AtomicUsize,
+    limits:    QueueLimits,
+    publisher: Option<Publisher>,
+}
+
+/// Workers of a [`Static`] pool, shared with tasks spawning replacements.
+struct Workers {
+    script:    String,
+    socket:    String,
+    linker:    Arc<Linker>,
+    idle:      SegQueue<Worker>,
+    /// One permit per worker sitting in `idle`.
+    permits:   Semaphore,
+    /// Number of workers kept running.
+    size:      AtomicUsize,
+    /// Every running worker, idle or checked out.
+    stats:     Mutex<BTreeMap<Pid, Stats>>,
+    /// Workers being spawned.
+    spawning:  AtomicUsize,
+    /// Time stopping workers get to exit before being terminated.
+    grace:     Mutex<Duration>,
+    isolation: Isolation,
+    backoff:   Mutex<Backoff>,
+    /// Notified when the pool starts failing, releasing waiting requests.
+    failing:   Notify,
+}
+
+/// Consecutive failures to start workers.
+#[derive(Debug, Default)]
+struct Backoff {
+    policy:       SpawnBackoff,
+    failures:     u32,
+    last_failure: Option<Instant>,
+}
+
+impl Backoff {
+    /// Time to wait after the last failure before spawning again.
+    fn delay(&self) -> Duration {
+        let doublings = self.failures.saturating_sub(1).min(31);
+        let delay = self.policy.initial.saturating_mul(1 << doublings);
+        match self.failures {
+            0 => Duration::ZERO,
+            _ => delay.min(self.policy.max),
+        }
+    }
+
+    fn remaining(&self) -> Duration {
+        match self.last_failure {
+            Some(last) => self.delay().saturating_sub(last.elapsed()),
+            None => Duration::ZERO,
+        }
+    }
+
+    fn failing(&self) -> bool {
+        self.failures >= self.policy.max_failures
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Stats {
+    started:   Instant,
+    /// Last time the worker answered a request or ping.
+    last_seen: Instant,
+    requests:  u64,
+    busy:      bool,
+    /// Replaced once it's idle.
+    retiring:  bool,
+}
+
+impl Stats {
+    fn new() -> Self {
+        Self {
+            started:   Instant::now(),
+            last_seen: Instant::now(),
+            requests:  0,
+            busy:      false,
+            retiring:  false,
+        }
+    }
 }
 
 impl Static {
+    /// Spawns `size` workers running `worker_script`, confined as described
+    /// by `isolation`. Failed spawns are retried in the background,
+    /// waiting longer after each as set by `backoff`.
     pub async fn new(
         socket: &str,
         worker_script: &str,
         size: usize,
+        isolation: Isolation,
+        backoff: SpawnBackoff,
     ) -> Result<Self> {
         let connections = listen(socket)?;
-        let linker = Linker::new(connections);
-
-        let workers = join_all(
-            (0..size)
-                .map(|_| Worker::new(worker_script, socket, linker.clone())),
-        )
-        .await;
+        if let Some(credentials) = &isolation.credentials {
+            credentials.check()?;
+            // Workers need write access to connect to the socket.
+            std::os::unix::fs::chown(
+                socket,
+                Some(credentials.uid),
+                Some(credentials.gid),
+            )
+            .map_err(|err| {
+                anyhow!("could not hand socket {} to workers: {}", socket, err)
+            })?;
+        }
 
-        let workers = workers
-            .into_iter()
-            .collect::<Result<Vec<_>, _>>()
-            .map_err(|err| anyhow!("could not create worker: {}", err))?;
+        let workers = Arc::new(Workers {
+            script: worker_script.to_string(),
+            socket: socket.to_string(),
+            linker: Linker::new(connections),
+            idle: SegQueue::new(),
+            permits: Semaphore::new(0),
+            size: AtomicUsize::new(size),
+            stats: Mutex::new(BTreeMap::new()),
+            spawning: AtomicUsize::new(0),
+            grace: Mutex::new(DEFAULT_GRACE),
+            isolation,
+            backoff: Mutex::new(Backoff {
+                policy: backoff,
+                ..Backoff::default()
+            }),
+            failing: Notify::new(),
+        });
 
-        let (worker_tx, worker_rx) = mpsc::channel(size);
-        for worker in workers {
-            worker_tx.send(worker).await.map_err(|err| {
-                anyhow!("could not send worker to worker ch: {}", err)
-            })?;
+        // A broken script shouldn't prevent starting, missing workers are
+        // spawned in the background once it's fixed.
+        let started = Instant::now();
+        let spawned = join_all((0..size).map(|_| workers.spawn())).await;
+        for worker in spawned {
+            match worker {
+                Ok(worker) => workers.add(worker),
+                Err(err) => workers.spawn_failed(started, &err),
+            }
         }
+        workers.fill();
 
         Ok(Self {
-            worker_tx,
-            worker_rx: Mutex::new(worker_rx),
+            workers,
+            waiting: AtomicUsize::new(0),
+            limits: QueueLimits::default(),
+            publisher: None,
         })
     }
+
+    pub fn with_queue_limits(
+        mut self,
+        limits: QueueLimits,
+    ) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Forwards messages workers publish to `publisher`.
+    pub fn with_publisher(
+        mut self,
+        publisher: Publisher,
+    ) -> Self {
+        self.publisher = Some(publisher);
+        self
+    }
+
+    /// Gives stopping workers `grace` to exit before sending `SIGTERM`, and
+    /// as long again before sending `SIGKILL`.
+    pub fn with_grace(
+        self,
+        grace: Duration,
+    ) -> Self {
+        *self.workers.grace() = grace;
+        self
+    }
+
+    /// Pings workers idle for `interval`, replacing those not answering
+    /// within `deadline`. Stops once the pool is dropped.
+    pub fn with_ping(
+        self,
+        interval: Duration,
+        deadline: Duration,
+    ) -> Self {
+        let workers = Arc::downgrade(&self.workers);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                match workers.upgrade() {
+                    Some(workers) => workers.ping(interval, deadline).await,
+                    None => break,
+                }
+            }
+        });
+        self
+    }
+
+    /// Checks out a free worker, waiting until one is available.
+    ///
+    /// Every permit of `permits` corresponds to a worker sitting in `idle`,
+    /// so once a permit is acquired popping from the queue can't fail. The
+    /// worker is pushed back to the queue when the returned guard is
+    /// dropped, retiring workers are replaced instead.
+    ///
+    /// Fails with [`Overloaded`] if waiting would exceed the pool's
+    /// [`QueueLimits`], or while workers keep failing to start.
+    pub async fn checkout(&self) -> Result<WorkerGuard<'_>> {
+        loop {
+            if self.failing() {
+                return Err(Overloaded::Failing.into());
+            }
+            let permit = match self.workers.permits.try_acquire() {
+                Ok(permit) => permit,
+                Err(_) => self.wait_for_permit().await?,
+            };
+            let worker = self
+                .workers
+                .idle
+                .pop()
+                .ok_or_else(|| anyhow!("could not get free worker"))?;
+
+            let mut stats = self.workers.stats();
+            match stats.get_mut(&worker.pid()) {
+                Some(entry) if !entry.retiring => entry.busy = true,
+                _ => {
+                    drop(stats);
+                    self.workers.discard(worker, permit);
+                    continue;
+                }
+            }
+
+            return Ok(WorkerGuard {
+                pool:      self,
+                worker:    Some(worker),
+                permit:    Some(permit),
+                in_flight: false,
+            });
+        }
+    }
+
+    async fn wait_for_permit(&self) -> Result<SemaphorePermit<'_>> {
+        let waiting = Waiting::enter(&self.waiting);
+        if let Some(max_len) = self.limits.max_len {
+            if waiting.position > max_len {
+                return Err(Overloaded::QueueFull.into());
+            }
+        }
+
+        // Created before checking, so failing in between still wakes us.
+        let failing = self.workers.failing.notified();
+        if self.failing() {
+            return Err(Overloaded::Failing.into());
+        }
+        let acquire = async {
+            tokio::select! {
+                permit = self.workers.permits.acquire() => Ok(permit),
+                _ = failing => Err(Overloaded::Failing),
+            }
+        };
+        let permit = match self.limits.max_wait {
+            Some(max_wait) => {
+                timeout(max_wait, acquire)
+                    .await
+                    .map_err(|_| Overloaded::QueueTimeout)??
+            }
+            None => acquire.await?,
+        };
+
+        permit
+            .map_err(|err| anyhow!("could not acquire worker permit: {}", err))
+    }
+
+    /// Whether workers failed to start too many times in a row.
+    pub fn failing(&self) -> bool {
+        self.workers.backoff().failing()
+    }
+
+    /// Size, queue and workers of the pool.
+    pub fn status(&self) -> PoolStatus {
+        let workers = self
+            .workers
+            .stats()
+            .iter()
+            .map(|(pid, stats)| WorkerStatus {
+                pid:      *pid,
+                state:    match (stats.retiring, stats.busy) {
+                    (true, _) => WorkerState::Retiring,
+                    (false, true) => WorkerState::Busy,
+                    (false, false) => WorkerState::Idle,
+                },
+                uptime:   stats.started.elapsed().as_secs(),
+                requests: stats.requests,
+                memory:   resident_memory(*pid),
+            })
+            .collect();
+
+        PoolStatus {
+            size: self.workers.size.load(Ordering::SeqCst),
+            queue_len: self.queue_len(),
+            workers,
+            failing: self.failing(),
+        }
+    }
+
+    /// Number of workers not being replaced.
+    pub fn running(&self) -> usize {
+        self.workers.running()
+    }
+
+    /// Replaces every worker, busy ones once they finish their request.
+    pub fn reset(&self) {
+        for stats in self.workers.stats().values_mut() {
+            stats.retiring = true;
+        }
+        self.workers.fill();
+        self.workers.sweep();
+    }
+
+    /// Replaces worker `pid`, returns whether it belongs to the pool.
+    pub fn kill(
+        &self,
+        pid: Pid,
+    ) -> bool {
+        match self.workers.stats().get_mut(&pid) {
+            Some(stats) => stats.retiring = true,
+            None => return false,
+        }
+        self.workers.fill();
+        self.workers.sweep();
+        true
+    }
+
+    /// Spawns or retires workers until `size` are running, idle workers are
+    /// retired first.
+    pub fn scale(
+        &self,
+        size: usize,
+    ) {
+        self.workers.size.store(size, Ordering::SeqCst);
+        {
+            let mut stats = self.workers.stats();
+            let running = stats.values().filter(|stats| !stats.retiring);
+            let excess = running.count().saturating_sub(size);
+            let mut candidates = stats
+                .values_mut()
+                .filter(|stats| !stats.retiring)
+                .collect::<Vec<_>>();
+            candidates.sort_by_key(|stats| stats.busy);
+            for stats in candidates.into_iter().take(excess) {
+                stats.retiring = true;
+            }
+        }
+        self.workers.fill();
+        self.workers.sweep();
+    }
+
+    /// Stops idle workers and waits for them to exit, busy ones are stopped
+    /// once they finish their request. No workers are spawned afterwards.
+    pub async fn stop(&self) {
+        self.workers.size.store(0, Ordering::SeqCst);
+        for stats in self.workers.stats().values_mut() {
+            stats.retiring = true;
+        }
+
+        let grace = *self.workers.grace();
+        let mut stopping = vec![];
+        for _ in 0..self.workers.idle.len() {
+            let permit = match self.workers.permits.try_acquire() {
+                Ok(permit) => permit,
+                Err(_) => break,
+            };
+            let worker = match self.workers.idle.pop() {
+                Some(worker) => worker,
+                None => break,
+            };
+            permit.forget();
+            self.workers.stats().remove(&worker.pid());
+            stopping.push(stop(worker, grace));
+        }
+        join_all(stopping).await;
+    }
+}
+
+impl Workers {
+    fn stats(&self) -> MutexGuard<'_, BTreeMap<Pid, Stats>> {
+        self.stats.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn grace(&self) -> MutexGuard<'_, Duration> {
+        self.grace.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn backoff(&self) -> MutexGuard<'_, Backoff> {
+        self.backoff.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn running(&self) -> usize {
+        let stats = self.stats();
+        stats.values().filter(|stats| !stats.retiring).count()
+    }
+
+    async fn spawn(&self) -> Result<Worker> {
+        let linker = self.linker.clone();
+        Worker::new(&self.script, &self.socket, linker, &self.isolation).await
+    }
+
+    /// Makes `worker` available for requests.
+    fn add(
+        &self,
+        worker: Worker,
+    ) {
+        self.stats().insert(worker.pid(), Stats::new());
+        self.idle.push(worker);
+        self.permits.add_permits(1);
+    }
+
+    /// Stops `worker` checked out with `permit`, spawning a replacement if
+    /// the pool is short of workers.
+    fn discard(
+        self: &Arc<Self>,
+        worker: Worker,
+        permit: SemaphorePermit<'_>,
+    ) {
+        permit.forget();
+        self.stats().remove(&worker.pid());
+        tokio::spawn(stop(worker, *self.grace()));
+        self.fill();
+    }
+
+    /// Spawns workers until the pool has `size` workers not retiring.
+    fn fill(self: &Arc<Self>) {
+        let stats = self.stats();
+        let running = stats.values().filter(|stats| !stats.retiring).count();
+        let spawning = self.spawning.load(Ordering::SeqCst);
+        let missing = self
+            .size
+            .load(Ordering::SeqCst)
+            .saturating_sub(running + spawning);
+        self.spawning.fetch_add(missing, Ordering::SeqCst);
+        drop(stats);
+
+        for _ in 0..missing {
+            let workers = self.clone();
+            tokio::spawn(async move {
+                workers.replenish().await;
+                workers.spawning.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+    }
+
+    /// Spawns a worker, retrying after failures until one starts or the
+    /// pool doesn't need it anymore.
+    async fn replenish(&self) {
+        loop {
+            let remaining = self.backoff().remaining();
+            tokio::time::sleep(remaining).await;
+            // The pool may have been scaled down or stopped meanwhile.
+            if self.running() >= self.size.load(Ordering::SeqCst) {
+                return;
+            }
+
+            let started = Instant::now();
+            match self.spawn().await {
+                Ok(worker) => {
+                    self.spawned();
+                    self.add(worker);
+                    return;
+                }
+                Err(err) => self.spawn_failed(started, &err),
+            }
+        }
+    }
+
+    /// Resets the backoff after a worker started.
+    fn spawned(&self) {
+        let mut backoff = self.backoff();
+        if backoff.failing() {
+            log::info!("workers running {} start again", self.script);
+        }
+        backoff.failures = 0;
+        backoff.last_failure = None;
+    }
+
+    /// Records a failed attempt to start a worker begun at `started`.
+    ///
+    /// Attempts running concurrently count as one failure, so spawning
+    /// several workers doesn't skip backoff steps.
+    fn spawn_failed(
+        &self,
+        started: Instant,
+        err: &anyhow::Error,
+    ) {
+        let mut backoff = self.backoff();
+        let counted = backoff.last_failure.is_some_and(|last| started < last);
+        if !counted {
+            backoff.failures += 1;
+        }
+        backoff.last_failure = Some(Instant::now());
+
+        if !counted && backoff.failures == backoff.policy.max_failures {
+            log::error!(
+                "workers running {} failed to start {} times in a row, \
+                 rejecting requests until one starts: {}",
+                self.script,
+                backoff.failures,
+                err
+            );
+            self.failing.notify_waiters();
+        } else {
+            log::error!(
+                "could not create worker, retrying in {:?}: {}",
+                backoff.delay(),
+                err
+            );
+        }
+    }
+
+    /// Pings workers idle for at least `idle_for` one at a time, replacing
+    /// the ones not answering within `deadline`.
+    async fn ping(
+        self: &Arc<Self>,
+        idle_for: Duration,
+        deadline: Duration,
+    ) {
+        for _ in 0..self.idle.len() {
+            let permit = match self.permits.try_acquire() {
+                Ok(permit) => permit,
+                Err(_) => break,
+            };
+            let mut worker = match self.idle.pop() {
+                Some(worker) => worker,
+                None => break,
+            };
+
+            let pid = worker.pid();
+            let due = self.stats().get(&pid).is_some_and(|stats| {
+                !stats.retiring && stats.last_seen.elapsed() >= idle_for
+            });
+            if !due {
+                self.idle.push(worker);
+                continue;
+            }
+
+            match worker.ping(deadline).await {
+                Ok(()) => {
+                    if let Some(stats) = self.stats().get_mut(&pid) {
+                        stats.last_seen = Instant::now();
+                    }
+                    self.idle.push(worker);
+                    drop(permit);
+                }
+                Err(err) => {
+                    log::warn!(
+                        "replacing unresponsive worker {}: {}",
+                        pid,
+                        err
+                    );
+                    self.discard(worker, permit);
+                }
+            }
+        }
+    }
+
+    /// Discards idle workers marked as retiring.
+    fn sweep(self: &Arc<Self>) {
+        for _ in 0..self.idle.len() {
+            let permit = match self.permits.try_acquire() {
+                Ok(permit) => permit,
+                Err(_) => break,
+            };
+            let worker = match self.idle.pop() {
+                Some(worker) => worker,
+                None => break,
+            };
+
+            let retiring = self
+                .stats()
+                .get(&worker.pid())
+                .is_none_or(|stats| stats.retiring);
+            if retiring {
+                self.discard(worker, permit);
+            } else {
+                self.idle.push(worker);
+            }
+        }
+    }
+}
+
+async fn stop(
+    worker: Worker,
+    grace: Duration,
+) {
+    let pid = worker.pid();
+    log::debug!("stopping worker {}", pid);
+    if let Err(err) = worker.stop(grace).await {
+        log::error!("could not stop worker {}: {}", pid, err);
+    }
+}
+
+/// Resident set size of process `pid` in bytes, Linux only.
+fn resident_memory(pid: Pid) -> Option<u64> {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kib = line.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+    Some(kib * 1024)
+}
+
+/// Counts a request as waiting in the queue for as long as it is alive.
+struct Waiting<'a> {
+    counter:  &'a AtomicUsize,
+    /// Queue length including this request.
+    position: usize,
+}
+
+impl<'a> Waiting<'a> {
+    fn enter(counter: &'a AtomicUsize) -> Self {
+        let position = counter.fetch_add(1, Ordering::SeqCst) + 1;
+        Self { counter, position }
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.counter.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 #[async_trait]
@@ -66,28 +688,123 @@ impl Pool for Static {
         &self,
         req: Request,
     ) -> Result<Response> {
-        // TODO: add timeout.
-        // TODO: WorkerGuard?
-        let mut worker = {
-            let mut rx = self.worker_rx.lock().await;
-            rx.recv()
-                .await
-                .ok_or_else(|| anyhow!("could not get free worker"))
-        }?;
+        let (response, _) = self.exec_traced(req).await?;
+        Ok(response)
+    }
+
+    async fn exec_traced(
+        &self,
+        req: Request,
+    ) -> Result<(Response, Execution)> {
+        let start = Instant::now();
+        let mut worker = self
+            .checkout()
+            .instrument(tracing::info_span!("checkout"))
+            .await?;
+        let pid = worker.pid();
+        let queue_time = start.elapsed();
+        worker.in_flight = true;
+        let response = worker
+            .exec(req, self.publisher.as_ref())
+            .instrument(tracing::info_span!("round_trip", pid))
+            .await;
+        worker.in_flight = false;
+        let response = match response {
+            Ok(response) => response,
+            Err(err) => {
+                worker.retire().await;
+                return Err(err);
+            }
+        };
+
+        Ok((response, Execution {
+            pid: Some(pid),
+            queue_time,
+            exec_time: start.elapsed() - queue_time,
+        }))
+    }
+
+    fn queue_len(&self) -> usize {
+        self.waiting.load(Ordering::SeqCst)
+    }
+}
 
-        let response = worker.exec(req).await;
+/// A worker checked out from a [`Static`] pool.
+///
+/// Returns the worker to the pool on drop, or replaces it if it was retired
+/// in the meantime or dropped during a round trip.
+pub struct WorkerGuard<'a> {
+    pool:      &'a Static,
+    worker:    Option<Worker>,
+    // NOTE: permit must be released after the worker is pushed back.
+    permit:    Option<SemaphorePermit<'a>>,
+    /// A request was sent but its response not read yet, the next request
+    /// would get it if the worker were reused.
+    in_flight: bool,
+}
 
-        // TODO: make sure this is super fast or do it in background.
-        if let Err(err) = self.worker_tx.send(worker).await {
-            error!("could not send worker back to worker ch: {}", err);
+impl WorkerGuard<'_> {
+    /// Replaces the worker once the guard is dropped, logging why it exited
+    /// if it did.
+    async fn retire(&mut self) {
+        let pid = self.pid();
+        match self.exit_reason().await {
+            Some(reason) => log::warn!("worker {} {}", pid, reason),
+            None => log::warn!("replacing failing worker {}", pid),
+        }
+        if let Some(stats) = self.pool.workers.stats().get_mut(&pid) {
+            stats.retiring = true;
         }
+    }
+}
 
-        response
+impl Deref for WorkerGuard<'_> {
+    type Target = Worker;
+
+    fn deref(&self) -> &Self::Target {
+        self.worker.as_ref().expect("worker guard is empty")
+    }
+}
+
+impl DerefMut for WorkerGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.worker.as_mut().expect("worker guard is empty")
+    }
+}
+
+impl Drop for WorkerGuard<'_> {
+    fn drop(&mut self) {
+        let (worker, permit) = match (self.worker.take(), self.permit.take()) {
+            (Some(worker), Some(permit)) => (worker, permit),
+            _ => return,
+        };
+
+        let workers = &self.pool.workers;
+        let mut stats = workers.stats();
+        let retiring = match stats.get_mut(&worker.pid()) {
+            Some(entry) => {
+                entry.busy = false;
+                entry.requests += 1;
+                entry.last_seen = Instant::now();
+                entry.retiring
+            }
+            None => true,
+        };
+        drop(stats);
+
+        if retiring || self.in_flight {
+            workers.discard(worker, permit);
+        } else {
+            workers.idle.push(worker);
+            drop(permit);
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::sync::Arc;
+
     use test::Bencher;
     use tokio::runtime::Runtime;
 
@@ -99,6 +816,8 @@ mod tests {
             "/tmp/coyote.test.sock",
             "./src/worker/test_data/sleepy_pid_worker.php",
             2,
+            Isolation::default(),
+            SpawnBackoff::default(),
         )
         .await?;
 
@@ -113,8 +832,373 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn tracing_executions() -> Result<()> {
+        let pool = Static::new(
+            "/tmp/coyote.test.sock.10",
+            "./src/worker/test_data/sleepy_pid_worker.php",
+            1,
+            Isolation::default(),
+            SpawnBackoff::default(),
+        )
+        .await?;
+
+        let (first, second) = tokio::join!(
+            pool.exec_traced(r#"{"message":"hello world"}"#.into()),
+            pool.exec_traced(r#"{"message":"hello world"}"#.into()),
+        );
+        let ((_, first), (_, second)) = (first?, second?);
+
+        assert!(first.pid.is_some());
+        assert_eq!(first.pid, second.pid);
+        assert!(second.queue_time >= Duration::from_millis(50));
+
+        Ok(())
+    }
+
+    /// Waits until the pool runs `size` workers none of `old` is part of.
+    async fn replaced(
+        pool: &Static,
+        size: usize,
+        old: &[Pid],
+    ) -> Vec<Pid> {
+        loop {
+            let pids = pool
+                .status()
+                .workers
+                .iter()
+                .map(|worker| worker.pid)
+                .collect::<Vec<_>>();
+            if pids.len() == size && pids.iter().all(|pid| !old.contains(pid)) {
+                return pids;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn scaling_and_replacing_workers() -> Result<()> {
+        let pool = Static::new(
+            "/tmp/coyote.test.sock.11",
+            "./src/worker/test_data/sleepy_pid_worker.php",
+            2,
+            Isolation::default(),
+            SpawnBackoff::default(),
+        )
+        .await?;
+
+        let status = pool.status();
+        assert_eq!(status.size, 2);
+        assert!(status
+            .workers
+            .iter()
+            .all(|worker| worker.state == WorkerState::Idle));
+        let pids = status
+            .workers
+            .iter()
+            .map(|worker| worker.pid)
+            .collect::<Vec<_>>();
+
+        let wait = Duration::from_secs(5);
+        assert!(pool.kill(pids[0]));
+        assert!(!pool.kill(pids[0]));
+        timeout(wait, replaced(&pool, 2, &pids[..1])).await?;
+
+        pool.scale(3);
+        timeout(wait, replaced(&pool, 3, &[])).await?;
+        pool.scale(1);
+        assert_eq!(pool.status().workers.len(), 1);
+        assert_eq!(pool.workers.idle.len(), 1);
+
+        let old = [pool.status().workers[0].pid];
+        pool.reset();
+        let pids = timeout(wait, replaced(&pool, 1, &old)).await?;
+        pool.exec(r#"{"message":"hello world"}"#.into()).await?;
+        let worker = &pool.status().workers[0];
+        assert_eq!((worker.pid, worker.requests), (pids[0], 1));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replacing_unresponsive_workers() -> Result<()> {
+        let interval = Duration::from_millis(20);
+        let pool = Static::new(
+            "/tmp/coyote.test.sock.17",
+            "./src/worker/test_data/sleepy_pid_worker.php",
+            1,
+            Isolation::default(),
+            SpawnBackoff::default(),
+        )
+        .await?
+        .with_ping(interval, interval);
+
+        let pid = pool.status().workers[0].pid;
+        tokio::time::sleep(interval * 5).await;
+        assert_eq!(pool.status().workers[0].pid, pid);
+
+        // A stopped process can't answer pings.
+        std::process::Command::new("kill")
+            .args(["-STOP", &pid.to_string()])
+            .status()?;
+        timeout(Duration::from_secs(5), replaced(&pool, 1, &[pid])).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stopping_pools() -> Result<()> {
+        let pool = Static::new(
+            "/tmp/coyote.test.sock.20",
+            "./src/worker/test_data/echo_worker.php",
+            2,
+            Isolation::default(),
+            SpawnBackoff::default(),
+        )
+        .await?
+        .with_grace(Duration::from_secs(5));
+        let pids = pool
+            .status()
+            .workers
+            .iter()
+            .map(|worker| worker.pid)
+            .collect::<Vec<_>>();
+
+        timeout(Duration::from_secs(1), pool.stop()).await?;
+        assert!(pool.status().workers.is_empty());
+        for pid in pids {
+            let proc = format!("/proc/{}", pid);
+            assert!(!std::path::Path::new(&proc).exists());
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replacing_crashed_workers() -> Result<()> {
+        let pool = Static::new(
+            "/tmp/coyote.test.sock.22",
+            "./src/worker/test_data/echo_worker.php",
+            1,
+            Isolation::default(),
+            SpawnBackoff::default(),
+        )
+        .await?;
+
+        let pid = pool.status().workers[0].pid;
+        std::process::Command::new("kill")
+            .args(["-KILL", &pid.to_string()])
+            .status()?;
+        assert!(pool.exec("hello".into()).await.is_err());
+        timeout(Duration::from_secs(5), replaced(&pool, 1, &[pid])).await?;
+        assert_eq!(pool.exec("hello".into()).await?, "hello".into());
+
+        Ok(())
+    }
+
+    #[test]
+    fn backing_off_exponentially() {
+        let mut backoff = Backoff {
+            policy: SpawnBackoff {
+                initial:      Duration::from_millis(100),
+                max:          Duration::from_secs(1),
+                max_failures: 3,
+            },
+            ..Backoff::default()
+        };
+        let delays = (0..6)
+            .map(|failures| {
+                backoff.failures = failures;
+                backoff.delay().as_millis()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(delays, [0, 100, 200, 400, 800, 1000]);
+
+        backoff.failures = 64;
+        assert_eq!(backoff.delay(), Duration::from_secs(1));
+        assert!(backoff.failing());
+    }
+
+    #[tokio::test]
+    async fn recovering_from_failing_workers() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        // Workers exit right away until the script exists.
+        let script = dir.path().join("worker.php");
+        let pool = Static::new(
+            "/tmp/coyote.test.sock.24",
+            script.to_str().unwrap(),
+            1,
+            Isolation::default(),
+            SpawnBackoff {
+                initial:      Duration::from_millis(10),
+                max:          Duration::from_millis(20),
+                max_failures: 3,
+            },
+        )
+        .await?;
+        assert!(pool.status().workers.is_empty());
+
+        let failing = async {
+            while !pool.failing() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        timeout(Duration::from_secs(5), failing).await?;
+        assert!(pool.status().failing);
+        assert_eq!(
+            pool.exec("hello".into()).await.unwrap_err().downcast_ref(),
+            Some(&Overloaded::Failing),
+        );
+
+        std::fs::copy("./src/worker/test_data/echo_worker.php", &script)?;
+        timeout(Duration::from_secs(5), replaced(&pool, 1, &[])).await?;
+        assert!(!pool.failing());
+        assert_eq!(pool.exec("hello".into()).await?, "hello".into());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failing_right_after_start() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let script = dir.path().join("worker.php");
+        let pool = Static::new(
+            "/tmp/coyote.test.sock.26",
+            script.to_str().unwrap(),
+            2,
+            Isolation::default(),
+            SpawnBackoff {
+                initial:      Duration::from_secs(60),
+                max:          Duration::from_secs(60),
+                max_failures: 1,
+            },
+        )
+        .await?;
+
+        assert!(pool.failing());
+        assert_eq!(
+            pool.exec("hello".into()).await.unwrap_err().downcast_ref(),
+            Some(&Overloaded::Failing),
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn discarding_workers_of_cancelled_requests() -> Result<()> {
+        let pool = Static::new(
+            "/tmp/coyote.test.sock.25",
+            "./src/worker/test_data/sleepy_pid_worker.php",
+            1,
+            Isolation::default(),
+            SpawnBackoff::default(),
+        )
+        .await?;
+        let pid = pool.status().workers[0].pid;
+
+        let cancelled = timeout(
+            Duration::from_millis(20),
+            pool.exec(r#"{"message":"hello world"}"#.into()),
+        )
+        .await;
+        assert!(cancelled.is_err());
+
+        // The old worker's response is never read, the replacement answers.
+        let (response, execution) = timeout(
+            Duration::from_secs(5),
+            pool.exec_traced(r#"{"message":"hello world"}"#.into()),
+        )
+        .await??;
+        let replacement = execution.pid.unwrap();
+        assert_ne!(replacement, pid);
+        assert_eq!(response, replacement.to_string().as_str().into());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn worker_guard_returns_worker_on_drop() -> Result<()> {
+        let pool = Static::new(
+            "/tmp/coyote.test.sock.6",
+            "./src/worker/test_data/sleepy_pid_worker.php",
+            1,
+            Isolation::default(),
+            SpawnBackoff::default(),
+        )
+        .await?;
+
+        let first = pool.exec(r#"{"message":"hello world"}"#.into()).await?;
+        let second = pool.exec(r#"{"message":"hello world"}"#.into()).await?;
+
+        assert_eq!(first, second);
+        assert_eq!(pool.workers.idle.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejecting_when_queue_is_full() -> Result<()> {
+        let pool = Static::new(
+            "/tmp/coyote.test.sock.8",
+            "./src/worker/test_data/sleepy_pid_worker.php",
+            1,
+            Isolation::default(),
+            SpawnBackoff::default(),
+        )
+        .await?
+        .with_queue_limits(QueueLimits {
+            max_len:  Some(1),
+            max_wait: None,
+        });
+
+        let (res1, res2, res3) = tokio::join!(
+            pool.exec(r#"{"message":"hello world"}"#.into()),
+            pool.exec(r#"{"message":"hello world"}"#.into()),
+            pool.exec(r#"{"message":"hello world"}"#.into()),
+        );
+
+        assert!(res1.is_ok());
+        assert!(res2.is_ok());
+        assert_eq!(
+            res3.unwrap_err().downcast_ref::<Overloaded>(),
+            Some(&Overloaded::QueueFull),
+        );
+        assert_eq!(pool.queue_len(), 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejecting_after_max_queue_wait() -> Result<()> {
+        let pool = Static::new(
+            "/tmp/coyote.test.sock.9",
+            "./src/worker/test_data/sleepy_pid_worker.php",
+            1,
+            Isolation::default(),
+            SpawnBackoff::default(),
+        )
+        .await?
+        .with_queue_limits(QueueLimits {
+            max_len:  None,
+            max_wait: Some(Duration::from_millis(10)),
+        });
+
+        let (res1, res2) = tokio::join!(
+            pool.exec(r#"{"message":"hello world"}"#.into()),
+            pool.exec(r#"{"message":"hello world"}"#.into()),
+        );
+
+        assert!(res1.is_ok());
+        assert_eq!(
+            res2.unwrap_err().downcast_ref::<Overloaded>(),
+            Some(&Overloaded::QueueTimeout),
+        );
+        assert_eq!(pool.queue_len(), 0);
+
+        Ok(())
+    }
+
     #[bench]
-    // TODO: parallel benchmark.
     fn bench_static_pool(b: &mut Bencher) -> Result<()> {
         let rt = Runtime::new().unwrap();
         let _guard = rt.enter();
@@ -123,6 +1207,8 @@ mod tests {
             "/tmp/coyote.test.sock.1",
             "./src/worker/test_data/echo_worker.php",
             2,
+            Isolation::default(),
+            SpawnBackoff::default(),
         ))?;
 
         b.iter(|| {
@@ -135,4 +1221,38 @@ mod tests {
 
         Ok(())
     }
+
+    #[bench]
+    fn bench_static_pool_parallel(b: &mut Bencher) -> Result<()> {
+        const CLIENTS: usize = 64;
+
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+
+        let pool = Arc::new(rt.block_on(Static::new(
+            "/tmp/coyote.test.sock.7",
+            "./src/worker/test_data/echo_worker.php",
+            8,
+            Isolation::default(),
+            SpawnBackoff::default(),
+        ))?);
+
+        b.iter(|| {
+            let clients = (0..CLIENTS).map(|_| {
+                let pool = pool.clone();
+                tokio::spawn(async move {
+                    pool.exec(r#"{"message":"hello world"}"#.into()).await
+                })
+            });
+
+            for response in rt.block_on(join_all(clients)) {
+                assert_eq!(
+                    response.unwrap().unwrap(),
+                    r#"{"message":"hello world"}"#.into(),
+                );
+            }
+        });
+
+        Ok(())
+    }
 }
@@ -0,0 +1,14 @@
+<?php
+
+require "php/Relay.php";
+
+pcntl_signal(SIGTERM, SIG_IGN);
+
+$relay = new Coyote\Relay($argv[1]);
+
+while ($body = $relay->next()) {
+    $relay->send($body);
+}
+
+// Cleanup never finishes.
+sleep(3600);
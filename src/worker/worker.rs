@@ -1,8 +1,12 @@
+use std::fmt;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
 use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::{
     anyhow,
+    bail,
     Result,
 };
 use tokio::process::{
@@ -13,14 +17,53 @@ use tokio::time::timeout;
 
 use super::ipc::{
     Connection,
+    Pid,
+    Publisher,
     Request,
     Response,
 };
-use crate::worker::Linker;
+use crate::worker::{
+    Cgroup,
+    Isolation,
+    Linker,
+};
+
+/// Time a worker whose connection failed gets to finish exiting.
+const EXIT_TIMEOUT: Duration = Duration::from_millis(100);
 
 pub struct Worker {
-    _child: Child,
+    child:  Child,
     conn:   Connection,
+    cgroup: Option<Arc<Cgroup>>,
+}
+
+/// Why a worker process exited.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExitReason {
+    Exited(i32),
+    Signaled(i32),
+    /// Killed for exceeding the memory limit of its cgroup.
+    OutOfMemory,
+}
+
+impl fmt::Display for ExitReason {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        match self {
+            ExitReason::Exited(code) => {
+                write!(f, "exited with status {}", code)
+            }
+            ExitReason::Signaled(libc::SIGXCPU) => {
+                write!(f, "exceeded its CPU time limit")
+            }
+            ExitReason::Signaled(signal) => {
+                write!(f, "was killed by signal {}", signal)
+            }
+            ExitReason::OutOfMemory => write!(f, "ran out of memory"),
+        }
+    }
 }
 
 impl Worker {
@@ -28,32 +71,116 @@ impl Worker {
         script: &str,
         socket: &str,
         linker: Arc<Linker>,
+        isolation: &Isolation,
     ) -> Result<Self> {
-        let child = Command::new("php")
-            .arg(script)
-            .arg(socket)
-            .kill_on_drop(true)
-            .spawn()?;
+        let mut command = Command::new("php");
+        command.arg(script).arg(socket).kill_on_drop(true);
+        isolation.apply(&mut command)?;
+        let mut child = command.spawn()?;
 
         let pid = child
             .id()
             .ok_or_else(|| anyhow!("could not get pid of worker"))?;
 
-        let conn =
-            timeout(Duration::from_millis(2000), linker.get(pid as usize))
-                .await??;
+        // Scripts failing to compile exit right away, no need to wait for
+        // the connection timeout.
+        let connecting =
+            timeout(Duration::from_millis(2000), linker.get(pid as usize));
+        let conn = tokio::select! {
+            conn = connecting => conn??,
+            status = child.wait() => {
+                let cgroup = isolation.cgroup.as_deref();
+                match exit_reason(status?, cgroup) {
+                    Some(reason) => {
+                        bail!("worker {} before connecting", reason)
+                    }
+                    None => bail!("worker exited before connecting"),
+                }
+            }
+        };
 
         Ok(Self {
-            _child: child,
+            child,
             conn,
+            cgroup: isolation.cgroup.clone(),
         })
     }
 
+    pub fn pid(&self) -> Pid {
+        self.conn.pid()
+    }
+
     pub async fn exec(
         &mut self,
         req: Request,
+        publisher: Option<&Publisher>,
     ) -> Result<Response> {
-        self.conn.round_trip(req).await
+        self.conn.round_trip(req, publisher).await
+    }
+
+    /// Checks the worker answers a ping within `deadline`.
+    pub async fn ping(
+        &mut self,
+        deadline: Duration,
+    ) -> Result<()> {
+        timeout(deadline, self.conn.ping())
+            .await
+            .map_err(|_| anyhow!("no pong within {:?}", deadline))?
+    }
+
+    /// Why the worker exited, `None` if it's still running. Waits briefly as
+    /// the process may still be exiting when its connection fails.
+    pub async fn exit_reason(&mut self) -> Option<ExitReason> {
+        let status = timeout(EXIT_TIMEOUT, self.child.wait()).await.ok()?;
+        exit_reason(status.ok()?, self.cgroup.as_deref())
+    }
+
+    /// Asks the worker to exit, escalating to `SIGTERM` then `SIGKILL` if
+    /// it is still running after `grace` each time.
+    pub async fn stop(
+        mut self,
+        grace: Duration,
+    ) -> Result<()> {
+        let pid = self.pid();
+        if let Err(err) = self.conn.stop().await {
+            log::debug!("could not send stop to worker {}: {}", pid, err);
+        }
+        if timeout(grace, self.child.wait()).await.is_ok() {
+            return Ok(());
+        }
+
+        log::warn!(
+            "worker {} still running after {:?}, terminating",
+            pid,
+            grace
+        );
+        if let Some(id) = self.child.id() {
+            // SAFETY: `id` is our own child, not reaped yet.
+            unsafe { libc::kill(id as libc::pid_t, libc::SIGTERM) };
+        }
+        if timeout(grace, self.child.wait()).await.is_ok() {
+            return Ok(());
+        }
+
+        log::warn!("worker {} ignored SIGTERM, killing", pid);
+        self.child.kill().await?;
+        Ok(())
+    }
+}
+
+/// Why a worker exited with `status`, checking `cgroup` for OOM kills.
+fn exit_reason(
+    status: ExitStatus,
+    cgroup: Option<&Cgroup>,
+) -> Option<ExitReason> {
+    let oom_killed = || cgroup.is_some_and(|cgroup| cgroup.oom_killed());
+    match (status.code(), status.signal()) {
+        (Some(code), _) => Some(ExitReason::Exited(code)),
+        (None, Some(libc::SIGKILL)) if oom_killed() => {
+            Some(ExitReason::OutOfMemory)
+        }
+        (None, Some(signal)) => Some(ExitReason::Signaled(signal)),
+        (None, None) => None,
     }
 }
 
@@ -65,6 +192,7 @@ mod tests {
     use super::*;
     use crate::worker::{
         ipc::listen,
+        Limits,
         Linker,
     };
 
@@ -74,17 +202,88 @@ mod tests {
         let script = "./src/worker/test_data/echo_worker.php";
         let connections = listen(socket)?;
         let linker = Linker::new(connections);
+        let isolation = Isolation::default();
 
-        let mut worker = Worker::new(script, socket, linker).await?;
+        let mut worker = Worker::new(script, socket, linker, &isolation).await?;
 
         assert_eq!(
-            worker.exec(r#"{"message":"hello world"}"#.into()).await?,
+            worker.exec(r#"{"message":"hello world"}"#.into(), None).await?,
             r#"{"message":"hello world"}"#.into(),
         );
 
         Ok(())
     }
 
+    #[tokio::test]
+    async fn stopping_workers() -> Result<()> {
+        let running =
+            |pid| std::path::Path::new(&format!("/proc/{}", pid)).exists();
+        let isolation = Isolation::default();
+
+        let socket = "/tmp/coyote.test.sock.18";
+        let script = "./src/worker/test_data/echo_worker.php";
+        let linker = Linker::new(listen(socket)?);
+        let worker = Worker::new(script, socket, linker, &isolation).await?;
+        let pid = worker.pid();
+        timeout(Duration::from_secs(1), worker.stop(Duration::from_secs(5)))
+            .await??;
+        assert!(!running(pid));
+
+        let socket = "/tmp/coyote.test.sock.19";
+        let script = "./src/worker/test_data/stubborn_worker.php";
+        let linker = Linker::new(listen(socket)?);
+        let worker = Worker::new(script, socket, linker, &isolation).await?;
+        let pid = worker.pid();
+        worker.stop(Duration::from_millis(50)).await?;
+        assert!(!running(pid));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn confining_workers() -> Result<()> {
+        let root = tempfile::tempdir()?;
+        let path = root.path().join("app");
+        let cgroup = Arc::new(Cgroup::create(&path, Some(64 << 20), None)?);
+        std::fs::write(path.join("cgroup.procs"), "")?;
+        let isolation = Isolation {
+            credentials: None,
+            limits:      Limits {
+                open_files: Some(64),
+                ..Limits::default()
+            },
+            cgroup:      Some(cgroup),
+        };
+
+        let socket = "/tmp/coyote.test.sock.23";
+        let script = "./src/worker/test_data/echo_worker.php";
+        let linker = Linker::new(listen(socket)?);
+        let mut worker = Worker::new(script, socket, linker, &isolation).await?;
+        let pid = worker.pid();
+
+        let limits = std::fs::read_to_string(format!("/proc/{}/limits", pid))?;
+        let open_files = limits
+            .lines()
+            .find(|line| line.starts_with("Max open files"))
+            .unwrap();
+        assert_eq!(
+            open_files.split_whitespace().collect::<Vec<_>>()[3..5],
+            ["64", "64"]
+        );
+        // The worker joined the cgroup by writing 0 to its procs file.
+        assert_eq!(std::fs::read_to_string(path.join("cgroup.procs"))?, "0");
+        assert_eq!(worker.exit_reason().await, None);
+
+        std::fs::write(path.join("memory.events"), "oom_kill 1\n")?;
+        std::process::Command::new("kill")
+            .args(["-KILL", &pid.to_string()])
+            .status()?;
+        assert!(worker.exec("hello".into(), None).await.is_err());
+        assert_eq!(worker.exit_reason().await, Some(ExitReason::OutOfMemory));
+
+        Ok(())
+    }
+
     #[bench]
     fn bench_communicating_with_worker(b: &mut Bencher) -> Result<()> {
         let rt = Runtime::new().unwrap();
@@ -95,12 +294,19 @@ mod tests {
         let connections = listen(socket)?;
         let linker = Linker::new(connections);
 
-        let mut worker = rt.block_on(Worker::new(script, socket, linker))?;
+        let mut worker = rt.block_on(Worker::new(
+            script,
+            socket,
+            linker,
+            &Isolation::default(),
+        ))?;
 
         b.iter(|| {
             assert_eq!(
-                rt.block_on(worker.exec(r#"{"message":"hello world"}"#.into()))
-                    .unwrap(),
+                rt.block_on(
+                    worker.exec(r#"{"message":"hello world"}"#.into(), None)
+                )
+                .unwrap(),
                 r#"{"message":"hello world"}"#.into(),
             );
         });
@@ -6,5 +6,39 @@ $relay = new Coyote\Relay($argv[1]);
 
 while ($body = $relay->next()) {
     $req = json_decode($body, true);
-    $relay->send(json_encode(["hello" => $req["name"]]));
+
+    // WebSocket and subscription events are answered with commands,
+    // messages are echoed and every topic may be subscribed to.
+    if (isset($req["event"])) {
+        $commands = [];
+        if ($req["event"] === "subscribe") {
+            foreach ($req["topics"] as $topic) {
+                $commands["join"][] = [
+                    "connection" => $req["connection"],
+                    "channel" => $topic,
+                ];
+            }
+        }
+        if ($req["event"] === "message") {
+            $commands["send"] = [[
+                "connection" => $req["connection"],
+                "data" => $req["data"],
+                "binary" => $req["binary"] ?? false,
+            ]];
+        }
+        $relay->send(json_encode((object)$commands));
+        continue;
+    }
+
+    $path = parse_url($req["uri"], PHP_URL_PATH);
+
+    if ($req["method"] !== "GET" || strpos($path, "/hello/") !== 0) {
+        $relay->send(json_encode(["status" => 404]));
+        continue;
+    }
+
+    $relay->send(json_encode([
+        "headers" => ["content-type" => "application/json"],
+        "body" => json_encode(["hello" => substr($path, strlen("/hello/"))]),
+    ]));
 }